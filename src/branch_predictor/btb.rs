@@ -0,0 +1,55 @@
+use std::cell::RefCell;
+
+pub const BTB_SIZE: usize = 64;
+
+// direct mapped, holds the last target of each indirect jump
+pub struct Btb {
+    // pc and target
+    entries: RefCell<[Option<(u32, u32)>; BTB_SIZE]>,
+}
+
+impl Btb {
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new([None; BTB_SIZE]),
+        }
+    }
+
+    fn index(pc: u32) -> usize {
+        (pc >> 1) as usize % BTB_SIZE
+    }
+
+    pub fn lookup(&self, pc: u32) -> Option<u32> {
+        self.entries.borrow()[Self::index(pc)]
+            .filter(|&(tag, _)| tag == pc)
+            .map(|(_, target)| target)
+    }
+
+    pub fn insert(&self, pc: u32, target: u32) {
+        self.entries.borrow_mut()[Self::index(pc)] =
+            Some((pc, target));
+    }
+}
+
+impl Default for Btb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let btb = Btb::new();
+    assert_eq!(btb.lookup(0x100), None);
+
+    btb.insert(0x100, 0x200);
+    assert_eq!(btb.lookup(0x100), Some(0x200));
+
+    // same index, different tag
+    let alias = 0x100 + 2 * BTB_SIZE as u32;
+    assert_eq!(btb.lookup(alias), None);
+    btb.insert(alias, 0x300);
+    assert_eq!(btb.lookup(0x100), None);
+    assert_eq!(btb.lookup(alias), Some(0x300));
+}
@@ -0,0 +1,240 @@
+use std::cell::{Cell, RefCell};
+
+pub mod btb;
+pub mod ras;
+
+const BIMODAL_ENTRIES: usize = 1024;
+const GSHARE_HISTORY_BITS: u32 = 10;
+
+// the direction of conditional branches, jumps and their
+// targets are handled by the fetch stage with the BTB and
+// the return address stack
+pub trait BranchPredictor {
+    // target is where the branch at pc goes if taken
+    fn predict(&self, pc: u32, target: u32) -> bool;
+    // once Execute has resolved the branch
+    fn update(&self, pc: u32, taken: bool);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BranchStats {
+    pub branches: u64,
+    pub branch_mispredictions: u64,
+    // jalr, including returns
+    pub indirect_jumps: u64,
+    pub indirect_mispredictions: u64,
+}
+
+// by the name used on the command line
+pub fn from_name(
+    name: &str,
+) -> Option<Box<dyn BranchPredictor>> {
+    match name {
+        "not-taken" => Some(Box::new(NotTaken)),
+        "btfn" => Some(Box::new(Btfn)),
+        "bimodal" => {
+            Some(Box::new(Bimodal::new(BIMODAL_ENTRIES)))
+        }
+        "gshare" => {
+            Some(Box::new(Gshare::new(GSHARE_HISTORY_BITS)))
+        }
+        "tournament" => Some(Box::new(Tournament::new(
+            BIMODAL_ENTRIES,
+            GSHARE_HISTORY_BITS,
+        ))),
+        _ => None,
+    }
+}
+
+// 2-bit saturating counters, taken from 2 on
+fn is_taken(counter: u8) -> bool {
+    counter >= 2
+}
+
+fn train(counter: &mut u8, taken: bool) {
+    *counter = if taken {
+        (*counter + 1).min(3)
+    } else {
+        counter.saturating_sub(1)
+    };
+}
+
+// instructions are at least 2-byte aligned
+fn index(pc: u32, entries: usize) -> usize {
+    (pc >> 1) as usize & (entries - 1)
+}
+
+pub struct NotTaken;
+
+impl BranchPredictor for NotTaken {
+    fn predict(&self, _pc: u32, _target: u32) -> bool {
+        false
+    }
+
+    fn update(&self, _pc: u32, _taken: bool) {}
+}
+
+// backward taken, forward not taken, i.e. loops are assumed
+// to go round again
+pub struct Btfn;
+
+impl BranchPredictor for Btfn {
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        target <= pc
+    }
+
+    fn update(&self, _pc: u32, _taken: bool) {}
+}
+
+// a table of counters indexed by the pc
+pub struct Bimodal {
+    counters: RefCell<Vec<u8>>,
+}
+
+impl Bimodal {
+    // entries has to be a power of 2
+    pub fn new(entries: usize) -> Self {
+        Self {
+            counters: RefCell::new(vec![1; entries]),
+        }
+    }
+}
+
+impl BranchPredictor for Bimodal {
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        let counters = self.counters.borrow();
+        is_taken(counters[index(pc, counters.len())])
+    }
+
+    fn update(&self, pc: u32, taken: bool) {
+        let mut counters = self.counters.borrow_mut();
+        let i = index(pc, counters.len());
+        train(&mut counters[i], taken);
+    }
+}
+
+// counters indexed by the pc xor the outcomes of the last
+// branches. The history is updated when a branch resolves,
+// not when it is predicted
+pub struct Gshare {
+    counters: RefCell<Vec<u8>>,
+    history: Cell<u32>,
+    history_bits: u32,
+}
+
+impl Gshare {
+    pub fn new(history_bits: u32) -> Self {
+        Self {
+            counters: RefCell::new(vec![
+                1;
+                1 << history_bits
+            ]),
+            history: Cell::new(0),
+            history_bits,
+        }
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        index(
+            pc ^ (self.history.get() << 1),
+            1 << self.history_bits,
+        )
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn predict(&self, pc: u32, _target: u32) -> bool {
+        is_taken(self.counters.borrow()[self.index(pc)])
+    }
+
+    fn update(&self, pc: u32, taken: bool) {
+        let i = self.index(pc);
+        train(&mut self.counters.borrow_mut()[i], taken);
+
+        let mask = (1 << self.history_bits) - 1;
+        let history =
+            (self.history.get() << 1) | taken as u32;
+        self.history.set(history & mask);
+    }
+}
+
+// picks bimodal or gshare per branch, by whichever has been
+// right more often
+pub struct Tournament {
+    bimodal: Bimodal,
+    gshare: Gshare,
+    // taken stands for gshare
+    chooser: Bimodal,
+}
+
+impl Tournament {
+    pub fn new(entries: usize, history_bits: u32) -> Self {
+        Self {
+            bimodal: Bimodal::new(entries),
+            gshare: Gshare::new(history_bits),
+            chooser: Bimodal::new(entries),
+        }
+    }
+}
+
+impl BranchPredictor for Tournament {
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        if self.chooser.predict(pc, target) {
+            self.gshare.predict(pc, target)
+        } else {
+            self.bimodal.predict(pc, target)
+        }
+    }
+
+    fn update(&self, pc: u32, taken: bool) {
+        let bimodal = self.bimodal.predict(pc, 0);
+        let gshare = self.gshare.predict(pc, 0);
+        if bimodal != gshare {
+            self.chooser.update(pc, gshare == taken);
+        }
+
+        self.bimodal.update(pc, taken);
+        self.gshare.update(pc, taken);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    // mispredictions of a branch at 0x100 over outcomes
+    let run = |predictor: &dyn BranchPredictor,
+               target: u32,
+               outcomes: &[bool]| {
+        outcomes
+            .iter()
+            .filter(|&&taken| {
+                let prediction =
+                    predictor.predict(0x100, target);
+                predictor.update(0x100, taken);
+                prediction != taken
+            })
+            .count()
+    };
+
+    let taken = [true; 8];
+    let alternating: Vec<bool> =
+        (0..64).map(|i| i % 2 == 0).collect();
+
+    assert_eq!(run(&NotTaken, 0x80, &taken), 8);
+    assert_eq!(run(&Btfn, 0x80, &taken), 0);
+    assert_eq!(run(&Btfn, 0x180, &taken), 8);
+    assert_eq!(run(&Bimodal::new(16), 0x80, &taken), 1);
+
+    // the history tells the two directions apart
+    assert!(
+        run(&Bimodal::new(16), 0x80, &alternating) >= 32
+    );
+    assert!(run(&Gshare::new(4), 0x80, &alternating) <= 4);
+    assert!(
+        run(&Tournament::new(16, 4), 0x80, &alternating)
+            <= 8
+    );
+
+    assert!(from_name("gshare").is_some());
+    assert!(from_name("perceptron").is_none());
+}
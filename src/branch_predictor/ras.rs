@@ -0,0 +1,52 @@
+use std::cell::RefCell;
+
+pub const RAS_DEPTH: usize = 8;
+
+// return address stack, the oldest entry is dropped when it
+// overflows
+pub struct Ras {
+    entries: RefCell<Vec<u32>>,
+}
+
+impl Ras {
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new(Vec::with_capacity(
+                RAS_DEPTH,
+            )),
+        }
+    }
+
+    pub fn push(&self, addr: u32) {
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() == RAS_DEPTH {
+            entries.remove(0);
+        }
+        entries.push(addr);
+    }
+
+    pub fn pop(&self) -> Option<u32> {
+        self.entries.borrow_mut().pop()
+    }
+}
+
+impl Default for Ras {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let ras = Ras::new();
+    assert_eq!(ras.pop(), None);
+
+    for addr in 0..(RAS_DEPTH as u32 + 2) {
+        ras.push(addr);
+    }
+    for addr in (2..(RAS_DEPTH as u32 + 2)).rev() {
+        assert_eq!(ras.pop(), Some(addr));
+    }
+    assert_eq!(ras.pop(), None);
+}
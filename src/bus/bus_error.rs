@@ -1,9 +1,35 @@
-use std::fmt;
+use super::mmio_device::DeviceError;
+use std::{fmt, num::NonZeroU32};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BusError {
     LoadAddrMisaligned(usize),
     StoreAddrMisaligned(usize, u32),
+
+    LoadAccessFault(usize, AccessFault),
+    StoreAccessFault(usize, u32, AccessFault),
+    FetchAccessFault(usize, AccessFault),
+}
+
+// numbers the faults the bus has logged, so that a trap
+// stays as small as it is without one
+pub type FaultId = NonZeroU32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessFault {
+    // no device, or the access runs past the end of one
+    Unmapped,
+    // the device rejected the access
+    Device(DeviceError),
+}
+
+impl fmt::Display for AccessFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AccessFault::Unmapped => write!(f, "unmapped"),
+            AccessFault::Device(e) => write!(f, "{}", e),
+        }
+    }
 }
 
 impl std::error::Error for BusError {}
@@ -21,6 +47,41 @@ impl fmt::Display for BusError {
                     addr, val
                 )
             }
+            BusError::LoadAccessFault(addr, e) => {
+                write!(f, "Read Fault at {:#010x}: {}", addr, e)
+            }
+            BusError::StoreAccessFault(addr, val, e) => {
+                write!(
+                    f,
+                    "Write Fault at {:#010x} with value {}: {}",
+                    addr, val, e
+                )
+            }
+            BusError::FetchAccessFault(addr, e) => {
+                write!(f, "Fetch Fault at {:#010x}: {}", addr, e)
+            }
+        }
+    }
+}
+
+// raised when a device is registered on the bus
+#[derive(Debug)]
+pub enum MapError {
+    Overlap(String, String),
+    OutOfRange(String),
+}
+
+impl std::error::Error for MapError {}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Overlap(name, other) => {
+                write!(f, "Device {} overlaps {}", name, other)
+            }
+            MapError::OutOfRange(name) => {
+                write!(f, "Device {} is out of range", name)
+            }
         }
     }
 }
@@ -0,0 +1,208 @@
+use super::mmio_device::{DeviceError, MMIODevice};
+use crate::pipeline::memory_access::MemoryAccessWidth;
+use std::cell::Cell;
+
+pub const CLINT_START: usize = 0x0200_0000;
+
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+// register offsets of hart 0, the 64-bit ones are split into
+// two words
+const MSIP: usize = 0x0000;
+const MTIMECMP: usize = 0x4000;
+const MTIMECMPH: usize = 0x4004;
+const MTIME: usize = 0xbff8;
+const MTIMEH: usize = 0xbffc;
+
+fn set_low(reg: &Cell<u64>, val: u32) {
+    reg.set(
+        (reg.get() & 0xffff_ffff_0000_0000) | val as u64,
+    );
+}
+
+fn set_high(reg: &Cell<u64>, val: u32) {
+    reg.set((reg.get() & 0xffff_ffff) | (val as u64) << 32);
+}
+
+// the core-local interruptor: the machine timer and software
+// interrupts of a single hart. mtime counts retired
+// instructions rather than wall-clock time, so that runs are
+// reproducible
+pub struct Clint {
+    msip: Cell<bool>,
+    mtimecmp: Cell<u64>,
+    mtime: Cell<u64>,
+
+    // retired instructions per mtime tick
+    time_base: Cell<u64>,
+    // retired since the last tick
+    retired: Cell<u64>,
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Self {
+            msip: Cell::new(false),
+            // no timer interrupt until software sets one up
+            mtimecmp: Cell::new(u64::MAX),
+            mtime: Cell::new(0),
+
+            time_base: Cell::new(1),
+            retired: Cell::new(0),
+        }
+    }
+
+    pub fn set_time_base(
+        &self,
+        instructions_per_tick: u64,
+    ) {
+        self.time_base.set(instructions_per_tick.max(1));
+        self.retired.set(0);
+    }
+
+    pub fn advance(&self, retired: u64) {
+        let time_base = self.time_base.get();
+        let retired = self.retired.get() + retired;
+        self.mtime.set(
+            self.mtime
+                .get()
+                .wrapping_add(retired / time_base),
+        );
+        self.retired.set(retired % time_base);
+    }
+
+    pub fn get_mtime(&self) -> u64 {
+        self.mtime.get()
+    }
+
+    pub fn is_timer_pending(&self) -> bool {
+        self.mtime.get() >= self.mtimecmp.get()
+    }
+
+    pub fn is_software_pending(&self) -> bool {
+        self.msip.get()
+    }
+
+    // how many instructions may retire before the timer
+    // interrupt becomes pending, unbounded while it already
+    // is
+    pub fn instructions_until_timer(&self) -> u64 {
+        if self.is_timer_pending() {
+            return u64::MAX;
+        }
+        let ticks = self.mtimecmp.get() - self.mtime.get();
+        ticks
+            .saturating_mul(self.time_base.get())
+            .saturating_sub(self.retired.get())
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        match offset {
+            MSIP => self.msip.get() as u32,
+            MTIMECMP => self.mtimecmp.get() as u32,
+            MTIMECMPH => (self.mtimecmp.get() >> 32) as u32,
+            MTIME => self.mtime.get() as u32,
+            MTIMEH => (self.mtime.get() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        match offset {
+            MSIP => self.msip.set(val & 1 != 0),
+            MTIMECMP => set_low(&self.mtimecmp, val),
+            MTIMECMPH => set_high(&self.mtimecmp, val),
+            MTIME => set_low(&self.mtime, val),
+            MTIMEH => set_high(&self.mtime, val),
+            _ => {} // harts that do not exist
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the registers only take word accesses
+impl MMIODevice for Clint {
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => {
+                Ok(self.read_reg(offset))
+            }
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        self.check_write(offset, width)?;
+        self.write_reg(offset, val);
+        Ok(())
+    }
+
+    fn check_write(
+        &self,
+        _offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => Ok(()),
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    const WORD: MemoryAccessWidth = MemoryAccessWidth::Word;
+
+    let clint = Clint::new();
+    let read = |reg| clint.read(reg, WORD).unwrap();
+    let write =
+        |reg, val| clint.write(reg, val, WORD).unwrap();
+
+    assert!(!clint.is_timer_pending());
+    write(MTIMECMP, 10);
+    write(MTIMECMPH, 0);
+    assert_eq!(clint.instructions_until_timer(), 10);
+
+    clint.set_time_base(4);
+    assert_eq!(clint.instructions_until_timer(), 40);
+    clint.advance(6);
+    assert_eq!(read(MTIME), 1);
+    assert_eq!(clint.instructions_until_timer(), 34);
+    clint.advance(34);
+    assert_eq!(read(MTIME), 10);
+    assert!(clint.is_timer_pending());
+    assert_eq!(clint.instructions_until_timer(), u64::MAX);
+
+    // mtime is writable as well
+    write(MTIMEH, 1);
+    assert_eq!(clint.get_mtime(), (1 << 32) + 10);
+    write(MTIMECMPH, 2);
+    assert!(!clint.is_timer_pending());
+
+    write(MSIP, 0xff);
+    assert_eq!(read(MSIP), 1);
+    assert!(clint.is_software_pending());
+    write(MSIP, 0);
+    assert!(!clint.is_software_pending());
+
+    let byte = MemoryAccessWidth::Byte;
+    assert_eq!(
+        clint.read(MSIP, byte),
+        Err(DeviceError::UnsupportedWidth(byte))
+    );
+}
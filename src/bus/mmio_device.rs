@@ -1,4 +1,146 @@
+use crate::pipeline::memory_access::MemoryAccessWidth;
+use std::{fmt, rc::Rc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    UnsupportedWidth(MemoryAccessWidth),
+    // a store to ROM
+    ReadOnly,
+}
+
+impl std::error::Error for DeviceError {}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::UnsupportedWidth(width) => {
+                write!(
+                    f,
+                    "{:?} access not supported",
+                    width
+                )
+            }
+            DeviceError::ReadOnly => {
+                write!(f, "store to read-only memory")
+            }
+        }
+    }
+}
+
+// offset is in bytes from the base of the device, values are
+// right-aligned and width bytes wide
 pub trait MMIODevice {
-    fn read(&self, location: usize) -> u32;
-    fn write(&self, location: usize, val: u32);
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError>;
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError>;
+
+    // whether write would accept the access, without doing
+    // it. A store split into several accesses checks all of
+    // them before the first one takes effect
+    fn check_write(
+        &self,
+        _offset: usize,
+        _width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+// lets the owner keep a handle to a registered device
+impl<T: MMIODevice> MMIODevice for Rc<T> {
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        (**self).read(offset, width)
+    }
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        (**self).write(offset, val, width)
+    }
+
+    fn check_write(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        (**self).check_write(offset, width)
+    }
+}
+
+// an interrupt controller, which numbers the lines of its
+// sources
+pub trait InterruptSink {
+    fn set_level(&self, source: u32, level: bool);
+}
+
+// the level-triggered line a device raises while it wants
+// the attention of the hart
+#[derive(Clone)]
+pub struct IrqLine {
+    sink: Rc<dyn InterruptSink>,
+    source: u32,
+}
+
+impl IrqLine {
+    pub fn new(
+        sink: Rc<dyn InterruptSink>,
+        source: u32,
+    ) -> Self {
+        Self { sink, source }
+    }
+
+    pub fn set_level(&self, level: bool) {
+        self.sink.set_level(self.source, level);
+    }
+
+    pub fn raise(&self) {
+        self.set_level(true);
+    }
+
+    pub fn lower(&self) {
+        self.set_level(false);
+    }
+}
+
+// little-endian helpers for byte-array backed devices, bytes
+// past the end read as 0 and are not written
+pub fn read_bytes(
+    bytes: &[u8],
+    offset: usize,
+    width: MemoryAccessWidth,
+) -> u32 {
+    (0..width.size()).fold(0, |val, i| {
+        let byte =
+            bytes.get(offset + i).copied().unwrap_or(0);
+        val | (byte as u32) << (8 * i)
+    })
+}
+
+pub fn write_bytes(
+    bytes: &mut [u8],
+    offset: usize,
+    val: u32,
+    width: MemoryAccessWidth,
+) {
+    for i in 0..width.size() {
+        if let Some(byte) = bytes.get_mut(offset + i) {
+            *byte = (val >> (8 * i)) as u8;
+        }
+    }
 }
@@ -1,15 +1,25 @@
 use self::{
-    bus_error::BusError, ram::RAMDevice, rom::ROMDevice,
+    bus_error::{AccessFault, BusError, FaultId, MapError},
+    ram::RAMDevice,
+    rom::ROMDevice,
 };
 use crate::{
     bus::mmio_device::MMIODevice,
     pipeline::memory_access::MemoryAccessWidth,
 };
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 pub mod bus_error;
+pub mod clint;
 pub mod mmio_device;
+pub mod plic;
 pub mod ram;
 pub mod rom;
+pub mod test_finisher;
+pub mod uart;
 
 const ADDR_ALIGN: usize = 4;
 
@@ -19,6 +29,9 @@ pub const RAM_START: usize = 0x8000_0000;
 // const _ROM_SIZE: usize = 0x4000_0000;
 pub const RAM_SIZE: usize = 0x1100;
 
+// more than the faults of the instructions in flight
+const FAULT_LOG_SIZE: usize = 16;
+
 pub struct MemoryLayout {
     pub rom_start: usize,
     pub rom_size: usize,
@@ -26,106 +39,231 @@ pub struct MemoryLayout {
     pub ram_size: usize,
 }
 
+// what becomes of an access fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessFaultPolicy {
+    // raise an access-fault exception in the guest
+    Trap,
+    // stop the system and report the faulting access
+    Halt,
+    // legacy: unmapped loads return 0 and stores are
+    // dropped. Fetches and faults raised by devices still
+    // trap
+    ReadAsZero,
+}
+
+struct DeviceRegion {
+    name: String,
+    base: usize,
+    size: usize,
+    device: Box<dyn MMIODevice>,
+}
+
+impl DeviceRegion {
+    fn end(&self) -> usize {
+        self.base + self.size
+    }
+}
+
 pub struct Bus {
-    ram: RAMDevice,
-    rom: ROMDevice,
+    ram: Rc<RAMDevice>,
     pub memory_layout: MemoryLayout,
+
+    // sorted by base address and never overlapping, so a
+    // binary search finds the device for an address
+    regions: RefCell<Vec<DeviceRegion>>,
+
+    // the word reserved by LR.W, any write to it in between
+    // makes the paired SC.W fail
+    reservation: RefCell<Option<usize>>,
+
+    // the HTIF tohost word of the program, if it has one,
+    // and the exit code once written there
+    tohost: Cell<Option<usize>>,
+    tohost_exit: Cell<Option<u32>>,
+
+    fault_policy: Cell<AccessFaultPolicy>,
+
+    // the last faults, for the host to tell what has halted
+    // the system
+    fault_log:
+        RefCell<[Option<(FaultId, BusError)>; FAULT_LOG_SIZE]>,
+    faults: Cell<u32>,
 }
 
 impl Bus {
     pub fn new(file: &[u32]) -> Self {
-        Self {
-            ram: RAMDevice::new(RAM_SIZE),
-            rom: ROMDevice::new(file),
+        let ram = Rc::new(RAMDevice::new(RAM_SIZE));
+        let bus = Self {
+            ram: ram.clone(),
             memory_layout: MemoryLayout {
                 rom_start: ROM_START,
                 rom_size: file.len() * ADDR_ALIGN,
                 ram_start: RAM_START,
                 ram_size: RAM_SIZE,
             },
-        }
+
+            regions: RefCell::new(Vec::new()),
+            reservation: RefCell::new(None),
+            tohost: Cell::new(None),
+            tohost_exit: Cell::new(None),
+            fault_policy: Cell::new(AccessFaultPolicy::Trap),
+
+            fault_log: RefCell::new([None; FAULT_LOG_SIZE]),
+            faults: Cell::new(0),
+        };
+
+        let rom = Box::new(ROMDevice::new(file));
+        let rom_size = bus.memory_layout.rom_size;
+        bus.register("rom", ROM_START, rom_size, rom)
+            .expect("ROM overlaps RAM");
+        bus.register("ram", RAM_START, RAM_SIZE, Box::new(ram))
+            .expect("RAM overlaps ROM");
+
+        bus
     }
 
-    fn read_chunk(&self, addr: usize) -> u32 {
-        if addr >= self.memory_layout.rom_start
-            && addr
-                < self.memory_layout.rom_start
-                    + self.memory_layout.rom_size
-        {
-            self.rom
-                .read((addr - self.memory_layout.rom_start) >> 2)
-        } else if addr >= self.memory_layout.ram_start
-            && addr
-                < self.memory_layout.ram_start
-                    + self.memory_layout.ram_size
-        {
-            self.ram
-                .read((addr - self.memory_layout.ram_start) >> 2)
-        } else {
-            0_u32
+    // maps size bytes at base to the device, which sees byte
+    // offsets relative to base
+    pub fn register(
+        &self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Box<dyn MMIODevice>,
+    ) -> Result<(), MapError> {
+        if base.checked_add(size).is_none() {
+            return Err(MapError::OutOfRange(name.to_string()));
+        }
+
+        let mut regions = self.regions.borrow_mut();
+        let idx = regions.partition_point(|r| r.base < base);
+
+        // only the neighbours can overlap a sorted list
+        let prev = idx.checked_sub(1).map(|i| &regions[i]);
+        let next = regions.get(idx);
+        let overlapping = prev
+            .filter(|r| r.end() > base)
+            .or(next.filter(|r| r.base < base + size));
+        if let Some(other) = overlapping {
+            return Err(MapError::Overlap(
+                name.to_string(),
+                other.name.clone(),
+            ));
         }
+
+        regions.insert(
+            idx,
+            DeviceRegion {
+                name: name.to_string(),
+                base,
+                size,
+                device,
+            },
+        );
+        Ok(())
     }
 
-    fn write_chunk(&self, addr: usize, val: u32) {
-        if addr >= self.memory_layout.rom_start
-            && addr
-                < self.memory_layout.rom_start
-                    + self.memory_layout.rom_size
-        {
-            self.rom.write(
-                (addr - self.memory_layout.rom_start) >> 2,
-                val,
-            );
-        } else if addr >= self.memory_layout.ram_start
-            && addr
-                < self.memory_layout.ram_start
-                    + self.memory_layout.ram_size
-        {
-            self.ram.write(
-                (addr - self.memory_layout.ram_start) >> 2,
-                val,
-            );
+    // (name, base, size) of every device in address order
+    pub fn device_map(&self) -> Vec<(String, usize, usize)> {
+        self.regions
+            .borrow()
+            .iter()
+            .map(|r| (r.name.clone(), r.base, r.size))
+            .collect()
+    }
+
+    pub fn set_tohost(&self, addr: usize) {
+        self.tohost.set(Some(addr));
+    }
+
+    // set once the guest has asked to exit through tohost
+    pub fn get_tohost_exit(&self) -> Option<u32> {
+        self.tohost_exit.get()
+    }
+
+    pub fn set_fault_policy(&self, policy: AccessFaultPolicy) {
+        self.fault_policy.set(policy);
+    }
+
+    pub fn get_fault_policy(&self) -> AccessFaultPolicy {
+        self.fault_policy.get()
+    }
+
+    // runs f on the device covering the whole access with the
+    // byte offset inside it
+    fn with_device<T>(
+        &self,
+        addr: usize,
+        width: MemoryAccessWidth,
+        f: impl FnOnce(&dyn MMIODevice, usize) -> T,
+    ) -> Result<T, AccessFault> {
+        let regions = self.regions.borrow();
+        let idx = regions.partition_point(|r| r.base <= addr);
+        let region = idx
+            .checked_sub(1)
+            .map(|i| &regions[i])
+            .filter(|r| addr + width.size() <= r.end())
+            .ok_or(AccessFault::Unmapped)?;
+        Ok(f(&*region.device, addr - region.base))
+    }
+
+    fn is_misaligned(
+        addr: usize,
+        width: MemoryAccessWidth,
+    ) -> bool {
+        match width {
+            MemoryAccessWidth::Byte => false,
+            MemoryAccessWidth::HalfWord => addr & 0b01 != 0,
+            MemoryAccessWidth::Word => addr & 0b11 != 0,
         }
     }
 
+    fn is_ignored(&self, fault: AccessFault) -> bool {
+        fault == AccessFault::Unmapped
+            && self.fault_policy.get()
+                == AccessFaultPolicy::ReadAsZero
+    }
+
     pub fn read(
         &self,
         addr: usize,
         width: MemoryAccessWidth,
     ) -> Result<u32, BusError> {
-        let val = self.read_chunk(addr);
-        let offset = addr & 0b11;
-
-        let read_result = match width {
-            MemoryAccessWidth::Byte => {
-                match offset {
-                    0b00 => val & 0x0000_00FF,
-                    0b01 => (val & 0x0000_FF00) >> 8,
-                    0b10 => (val & 0x00FF_0000) >> 16,
-                    0b11 => (val & 0xFF00_0000) >> 24,
-                    _ => 0_u32, // should never happen
-                }
-            }
-            MemoryAccessWidth::HalfWord => match offset {
-                0b00 => val & 0x0000_FFFF,
-                0b01 => (val & 0xFFFF_0000) >> 16,
-                _ => {
-                    return Err(BusError::LoadAddrMisaligned(
-                        addr,
-                    ));
-                }
-            },
-            MemoryAccessWidth::Word => match offset {
-                0b00 => val,
-                _ => {
-                    return Err(BusError::LoadAddrMisaligned(
-                        addr,
-                    ));
-                }
-            },
-        };
+        if Self::is_misaligned(addr, width) {
+            return Err(BusError::LoadAddrMisaligned(addr));
+        }
 
-        Ok(read_result)
+        let result = self.read_device(addr, width);
+        match result {
+            Err(fault) if self.is_ignored(fault) => Ok(0),
+            _ => result
+                .map_err(|e| BusError::LoadAccessFault(addr, e)),
+        }
+    }
+
+    fn read_device(
+        &self,
+        addr: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, AccessFault> {
+        self.with_device(addr, width, |device, offset| {
+            device.read(offset, width)
+        })
+        .and_then(|r| r.map_err(AccessFault::Device))
+    }
+
+    // an aligned instruction word. Fetches fault whatever the
+    // policy, running into unmapped memory must not execute
+    // zeros
+    pub fn fetch(&self, addr: usize) -> Result<u32, BusError> {
+        let width = MemoryAccessWidth::Word;
+        if Self::is_misaligned(addr, width) {
+            return Err(BusError::LoadAddrMisaligned(addr));
+        }
+
+        self.read_device(addr, width)
+            .map_err(|e| BusError::FetchAccessFault(addr, e))
     }
 
     pub fn write(
@@ -134,52 +272,154 @@ impl Bus {
         val: u32,
         width: MemoryAccessWidth,
     ) -> Result<(), BusError> {
-        let chunk = self.read_chunk(addr);
-        let offset = addr & 0b11;
-
-        let write_val = match width {
-            MemoryAccessWidth::Byte => {
-                match offset {
-                    0b00 => (chunk & 0xFFFF_FF00) | (val & 0xFF),
-                    0b01 => {
-                        (chunk & 0xFFFF_00FF)
-                            | ((val & 0xFF) << 8)
-                    }
-                    0b10 => {
-                        (chunk & 0xFF00_FFFF)
-                            | ((val & 0xFF) << 16)
-                    }
-                    0b11 => {
-                        (chunk & 0x00FF_FFFF)
-                            | ((val & 0xFF) << 24)
-                    }
-                    _ => 0_u32, // should never happen
-                }
-            }
-            MemoryAccessWidth::HalfWord => match offset {
-                0b00 => (chunk & 0xFFFF_0000) | (val & 0xFFFF),
-                0b01 => {
-                    (chunk & 0x0000_FFFF)
-                        | ((val & 0xFFFF) << 16)
-                }
-                _ => {
-                    return Err(BusError::StoreAddrMisaligned(
-                        addr, val,
-                    ));
-                }
-            },
-            MemoryAccessWidth::Word => match offset {
-                0b00 => val,
-                _ => {
-                    return Err(BusError::StoreAddrMisaligned(
-                        addr, val,
-                    ));
-                }
-            },
-        };
+        if Self::is_misaligned(addr, width) {
+            return Err(BusError::StoreAddrMisaligned(addr, val));
+        }
+
+        let result = self
+            .with_device(addr, width, |device, offset| {
+                device.write(offset, val, width)
+            })
+            .and_then(|r| r.map_err(AccessFault::Device));
+        match result {
+            Err(fault) if self.is_ignored(fault) => {}
+            _ => result.map_err(|e| {
+                BusError::StoreAccessFault(addr, val, e)
+            })?,
+        }
+
+        let reserved = *self.reservation.borrow();
+        if reserved.is_some_and(|r| r >> 2 == addr >> 2) {
+            self.reservation.replace(None);
+        }
+
+        // bit 0 marks an exit request, the rest is the code.
+        // Anything else would be a syscall for the host
+        if self.tohost.get() == Some(addr)
+            && width == MemoryAccessWidth::Word
+            && val & 1 != 0
+            && self.tohost_exit.get().is_none()
+        {
+            self.tohost_exit.set(Some(val >> 1));
+        }
+
+        Ok(())
+    }
+
+    // whether write would fault, without doing it
+    pub fn check_write(
+        &self,
+        addr: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), BusError> {
+        if Self::is_misaligned(addr, width) {
+            return Err(BusError::StoreAddrMisaligned(addr, val));
+        }
+
+        let result = self
+            .with_device(addr, width, |device, offset| {
+                device.check_write(offset, width)
+            })
+            .and_then(|r| r.map_err(AccessFault::Device));
+        match result {
+            Err(fault) if self.is_ignored(fault) => Ok(()),
+            _ => result.map_err(|e| {
+                BusError::StoreAccessFault(addr, val, e)
+            }),
+        }
+    }
+
+    pub fn load_reserved(
+        &self,
+        addr: usize,
+    ) -> Result<u32, BusError> {
+        let val = self.read(addr, MemoryAccessWidth::Word)?;
+        self.reservation.replace(Some(addr));
+        Ok(val)
+    }
+
+    // returns whether the store has been performed, the
+    // reservation is released either way
+    pub fn store_conditional(
+        &self,
+        addr: usize,
+        val: u32,
+    ) -> Result<bool, BusError> {
+        if addr & 0b11 != 0 {
+            return Err(BusError::StoreAddrMisaligned(addr, val));
+        }
 
-        self.write_chunk(addr, write_val);
+        let is_reserved = self.reservation.take() == Some(addr);
+        if is_reserved {
+            self.write(addr, val, MemoryAccessWidth::Word)?;
+        }
+        Ok(is_reserved)
+    }
 
+    // read-modify-write of a word with nothing in between,
+    // returns the old value
+    pub fn amo(
+        &self,
+        addr: usize,
+        op: impl FnOnce(u32) -> u32,
+    ) -> Result<u32, BusError> {
+        if addr & 0b11 != 0 {
+            return Err(BusError::StoreAddrMisaligned(addr, 0));
+        }
+
+        let old = self.read(addr, MemoryAccessWidth::Word)?;
+        self.write(addr, op(old), MemoryAccessWidth::Word)?;
+        Ok(old)
+    }
+
+    // for the trap raised for err to refer to
+    pub fn log_fault(&self, err: BusError) -> FaultId {
+        let count = self.faults.get().wrapping_add(1);
+        self.faults.set(count);
+        let id = FaultId::new(count).unwrap_or(FaultId::MIN);
+        let slot = count as usize % FAULT_LOG_SIZE;
+        self.fault_log.borrow_mut()[slot] = Some((id, err));
+        id
+    }
+
+    // None once it has been pushed out of the log
+    pub fn get_fault(&self, id: FaultId) -> Option<BusError> {
+        let slot = id.get() as usize % FAULT_LOG_SIZE;
+        self.fault_log.borrow()[slot]
+            .filter(|&(logged, _)| logged == id)
+            .map(|(_, err)| err)
+    }
+
+    pub fn is_ram(&self, addr: usize, size: usize) -> bool {
+        let layout = &self.memory_layout;
+        addr >= layout.ram_start
+            && addr + size <= layout.ram_start + layout.ram_size
+    }
+
+    // RAM or ROM, which unlike a device only change when
+    // written to
+    pub fn is_memory(&self, addr: usize, size: usize) -> bool {
+        let layout = &self.memory_layout;
+        let is_rom = addr >= layout.rom_start
+            && addr + size <= layout.rom_start + layout.rom_size;
+        is_rom || self.is_ram(addr, size)
+    }
+
+    // copies a program segment in, the rest up to mem_size
+    // is zeroed (.bss)
+    pub fn load(
+        &self,
+        addr: usize,
+        data: &[u8],
+        mem_size: usize,
+    ) -> Result<(), BusError> {
+        let bytes =
+            data.iter().copied().chain(std::iter::repeat(0));
+        for (i, byte) in bytes.take(mem_size).enumerate() {
+            let width = MemoryAccessWidth::Byte;
+            self.write(addr + i, byte as u32, width)?;
+        }
         Ok(())
     }
 
@@ -210,12 +450,8 @@ fn test() {
         val: u32,
         width: MemoryAccessWidth,
     ) {
-        let ret = bus.write(addr, val, width);
-        match ret {
-            Err(e) => {
-                println!("{}", e)
-            }
-            _ => (),
+        if let Err(e) = bus.write(addr, val, width) {
+            println!("{}", e)
         }
     }
 
@@ -253,3 +489,295 @@ fn test() {
     test_write(&bus, 0x8000_0001, 1, MemoryAccessWidth::Byte);
     test_read(&bus, 0x8000_0001, MemoryAccessWidth::Byte);
 }
+
+#[cfg(test)]
+#[test]
+fn test_atomic() {
+    let bus = Bus::new(&[]);
+    let addr = RAM_START + 0x10;
+    let width = MemoryAccessWidth::Word;
+
+    bus.write(addr, 5, width).unwrap();
+    assert_eq!(bus.amo(addr, |old| old + 2).unwrap(), 5);
+    assert_eq!(bus.read(addr, width).unwrap(), 7);
+
+    assert_eq!(bus.load_reserved(addr).unwrap(), 7);
+    assert!(bus.store_conditional(addr, 1).unwrap());
+    assert!(!bus.store_conditional(addr, 2).unwrap());
+    assert_eq!(bus.read(addr, width).unwrap(), 1);
+
+    // a store to the reserved word breaks the reservation
+    bus.load_reserved(addr).unwrap();
+    bus.write(addr + 2, 0, MemoryAccessWidth::Byte).unwrap();
+    assert!(!bus.store_conditional(addr, 3).unwrap());
+
+    assert!(bus.amo(addr + 2, |old| old).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_load() {
+    let bus = Bus::new(&[]);
+    let width = MemoryAccessWidth::Word;
+
+    bus.write(RAM_START + 8, 0xdead_beef, width).unwrap();
+    let data = [0x11, 0x22, 0x33, 0x44, 0x55];
+    bus.load(RAM_START + 1, &data, 10).unwrap();
+
+    let read = |addr| bus.read(addr, width).unwrap();
+    assert_eq!(read(RAM_START), 0x3322_1100);
+    assert_eq!(read(RAM_START + 4), 0x0000_5544);
+    assert_eq!(read(RAM_START + 8), 0xde00_0000); // zeroed
+
+    assert!(bus.is_ram(RAM_START, RAM_SIZE));
+    assert!(!bus.is_ram(RAM_START, RAM_SIZE + 1));
+    assert!(!bus.is_ram(ROM_START, 4));
+}
+
+#[cfg(test)]
+#[test]
+fn test_register() {
+    let bus = Bus::new(&[1, 2]);
+    let width = MemoryAccessWidth::Word;
+
+    // a dozen small RAMs between ROM and RAM
+    for i in 0..12 {
+        let base = 0x1000_0000 + i * 0x100;
+        let ram = Box::new(RAMDevice::new(0x10));
+        bus.register(&format!("dev{}", i), base, 0x10, ram)
+            .unwrap();
+        bus.write(base + 4, i as u32, width).unwrap();
+    }
+    for i in 0..12 {
+        let base = 0x1000_0000 + i * 0x100;
+        assert_eq!(bus.read(base + 4, width).unwrap(), i as u32);
+    }
+    assert!(bus.read(0x1000_0010, width).is_err());
+    assert_eq!(bus.read(ROM_START + 4, width).unwrap(), 2);
+
+    let map = bus.device_map();
+    assert_eq!(map.len(), 14);
+    assert_eq!(map[0].0, "dev0");
+    assert_eq!(map[13], (String::from("ram"), RAM_START, RAM_SIZE));
+
+    let ram = || Box::new(RAMDevice::new(4));
+    assert!(matches!(
+        bus.register("a", 0x1000_000c, 0x10, ram()),
+        Err(MapError::Overlap(_, other)) if other == "dev0"
+    ));
+    assert!(matches!(
+        bus.register("b", 0x1000_00f8, 0x10, ram()),
+        Err(MapError::Overlap(_, other)) if other == "dev1"
+    ));
+    assert!(matches!(
+        bus.register("c", RAM_START - 4, 8, ram()),
+        Err(MapError::Overlap(_, other)) if other == "ram"
+    ));
+    assert!(matches!(
+        bus.register("d", usize::MAX, 2, ram()),
+        Err(MapError::OutOfRange(_))
+    ));
+    bus.register("e", 0x1000_0010, 0xf0, ram()).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_device_access() {
+    use mmio_device::DeviceError;
+    use MemoryAccessWidth::{Byte, HalfWord, Word};
+
+    // a register block with side effects on every access,
+    // half-word accesses are not supported
+    struct Recorder {
+        accesses: RefCell<Vec<(bool, usize, u32, usize)>>,
+    }
+
+    impl MMIODevice for Recorder {
+        fn read(
+            &self,
+            offset: usize,
+            width: MemoryAccessWidth,
+        ) -> Result<u32, DeviceError> {
+            if width == HalfWord {
+                return Err(DeviceError::UnsupportedWidth(width));
+            }
+            self.accesses.borrow_mut().push((
+                false,
+                offset,
+                0,
+                width.size(),
+            ));
+            Ok(0)
+        }
+
+        fn write(
+            &self,
+            offset: usize,
+            val: u32,
+            width: MemoryAccessWidth,
+        ) -> Result<(), DeviceError> {
+            if width == HalfWord {
+                return Err(DeviceError::UnsupportedWidth(width));
+            }
+            self.accesses.borrow_mut().push((
+                true,
+                offset,
+                val,
+                width.size(),
+            ));
+            Ok(())
+        }
+    }
+
+    let bus = Bus::new(&[]);
+    let recorder = Rc::new(Recorder {
+        accesses: RefCell::new(Vec::new()),
+    });
+    let base = 0x1000_0000;
+    let device = Box::new(recorder.clone());
+    bus.register("recorder", base, 0x10, device).unwrap();
+
+    // a single write of that width, no read-modify-write
+    bus.write(base + 5, 0xab, Byte).unwrap();
+    bus.write(base + 8, 0x1234_5678, Word).unwrap();
+    assert_eq!(
+        *recorder.accesses.borrow(),
+        [(true, 5, 0xab, 1), (true, 8, 0x1234_5678, 4)]
+    );
+
+    let unsupported = AccessFault::Device(
+        DeviceError::UnsupportedWidth(HalfWord),
+    );
+    assert_eq!(
+        bus.write(base + 2, 1, HalfWord),
+        Err(BusError::StoreAccessFault(base + 2, 1, unsupported))
+    );
+    assert_eq!(
+        bus.read(base + 2, HalfWord),
+        Err(BusError::LoadAccessFault(base + 2, unsupported))
+    );
+    // a device fault is not an unmapped access to ignore
+    bus.set_fault_policy(AccessFaultPolicy::ReadAsZero);
+    assert!(bus.read(base + 2, HalfWord).is_err());
+    assert_eq!(recorder.accesses.borrow().len(), 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_access_fault() {
+    use mmio_device::DeviceError;
+
+    let bus = Bus::new(&[1, 2]);
+    let width = MemoryAccessWidth::Word;
+    let unmapped = 0x2000_0000;
+    let ram_end = RAM_START + RAM_SIZE;
+
+    bus.set_fault_policy(AccessFaultPolicy::ReadAsZero);
+    assert_eq!(bus.read(unmapped, width).unwrap(), 0);
+    assert!(bus.write(unmapped, 1, width).is_ok());
+    assert!(bus.fetch(ROM_START + 8).is_err());
+
+    bus.set_fault_policy(AccessFaultPolicy::Trap);
+    let fault = AccessFault::Unmapped;
+    assert!(matches!(
+        bus.read(unmapped, width),
+        Err(BusError::LoadAccessFault(0x2000_0000, f)) if f == fault
+    ));
+    assert!(matches!(
+        bus.write(unmapped, 1, width),
+        Err(BusError::StoreAccessFault(0x2000_0000, 1, _))
+    ));
+    assert!(matches!(
+        bus.fetch(ROM_START + 8),
+        Err(BusError::FetchAccessFault(_, _))
+    ));
+    let read_only = AccessFault::Device(DeviceError::ReadOnly);
+    assert_eq!(
+        bus.write(ROM_START, 1, width),
+        Err(BusError::StoreAccessFault(ROM_START, 1, read_only))
+    );
+
+    // running past the end of a device
+    let ram = Box::new(RAMDevice::new(6));
+    bus.register("short", 0x3000_0000, 6, ram).unwrap();
+    let half = MemoryAccessWidth::HalfWord;
+    assert!(bus.read(0x3000_0004, half).is_ok());
+    assert!(bus.read(0x3000_0004, width).is_err());
+    assert!(bus.read(ram_end, MemoryAccessWidth::Byte).is_err());
+
+    // the log keeps the last faults only
+    let err = bus.read(unmapped, width).unwrap_err();
+    let first = bus.log_fault(err);
+    assert_eq!(bus.get_fault(first), Some(err));
+    for _ in 1..FAULT_LOG_SIZE {
+        bus.log_fault(BusError::LoadAddrMisaligned(1));
+    }
+    assert_eq!(bus.get_fault(first), Some(err));
+    bus.log_fault(BusError::LoadAddrMisaligned(1));
+    assert_eq!(bus.get_fault(first), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_misaligned() {
+    use MemoryAccessWidth::{Byte, HalfWord, Word};
+
+    let bus = Bus::new(&[]);
+    let word = 0x3322_1100_u32;
+    let mask = |width| match width {
+        Byte => 0xFF,
+        HalfWord => 0xFFFF,
+        Word => u32::MAX,
+    };
+
+    for width in [Byte, HalfWord, Word] {
+        for offset in 0..4 {
+            let addr = RAM_START + offset;
+            let aligned = offset % width.size() == 0;
+
+            bus.write(RAM_START, word, Word).unwrap();
+            let read = bus.read(addr, width);
+            if aligned {
+                let val = (word >> (8 * offset)) & mask(width);
+                assert_eq!(read.unwrap(), val);
+            } else {
+                assert!(matches!(
+                    read,
+                    Err(BusError::LoadAddrMisaligned(a)) if a == addr
+                ));
+            }
+
+            // the other bytes of the word are kept
+            let write = bus.write(addr, u32::MAX, width);
+            let after = bus.read(RAM_START, Word).unwrap();
+            if aligned {
+                write.unwrap();
+                let written = mask(width) << (8 * offset);
+                assert_eq!(after, word | written);
+            } else {
+                assert!(matches!(
+                    write,
+                    Err(BusError::StoreAddrMisaligned(a, _))
+                        if a == addr
+                ));
+                assert_eq!(after, word);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_tohost() {
+    let bus = Bus::new(&[]);
+    let width = MemoryAccessWidth::Word;
+    let tohost = RAM_START + 0x100;
+
+    bus.set_tohost(tohost);
+    bus.write(tohost, 0x8000_0010, width).unwrap(); // a syscall
+    bus.write(tohost + 4, 1, width).unwrap();
+    assert_eq!(bus.get_tohost_exit(), None);
+
+    bus.write(tohost, (5 << 1) | 1, width).unwrap();
+    assert_eq!(bus.get_tohost_exit(), Some(5));
+}
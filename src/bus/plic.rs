@@ -0,0 +1,315 @@
+use super::mmio_device::{
+    DeviceError, InterruptSink, MMIODevice,
+};
+use crate::pipeline::memory_access::MemoryAccessWidth;
+use std::cell::{Cell, RefCell};
+
+pub const PLIC_START: usize = 0x0c00_0000;
+
+pub const PLIC_SIZE: usize = 0x400_0000;
+
+// source 0 is reserved, so sources 1 to 31 fit in one word of
+// pending and enable bits
+pub const NUM_SOURCES: usize = 32;
+
+// machine and supervisor mode of hart 0
+pub const NUM_CONTEXTS: usize = 2;
+pub const CONTEXT_MACHINE: usize = 0;
+pub const CONTEXT_SUPERVISOR: usize = 1;
+
+const PRIORITY_MASK: u32 = 0b111;
+
+// register offsets, per source or per context
+const PRIORITY: usize = 0x0000;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004; // complete on write
+const CONTEXT_STRIDE: usize = 0x1000;
+
+// the platform-level interrupt controller, with level-triggered
+// gateways: a source stays pending while its line is high,
+// and once claimed it is held back until completed
+pub struct Plic {
+    priorities: RefCell<[u32; NUM_SOURCES]>,
+    levels: Cell<u32>,
+    pending: Cell<u32>,
+    // claimed and not yet completed
+    in_service: Cell<u32>,
+
+    enables: RefCell<[u32; NUM_CONTEXTS]>,
+    thresholds: RefCell<[u32; NUM_CONTEXTS]>,
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            priorities: RefCell::new([0; NUM_SOURCES]),
+            levels: Cell::new(0),
+            pending: Cell::new(0),
+            in_service: Cell::new(0),
+
+            enables: RefCell::new([0; NUM_CONTEXTS]),
+            thresholds: RefCell::new([0; NUM_CONTEXTS]),
+        }
+    }
+
+    // the pending source a claim by context would return:
+    // the highest priority above the threshold, the lowest id
+    // among equals
+    fn best(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending.get()
+            & self.enables.borrow()[context];
+        if candidates == 0 {
+            return None;
+        }
+
+        let priorities = self.priorities.borrow();
+        let threshold = self.thresholds.borrow()[context];
+        (1..NUM_SOURCES as u32)
+            .filter(|source| {
+                candidates & (1 << source) != 0
+            })
+            .filter(|&source| {
+                priorities[source as usize] > threshold
+            })
+            .min_by_key(|&source| {
+                (
+                    PRIORITY_MASK
+                        - priorities[source as usize],
+                    source,
+                )
+            })
+    }
+
+    // the interrupt line of context to the hart, mip.MEIP
+    // or mip.SEIP
+    pub fn is_interrupting(&self, context: usize) -> bool {
+        self.best(context).is_some()
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                let bit = 1 << source;
+                self.pending.set(self.pending.get() & !bit);
+                self.in_service
+                    .set(self.in_service.get() | bit);
+                source
+            }
+            None => 0,
+        }
+    }
+
+    // ignored for sources the context has not enabled
+    fn complete(&self, context: usize, source: u32) {
+        if source as usize >= NUM_SOURCES {
+            return;
+        }
+        let bit = 1 << source;
+        if self.enables.borrow()[context] & bit == 0 {
+            return;
+        }
+        self.in_service.set(self.in_service.get() & !bit);
+        // a line still high requests again
+        self.pending.set(
+            self.pending.get() | (self.levels.get() & bit),
+        );
+    }
+
+    // the context of offset in the per-context blocks from
+    // base on, and the offset of the same register of
+    // context 0
+    fn context_reg(
+        offset: usize,
+        base: usize,
+        stride: usize,
+    ) -> Option<(usize, usize)> {
+        let context = offset.checked_sub(base)? / stride;
+        (context < NUM_CONTEXTS)
+            .then(|| (context, offset - context * stride))
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let priorities = PRIORITY + NUM_SOURCES * 4;
+        if offset < priorities {
+            return self.priorities.borrow()[offset / 4];
+        }
+        if offset == PENDING {
+            return self.pending.get();
+        }
+        if let Some((context, ENABLE)) =
+            Self::context_reg(offset, ENABLE, ENABLE_STRIDE)
+        {
+            return self.enables.borrow()[context];
+        }
+        match Self::context_reg(
+            offset,
+            THRESHOLD,
+            CONTEXT_STRIDE,
+        ) {
+            Some((context, THRESHOLD)) => {
+                self.thresholds.borrow()[context]
+            }
+            Some((context, CLAIM)) => self.claim(context),
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        let priorities = PRIORITY + NUM_SOURCES * 4;
+        if offset < priorities {
+            // source 0 does not exist
+            if offset >= 4 {
+                self.priorities.borrow_mut()[offset / 4] =
+                    val & PRIORITY_MASK;
+            }
+            return;
+        }
+        if let Some((context, ENABLE)) =
+            Self::context_reg(offset, ENABLE, ENABLE_STRIDE)
+        {
+            self.enables.borrow_mut()[context] = val & !1;
+            return;
+        }
+        match Self::context_reg(
+            offset,
+            THRESHOLD,
+            CONTEXT_STRIDE,
+        ) {
+            Some((context, THRESHOLD)) => {
+                self.thresholds.borrow_mut()[context] =
+                    val & PRIORITY_MASK
+            }
+            Some((context, CLAIM)) => {
+                self.complete(context, val)
+            }
+            _ => {} // pending bits are read-only
+        }
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptSink for Plic {
+    fn set_level(&self, source: u32, level: bool) {
+        if source == 0 || source as usize >= NUM_SOURCES {
+            return;
+        }
+        let bit = 1 << source;
+        let levels = self.levels.get() & !bit;
+        self.levels
+            .set(levels | if level { bit } else { 0 });
+
+        // a source in service waits for its completion
+        if self.in_service.get() & bit == 0 {
+            let pending = self.pending.get() & !bit;
+            self.pending
+                .set(pending | (self.levels.get() & bit));
+        }
+    }
+}
+
+// the registers only take word accesses
+impl MMIODevice for Plic {
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => {
+                Ok(self.read_reg(offset))
+            }
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        self.check_write(offset, width)?;
+        self.write_reg(offset, val);
+        Ok(())
+    }
+
+    fn check_write(
+        &self,
+        _offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => Ok(()),
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use super::mmio_device::IrqLine;
+    use std::rc::Rc;
+
+    const WORD: MemoryAccessWidth = MemoryAccessWidth::Word;
+    const S_ENABLE: usize = ENABLE + ENABLE_STRIDE;
+    const S_THRESHOLD: usize = THRESHOLD + CONTEXT_STRIDE;
+    const S_CLAIM: usize = CLAIM + CONTEXT_STRIDE;
+
+    let plic = Rc::new(Plic::new());
+    let read = |reg| plic.read(reg, WORD).unwrap();
+    let write =
+        |reg, val| plic.write(reg, val, WORD).unwrap();
+    let uart = IrqLine::new(plic.clone(), 10);
+    let disk = IrqLine::new(plic.clone(), 8);
+
+    write(PRIORITY + 10 * 4, 0xff);
+    assert_eq!(read(PRIORITY + 10 * 4), 7);
+    write(PRIORITY + 8 * 4, 3);
+    write(ENABLE, u32::MAX);
+    assert_eq!(read(ENABLE), !1);
+
+    uart.raise();
+    disk.raise();
+    assert_eq!(read(PENDING), (1 << 10) | (1 << 8));
+    assert!(plic.is_interrupting(CONTEXT_MACHINE));
+    assert!(!plic.is_interrupting(CONTEXT_SUPERVISOR));
+
+    // the highest priority first
+    assert_eq!(read(CLAIM), 10);
+    assert_eq!(read(CLAIM), 8);
+    assert_eq!(read(CLAIM), 0);
+    assert!(!plic.is_interrupting(CONTEXT_MACHINE));
+
+    // still high once completed, so pending again
+    write(CLAIM, 10);
+    assert_eq!(read(PENDING), 1 << 10);
+    uart.lower();
+    assert_eq!(read(PENDING), 0);
+    disk.lower();
+    write(CLAIM, 8);
+    assert_eq!(read(PENDING), 0);
+
+    // the threshold masks priorities up to its own
+    write(S_ENABLE, 1 << 8);
+    write(S_THRESHOLD, 3);
+    disk.raise();
+    assert!(!plic.is_interrupting(CONTEXT_SUPERVISOR));
+    write(S_THRESHOLD, 2);
+    assert!(plic.is_interrupting(CONTEXT_SUPERVISOR));
+    assert_eq!(read(S_CLAIM), 8);
+
+    let byte = MemoryAccessWidth::Byte;
+    assert_eq!(
+        plic.read(CLAIM, byte),
+        Err(DeviceError::UnsupportedWidth(byte))
+    );
+}
@@ -1,36 +1,41 @@
-use crate::bus::RAM_START;
+use crate::{
+    bus::RAM_START,
+    pipeline::memory_access::MemoryAccessWidth,
+};
 
-use super::mmio_device::MMIODevice;
+use super::mmio_device::{
+    read_bytes, write_bytes, DeviceError, MMIODevice,
+};
 use std::cell::RefCell;
 
 pub struct RAMDevice {
-    ram: RefCell<Vec<u32>>,
-    size: usize,
+    ram: RefCell<Vec<u8>>,
 }
 
 impl RAMDevice {
+    // size in bytes
     pub fn new(size: usize) -> Self {
         Self {
-            ram: RefCell::new(vec![0_u32; size]),
-            size,
+            ram: RefCell::new(vec![0_u8; size]),
         }
     }
 
     pub fn get_mem(&self, size: usize) -> Vec<u32> {
-        self.ram.borrow()[..size / 4].to_owned()
+        let ram = self.ram.borrow();
+        (0..size / 4)
+            .map(|i| read_bytes(&ram, i * 4, MemoryAccessWidth::Word))
+            .collect()
     }
 
     pub fn mem_dump(&self, size: usize) {
-        let mem_out = self.ram.borrow().to_owned()
+        let mem_out = self.get_mem(size)
         .into_iter()
-        .zip(0..size / 4)
-        .map(|(val, i)| {
+        .enumerate()
+        .map(|(i, val)| {
             let mut out = format!("{:#010x} ", val);
             if i % 4 == 0 {
-                out = String::from(format!(
-                    "\n{:#010x}: ",
-                    RAM_START + i * 4
-                )) + &out;
+                out = format!("\n{:#010x}: ", RAM_START + i * 4)
+                    + &out;
             }
             out
         })
@@ -40,16 +45,22 @@ impl RAMDevice {
 }
 
 impl MMIODevice for RAMDevice {
-    fn read(&self, location: usize) -> u32 {
-        if location < self.size {
-            self.ram.borrow()[location]
-        } else {
-            0_u32
-        }
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        Ok(read_bytes(&self.ram.borrow(), offset, width))
     }
 
-    fn write(&self, location: usize, val: u32) {
-        self.ram.borrow_mut()[location] = val;
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        write_bytes(&mut self.ram.borrow_mut(), offset, val, width);
+        Ok(())
     }
 }
 
@@ -57,14 +68,20 @@ impl MMIODevice for RAMDevice {
 #[test]
 fn test() {
     let ram = RAMDevice::new(0x1000_0000);
+    let width = MemoryAccessWidth::Word;
 
-    ram.write(0x0, 0x1122_3344);
+    ram.write(0x0, 0x1122_3344, width).unwrap();
 
-    ram.write(0x4, 0xdead_beef);
+    ram.write(0x4, 0xdead_beef, width).unwrap();
 
-    let mut val = ram.read(0x0);
+    let mut val = ram.read(0x0, width).unwrap();
     println!("{:#x}", val);
 
-    val = ram.read(0x4);
+    val = ram.read(0x4, width).unwrap();
     println!("{:#x}", val);
+
+    ram.write(0x5, 0x55, MemoryAccessWidth::Byte).unwrap();
+    val = ram.read(0x3, MemoryAccessWidth::HalfWord).unwrap();
+    assert_eq!(val, 0xef11);
+    assert_eq!(ram.read(0x4, width).unwrap(), 0xdead_55ef);
 }
@@ -1,4 +1,8 @@
-use super::{mmio_device::MMIODevice, ADDR_ALIGN};
+use super::{
+    mmio_device::{read_bytes, DeviceError, MMIODevice},
+    ADDR_ALIGN,
+};
+use crate::pipeline::memory_access::MemoryAccessWidth;
 use std::{cell::RefCell, fmt};
 
 #[derive(Debug)]
@@ -18,14 +22,18 @@ impl fmt::Display for ROMError {
 }
 
 pub struct ROMDevice {
-    rom: RefCell<Vec<u32>>,
+    rom: RefCell<Vec<u8>>,
     size: usize,
 }
 
+fn to_bytes(file: &[u32]) -> Vec<u8> {
+    file.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
 impl ROMDevice {
     pub fn new(file: &[u32]) -> Self {
         Self {
-            rom: RefCell::new(Vec::from(file)),
+            rom: RefCell::new(to_bytes(file)),
             size: file.len() * ADDR_ALIGN,
         }
     }
@@ -34,38 +42,62 @@ impl ROMDevice {
         if file.len() * 4 > self.size {
             Err(ROMError::_LoadError)
         } else {
-            self.rom.replace(Vec::from(file));
+            self.rom.replace(to_bytes(file));
             Ok(())
         }
     }
 }
 
 impl MMIODevice for ROMDevice {
-    fn read(&self, location: usize) -> u32 {
-        if location < self.size {
-            self.rom.borrow()[location]
-        } else {
-            0_u32
-        }
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        Ok(read_bytes(&self.rom.borrow(), offset, width))
     }
 
-    fn write(&self, _location: usize, _val: u32) {}
+    // stores raise an access fault
+    fn write(
+        &self,
+        offset: usize,
+        _val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        self.check_write(offset, width)
+    }
+
+    fn check_write(
+        &self,
+        _offset: usize,
+        _width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        Err(DeviceError::ReadOnly)
+    }
 }
 
 #[cfg(test)]
 #[test]
 fn test() {
     let file = [1_u32, 2, 3, 4, 5];
+    let width = MemoryAccessWidth::Word;
 
     let ram = ROMDevice::new(&file);
 
-    ram.write(0x0, 0x1122_3344); // invalid
+    assert_eq!(
+        ram.write(0x0, 0x1122_3344, width),
+        Err(DeviceError::ReadOnly)
+    );
 
-    ram.write(0x4, 0xdead_beef); // invalid
+    assert_eq!(
+        ram.write(0x4, 0xdead_beef, width),
+        Err(DeviceError::ReadOnly)
+    );
 
-    let mut val = ram.read(0x0);
+    let mut val = ram.read(0x0, width).unwrap();
     println!("{:#x}", val);
 
-    val = ram.read(0x4);
+    val = ram.read(0x4, width).unwrap();
     println!("{:#x}", val);
+    assert_eq!(val, 2);
 }
@@ -0,0 +1,107 @@
+use super::mmio_device::{DeviceError, MMIODevice};
+use crate::pipeline::memory_access::MemoryAccessWidth;
+use std::cell::Cell;
+
+pub const TEST_FINISHER_START: usize = 0x0010_0000;
+
+pub const TEST_FINISHER_SIZE: usize = 0x1000;
+
+// the low half of a write, a failure carries the exit code
+// in the upper half
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+
+// the SiFive test finisher, also the target of
+// syscon-poweroff. Resetting through it is not supported
+pub struct TestFinisher {
+    exit_code: Cell<Option<u32>>,
+}
+
+impl TestFinisher {
+    pub fn new() -> Self {
+        Self {
+            exit_code: Cell::new(None),
+        }
+    }
+
+    // set once the guest has asked to exit
+    pub fn get_exit_code(&self) -> Option<u32> {
+        self.exit_code.get()
+    }
+}
+
+impl Default for TestFinisher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MMIODevice for TestFinisher {
+    fn read(
+        &self,
+        _offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => Ok(0),
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        self.check_write(offset, width)?;
+        if offset != 0 || self.exit_code.get().is_some() {
+            return Ok(());
+        }
+
+        match val & 0xffff {
+            FINISHER_PASS => self.exit_code.set(Some(0)),
+            FINISHER_FAIL => {
+                self.exit_code.set(Some(val >> 16))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn check_write(
+        &self,
+        _offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        match width {
+            MemoryAccessWidth::Word => Ok(()),
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    const WORD: MemoryAccessWidth = MemoryAccessWidth::Word;
+
+    let finisher = TestFinisher::new();
+    finisher.write(0, 0x7777, WORD).unwrap(); // reset
+    finisher.write(4, FINISHER_PASS, WORD).unwrap();
+    assert_eq!(finisher.get_exit_code(), None);
+
+    finisher
+        .write(0, (3 << 16) | FINISHER_FAIL, WORD)
+        .unwrap();
+    assert_eq!(finisher.get_exit_code(), Some(3));
+    // the first request wins
+    finisher.write(0, FINISHER_PASS, WORD).unwrap();
+    assert_eq!(finisher.get_exit_code(), Some(3));
+
+    let byte = MemoryAccessWidth::Byte;
+    assert_eq!(
+        finisher.write(0, FINISHER_PASS, byte),
+        Err(DeviceError::UnsupportedWidth(byte))
+    );
+}
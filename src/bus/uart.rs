@@ -0,0 +1,297 @@
+use super::mmio_device::{DeviceError, MMIODevice};
+use crate::pipeline::memory_access::MemoryAccessWidth;
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+pub const UART_START: usize = 0x1000_0000;
+
+pub const UART_SIZE: usize = 8;
+
+// register offsets, DLL/DLM alias RBR/IER while LCR.DLAB is
+// set
+const RBR: usize = 0; // THR on write
+const IER: usize = 1;
+const IIR: usize = 2; // FCR on write
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+const LCR_DLAB: u8 = 1 << 7;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+// where transmitted characters go and received ones come
+// from, get() must not block
+pub trait UARTBackend {
+    fn put(&self, byte: u8);
+    fn get(&self) -> Option<u8>;
+}
+
+// output is dropped and there is never any input
+pub struct NullBackend;
+
+impl UARTBackend for NullBackend {
+    fn put(&self, _byte: u8) {}
+
+    fn get(&self) -> Option<u8> {
+        None
+    }
+}
+
+// host stdout/stdin, stdin is drained by a thread so that
+// polling LSR never blocks the guest
+pub struct StdioBackend {
+    input: Receiver<u8>,
+}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte)
+                        if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Self { input }
+    }
+}
+
+impl Default for StdioBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UARTBackend for StdioBackend {
+    fn put(&self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+    }
+
+    fn get(&self) -> Option<u8> {
+        self.input.try_recv().ok()
+    }
+}
+
+pub struct UARTDevice {
+    backend: Box<dyn UARTBackend>,
+
+    // a received byte not yet read from RBR
+    rx: Cell<Option<u8>>,
+
+    ier: Cell<u8>,
+    fcr: Cell<u8>,
+    lcr: Cell<u8>,
+    mcr: Cell<u8>,
+    scr: Cell<u8>,
+    divisor: RefCell<[u8; 2]>,
+}
+
+impl UARTDevice {
+    pub fn new(backend: Box<dyn UARTBackend>) -> Self {
+        Self {
+            backend,
+            rx: Cell::new(None),
+            ier: Cell::new(0),
+            fcr: Cell::new(0),
+            lcr: Cell::new(0),
+            mcr: Cell::new(0),
+            scr: Cell::new(0),
+            divisor: RefCell::new([0; 2]),
+        }
+    }
+
+    fn is_dlab(&self) -> bool {
+        self.lcr.get() & LCR_DLAB != 0
+    }
+
+    fn has_data(&self) -> bool {
+        if self.rx.get().is_none() {
+            self.rx.set(self.backend.get());
+        }
+        self.rx.get().is_some()
+    }
+
+    fn iir(&self) -> u8 {
+        let ier = self.ier.get();
+        let id = if ier & IER_RX_AVAILABLE != 0
+            && self.has_data()
+        {
+            IIR_RX_AVAILABLE
+        } else if ier & IER_THR_EMPTY != 0 {
+            // THR is always empty, the byte goes out at once
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        };
+
+        if self.fcr.get() & FCR_FIFO_ENABLE != 0 {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        let mut lsr = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+        if self.has_data() {
+            lsr |= LSR_DATA_READY;
+        }
+        lsr
+    }
+
+    fn read_reg(&self, reg: usize) -> u8 {
+        match reg {
+            RBR | IER if self.is_dlab() => {
+                self.divisor.borrow()[reg]
+            }
+            RBR => {
+                self.has_data();
+                self.rx.take().unwrap_or(0)
+            }
+            IER => self.ier.get(),
+            IIR => self.iir(),
+            LCR => self.lcr.get(),
+            MCR => self.mcr.get(),
+            LSR => self.lsr(),
+            MSR => 0,
+            SCR => self.scr.get(),
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, reg: usize, val: u8) {
+        match reg {
+            RBR | IER if self.is_dlab() => {
+                self.divisor.borrow_mut()[reg] = val;
+            }
+            RBR => self.backend.put(val),
+            IER => self.ier.set(val & 0x0f),
+            IIR => self.fcr.set(val),
+            LCR => self.lcr.set(val),
+            MCR => self.mcr.set(val & 0x1f),
+            SCR => self.scr.set(val),
+            _ => {} // LSR and MSR are read-only
+        }
+    }
+}
+
+// the registers are 8 bits wide and only take byte accesses
+impl MMIODevice for UARTDevice {
+    fn read(
+        &self,
+        offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<u32, DeviceError> {
+        match width {
+            MemoryAccessWidth::Byte => {
+                Ok(self.read_reg(offset) as u32)
+            }
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+
+    fn write(
+        &self,
+        offset: usize,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        self.check_write(offset, width)?;
+        self.write_reg(offset, val as u8);
+        Ok(())
+    }
+
+    fn check_write(
+        &self,
+        _offset: usize,
+        width: MemoryAccessWidth,
+    ) -> Result<(), DeviceError> {
+        match width {
+            MemoryAccessWidth::Byte => Ok(()),
+            _ => Err(DeviceError::UnsupportedWidth(width)),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use std::rc::Rc;
+
+    const BYTE: MemoryAccessWidth = MemoryAccessWidth::Byte;
+
+    struct BufferBackend {
+        output: Rc<RefCell<Vec<u8>>>,
+        input: RefCell<Vec<u8>>,
+    }
+
+    impl UARTBackend for BufferBackend {
+        fn put(&self, byte: u8) {
+            self.output.borrow_mut().push(byte);
+        }
+
+        fn get(&self) -> Option<u8> {
+            self.input.borrow_mut().pop()
+        }
+    }
+
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let uart = UARTDevice::new(Box::new(BufferBackend {
+        output: output.clone(),
+        input: RefCell::new(vec![b'b', b'a']),
+    }));
+    let read = |reg| uart.read(reg, BYTE).unwrap();
+    let write =
+        |reg, val| uart.write(reg, val, BYTE).unwrap();
+
+    write(RBR, b'h' as u32);
+    write(RBR, b'i' as u32);
+    assert_eq!(*output.borrow(), b"hi");
+
+    // the divisor latch shadows THR
+    write(LCR, LCR_DLAB as u32);
+    write(RBR, 3);
+    assert_eq!(read(RBR), 3);
+    write(LCR, 0x03);
+    assert_eq!(output.borrow().len(), 2);
+
+    assert_eq!(read(IIR), IIR_NO_INTERRUPT as u32);
+    write(IER, IER_RX_AVAILABLE as u32);
+    assert_eq!(read(IIR), IIR_RX_AVAILABLE as u32);
+
+    assert_eq!(read(LSR), 0x61);
+    assert_eq!(read(RBR), b'a' as u32);
+    assert_eq!(read(RBR), b'b' as u32);
+    assert_eq!(read(LSR), 0x60);
+    assert_eq!(read(RBR), 0);
+
+    let word = MemoryAccessWidth::Word;
+    assert_eq!(
+        uart.write(RBR, 0, word),
+        Err(DeviceError::UnsupportedWidth(word))
+    );
+    assert_eq!(output.borrow().len(), 2);
+}
@@ -0,0 +1,889 @@
+use std::{cell::RefCell, fmt};
+
+use crate::trap::{Interrupt, Trap};
+
+pub const NUM_CSR: usize = 4096;
+
+// unprivileged floating-point CSRs
+pub const FFLAGS: u32 = 0x001;
+pub const FRM: u32 = 0x002;
+pub const FCSR: u32 = 0x003;
+
+// unprivileged counters
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+
+// supervisor trap setup and handling
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+
+// supervisor protection and translation
+pub const SATP: u32 = 0x180;
+
+// machine information registers
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+
+// machine trap setup and handling
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MSTATUSH: u32 = 0x310;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+
+// machine counters
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+
+// RV32 with the I, M, A, F, D and C extensions, S and U modes
+const MISA_VALUE: u32 = (0b01 << 30)
+    | (1 << 0)
+    | (1 << 2)
+    | (1 << 3)
+    | (1 << 5)
+    | (1 << 8)
+    | (1 << 12)
+    | (1 << 18)
+    | (1 << 20);
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_FS: u32 = 0b11 << 13;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_SD: u32 = 1 << 31;
+
+// states of the FS field
+const FS_OFF: u32 = 0b00 << 13;
+const FS_INITIAL: u32 = 0b01 << 13;
+const FS_DIRTY: u32 = 0b11 << 13;
+
+const FFLAGS_MASK: u32 = 0x1f;
+const FRM_SHIFT: u32 = 5;
+const FCSR_MASK: u32 = 0xff;
+
+const MSTATUS_MPP_SHIFT: u32 = 11;
+
+const MSTATUS_MASK: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM;
+
+// sstatus is a restricted view of mstatus
+const SSTATUS_MASK: u32 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_SUM
+    | MSTATUS_MXR;
+
+// every implemented exception except ecall from M-mode,
+// which can never be delegated
+const MEDELEG_MASK: u32 = 0xb3ff & !(1 << 11);
+
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+
+// supervisor software, timer and external interrupts
+const MIDELEG_MASK: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// every interrupt can be enabled
+const MIE_MASK: u32 =
+    MIDELEG_MASK | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+
+// the machine-level bits follow the interrupt lines, the
+// supervisor ones are for M-mode software to post
+const MIP_MASK: u32 = MIDELEG_MASK;
+
+// S-mode may only clear its own software interrupt
+const SIP_MASK: u32 = MIP_SSIP;
+
+// the order in which pending interrupts are taken
+const INTERRUPT_PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoftware,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoftware,
+    Interrupt::SupervisorTimer,
+];
+
+// cycle, time and instret
+const COUNTEREN_MASK: u32 = 0b111;
+
+// direct and vectored are the only legal xtvec modes
+const TVEC_MODE_VECTORED: u32 = 0b01;
+
+// set in xcause for interrupts
+pub const CAUSE_INTERRUPT: u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum PrivilegeMode {
+    User = 0b00,
+    Supervisor = 0b01,
+    Machine = 0b11,
+}
+
+impl PrivilegeMode {
+    // 0b10 is reserved, WARL fields keep their old value
+    fn from_bits(bits: u32) -> Option<Self> {
+        match bits & 0b11 {
+            0b00 => Some(PrivilegeMode::User),
+            0b01 => Some(PrivilegeMode::Supervisor),
+            0b11 => Some(PrivilegeMode::Machine),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CsrError {
+    NotImplemented(u32),
+    WriteReadOnly(u32),
+    PrivilegeViolation(u32),
+    FpuDisabled(u32),
+}
+
+impl std::error::Error for CsrError {}
+
+impl fmt::Display for CsrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrError::NotImplemented(addr) => {
+                write!(f, "CSR {:#05x} not implemented", addr)
+            }
+            CsrError::WriteReadOnly(addr) => {
+                write!(f, "CSR {:#05x} is read-only", addr)
+            }
+            CsrError::PrivilegeViolation(addr) => {
+                write!(
+                    f,
+                    "CSR {:#05x} is not accessible from the \
+                    current privilege level",
+                    addr
+                )
+            }
+            CsrError::FpuDisabled(addr) => {
+                write!(
+                    f,
+                    "CSR {:#05x} is not accessible while the \
+                    FPU is off",
+                    addr
+                )
+            }
+        }
+    }
+}
+
+pub struct CsrFile {
+    csrs: RefCell<[u32; NUM_CSR]>,
+    privilege: RefCell<PrivilegeMode>,
+
+    cycle: RefCell<u64>,
+    instret: RefCell<u64>,
+    // mtime of the platform timer
+    time: RefCell<u64>,
+
+    // mip bits driven by devices, software cannot change them
+    irq_lines: RefCell<u32>,
+
+    // so tests can tell whether a CSR was read at all
+    #[cfg(test)]
+    reads: RefCell<u32>,
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        let mut csrs = [0_u32; NUM_CSR];
+        csrs[MISA as usize] = MISA_VALUE;
+        // the FPU is usable out of reset, so that bare-metal
+        // programs built for hard-float run unmodified
+        csrs[MSTATUS as usize] = MSTATUS_MPP | FS_INITIAL;
+
+        Self {
+            csrs: RefCell::new(csrs),
+            privilege: RefCell::new(PrivilegeMode::Machine),
+
+            cycle: RefCell::new(0),
+            instret: RefCell::new(0),
+            time: RefCell::new(0),
+
+            irq_lines: RefCell::new(0),
+
+            #[cfg(test)]
+            reads: RefCell::new(0),
+        }
+    }
+
+    pub fn privilege(&self) -> PrivilegeMode {
+        self.privilege.borrow().to_owned()
+    }
+
+    pub fn mstatus(&self) -> u32 {
+        self.csrs.borrow()[MSTATUS as usize]
+    }
+
+    pub fn satp(&self) -> u32 {
+        self.csrs.borrow()[SATP as usize]
+    }
+
+    // satp accesses and sfence.vma trap in S-mode when TVM is set
+    pub fn is_vm_trapped(&self) -> bool {
+        self.privilege() == PrivilegeMode::Supervisor
+            && self.mstatus() & MSTATUS_TVM != 0
+    }
+
+    pub fn is_fp_enabled(&self) -> bool {
+        self.mstatus() & MSTATUS_FS != FS_OFF
+    }
+
+    pub fn set_time(&self, time: u64) {
+        self.time.replace(time);
+    }
+
+    // raises or lowers the mip bit of an interrupt line
+    pub fn set_irq_line(
+        &self,
+        interrupt: Interrupt,
+        level: bool,
+    ) {
+        let mut irq_lines = self.irq_lines.borrow_mut();
+        if level {
+            *irq_lines |= interrupt.bit();
+        } else {
+            *irq_lines &= !interrupt.bit();
+        }
+    }
+
+    fn mip(&self) -> u32 {
+        self.csrs.borrow()[MIP as usize]
+            | *self.irq_lines.borrow()
+    }
+
+    // the interrupt to take before the next instruction.
+    // Interrupts for a higher privilege level are always
+    // enabled, the ones for the current level only with its
+    // xIE bit set, the ones for a lower level never
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        let csrs = self.csrs.borrow();
+        let pending = self.mip() & csrs[MIE as usize];
+        if pending == 0 {
+            return None;
+        }
+
+        let mstatus = csrs[MSTATUS as usize];
+        let mideleg = csrs[MIDELEG as usize];
+        let (m_enabled, s_enabled) = match self.privilege() {
+            PrivilegeMode::Machine => {
+                (mstatus & MSTATUS_MIE != 0, false)
+            }
+            PrivilegeMode::Supervisor => {
+                (true, mstatus & MSTATUS_SIE != 0)
+            }
+            PrivilegeMode::User => (true, true),
+        };
+        let enabled = if m_enabled { !mideleg } else { 0 }
+            | if s_enabled { mideleg } else { 0 };
+
+        INTERRUPT_PRIORITY
+            .into_iter()
+            .find(|i| pending & enabled & i.bit() != 0)
+    }
+
+    // the dynamic rounding mode
+    pub fn frm(&self) -> u32 {
+        self.csrs.borrow()[FCSR as usize] >> FRM_SHIFT
+    }
+
+    // SD summarizes a dirty FS
+    fn with_sd(mstatus: u32) -> u32 {
+        if mstatus & MSTATUS_FS == FS_DIRTY {
+            mstatus | MSTATUS_SD
+        } else {
+            mstatus & !MSTATUS_SD
+        }
+    }
+
+    // called whenever the floating-point state changes
+    pub fn set_fp_dirty(&self) {
+        let mut csrs = self.csrs.borrow_mut();
+        csrs[MSTATUS as usize] =
+            Self::with_sd(csrs[MSTATUS as usize] | FS_DIRTY);
+    }
+
+    // exception flags are sticky until software clears them
+    pub fn accrue_fflags(&self, flags: u32) {
+        self.csrs.borrow_mut()[FCSR as usize] |=
+            flags & FFLAGS_MASK;
+        self.set_fp_dirty();
+    }
+
+    // the top two address bits being 0b11 marks a read-only CSR
+    fn is_read_only(addr: u32) -> bool {
+        (addr >> 10) & 0b11 == 0b11
+    }
+
+    // bits 9:8 of the address encode the lowest privilege level
+    // allowed to access the CSR, counters are further gated
+    // by mcounteren and scounteren
+    fn check_privilege(&self, addr: u32) -> Result<(), CsrError> {
+        let privilege = self.privilege() as u32;
+        if (addr >> 8) & 0b11 > privilege
+            || (addr == SATP && self.is_vm_trapped())
+        {
+            return Err(CsrError::PrivilegeViolation(addr));
+        }
+
+        if let FFLAGS | FRM | FCSR = addr {
+            if !self.is_fp_enabled() {
+                return Err(CsrError::FpuDisabled(addr));
+            }
+        }
+
+        if let CYCLE | TIME | INSTRET | CYCLEH | TIMEH | INSTRETH =
+            addr
+        {
+            let csrs = self.csrs.borrow();
+            let bit = 1 << (addr & 0x1f);
+            let denied = match self.privilege() {
+                PrivilegeMode::Machine => false,
+                PrivilegeMode::Supervisor => {
+                    csrs[MCOUNTEREN as usize] & bit == 0
+                }
+                PrivilegeMode::User => {
+                    (csrs[MCOUNTEREN as usize]
+                        & csrs[SCOUNTEREN as usize]
+                        & bit)
+                        == 0
+                }
+            };
+            if denied {
+                return Err(CsrError::PrivilegeViolation(addr));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&self, addr: u32) -> Result<u32, CsrError> {
+        let cycle = *self.cycle.borrow();
+        let instret = *self.instret.borrow();
+        let time = *self.time.borrow();
+        let mip = self.mip();
+        let csrs = self.csrs.borrow();
+
+        let val = match addr {
+            CYCLE | MCYCLE => cycle as u32,
+            CYCLEH | MCYCLEH => (cycle >> 32) as u32,
+            TIME => time as u32,
+            TIMEH => (time >> 32) as u32,
+            INSTRET | MINSTRET => instret as u32,
+            INSTRETH | MINSTRETH => (instret >> 32) as u32,
+            FFLAGS => csrs[FCSR as usize] & FFLAGS_MASK,
+            FRM => csrs[FCSR as usize] >> FRM_SHIFT,
+            FCSR => csrs[FCSR as usize],
+            SSTATUS => {
+                csrs[MSTATUS as usize]
+                    & (SSTATUS_MASK | MSTATUS_SD)
+            }
+            MIP => mip,
+            // views of the delegated interrupts
+            SIP => mip & csrs[MIDELEG as usize],
+            SIE => csrs[MIE as usize] & csrs[MIDELEG as usize],
+            STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE
+            | STVAL | SATP | MVENDORID | MARCHID | MIMPID | MHARTID
+            | MSTATUS | MISA | MEDELEG | MIDELEG | MTVEC
+            | MCOUNTEREN | MSTATUSH | MSCRATCH | MEPC | MCAUSE
+            | MTVAL | MIE => csrs[addr as usize],
+            _ => return Err(CsrError::NotImplemented(addr)),
+        };
+        drop(csrs);
+
+        self.check_privilege(addr)?;
+
+        #[cfg(test)]
+        self.reads.replace_with(|&mut reads| reads + 1);
+        Ok(val)
+    }
+
+    #[cfg(test)]
+    pub fn get_reads(&self) -> u32 {
+        *self.reads.borrow()
+    }
+
+    pub fn write(&self, addr: u32, val: u32) -> Result<(), CsrError> {
+        if Self::is_read_only(addr) {
+            // still distinguish unknown CSRs from read-only ones
+            self.read(addr)?;
+            return Err(CsrError::WriteReadOnly(addr));
+        }
+        self.check_privilege(addr)?;
+
+        let set_low = |counter: &RefCell<u64>| {
+            counter.replace_with(|&mut c| {
+                (c & 0xffff_ffff_0000_0000) | val as u64
+            });
+        };
+        let set_high = |counter: &RefCell<u64>| {
+            counter.replace_with(|&mut c| {
+                (c & 0xffff_ffff) | ((val as u64) << 32)
+            });
+        };
+        let masked = |old: u32, mask: u32| {
+            (old & !mask) | (val & mask)
+        };
+
+        let mut csrs = self.csrs.borrow_mut();
+        let old = csrs[addr as usize];
+
+        match addr {
+            MSTATUS => {
+                let mut mstatus = masked(old, MSTATUS_MASK);
+                if PrivilegeMode::from_bits(
+                    val >> MSTATUS_MPP_SHIFT,
+                )
+                .is_none()
+                {
+                    mstatus = (mstatus & !MSTATUS_MPP)
+                        | (old & MSTATUS_MPP);
+                }
+                csrs[addr as usize] = Self::with_sd(mstatus);
+            }
+            SSTATUS => {
+                let mstatus = csrs[MSTATUS as usize];
+                csrs[MSTATUS as usize] = Self::with_sd(
+                    (mstatus & !SSTATUS_MASK)
+                        | (val & SSTATUS_MASK),
+                );
+            }
+            FFLAGS | FRM | FCSR => {
+                let fcsr = csrs[FCSR as usize];
+                csrs[FCSR as usize] = match addr {
+                    FFLAGS => masked(fcsr, FFLAGS_MASK),
+                    FRM => {
+                        (fcsr & FFLAGS_MASK)
+                            | ((val << FRM_SHIFT) & FCSR_MASK)
+                    }
+                    _ => val & FCSR_MASK,
+                };
+                let mstatus = csrs[MSTATUS as usize];
+                csrs[MSTATUS as usize] =
+                    Self::with_sd(mstatus | FS_DIRTY);
+            }
+            MTVEC | STVEC => {
+                csrs[addr as usize] =
+                    val & !(0b11 ^ TVEC_MODE_VECTORED);
+            }
+            MEDELEG => csrs[addr as usize] = masked(old, MEDELEG_MASK),
+            MIDELEG => csrs[addr as usize] = masked(old, MIDELEG_MASK),
+            MIE => csrs[addr as usize] = masked(old, MIE_MASK),
+            MIP => csrs[addr as usize] = masked(old, MIP_MASK),
+            SIE | SIP => {
+                let (reg, mask) = if addr == SIE {
+                    (MIE, csrs[MIDELEG as usize])
+                } else {
+                    (MIP, csrs[MIDELEG as usize] & SIP_MASK)
+                };
+                let old = csrs[reg as usize];
+                csrs[reg as usize] = masked(old, mask);
+            }
+            MCOUNTEREN | SCOUNTEREN => {
+                csrs[addr as usize] = masked(old, COUNTEREN_MASK)
+            }
+            MEPC | SEPC => csrs[addr as usize] = val & !0b1,
+            MSTATUSH => (),
+            MSCRATCH | MCAUSE | MTVAL | SSCRATCH | SCAUSE | STVAL
+            | SATP => csrs[addr as usize] = val,
+            MCYCLE => set_low(&self.cycle),
+            MCYCLEH => set_high(&self.cycle),
+            MINSTRET => set_low(&self.instret),
+            MINSTRETH => set_high(&self.instret),
+            // WARL: only one ISA configuration is supported,
+            // so every write leaves misa unchanged
+            MISA => (),
+            _ => return Err(CsrError::NotImplemented(addr)),
+        }
+
+        Ok(())
+    }
+
+    // returns the address of the trap handler
+    pub fn trap_enter(&self, pc: u32, trap: Trap) -> u32 {
+        let cause = trap.cause as u32;
+        let delegated = self.is_delegated(MEDELEG, cause);
+
+        // synchronous exceptions always go to BASE, even when
+        // xtvec is in vectored mode
+        self.enter(pc, cause, trap.tval, delegated) & !0b11
+    }
+
+    // pc is that of the first instruction not executed,
+    // returns the address of the interrupt handler
+    pub fn interrupt_enter(
+        &self,
+        pc: u32,
+        interrupt: Interrupt,
+    ) -> u32 {
+        let code = interrupt as u32;
+        let delegated = self.is_delegated(MIDELEG, code);
+
+        let cause = CAUSE_INTERRUPT | code;
+        let tvec = self.enter(pc, cause, 0, delegated);
+        if tvec & 0b11 == TVEC_MODE_VECTORED {
+            (tvec & !0b11) + 4 * code
+        } else {
+            tvec & !0b11
+        }
+    }
+
+    // traps never move to a lower privilege level
+    fn is_delegated(&self, deleg: u32, code: u32) -> bool {
+        self.privilege() != PrivilegeMode::Machine
+            && self.csrs.borrow()[deleg as usize] & (1 << code)
+                != 0
+    }
+
+    // saves the state of the trapping mode and switches to
+    // the handling one, returns its xtvec
+    fn enter(
+        &self,
+        pc: u32,
+        cause: u32,
+        tval: u32,
+        delegated: bool,
+    ) -> u32 {
+        let mut csrs = self.csrs.borrow_mut();
+        let privilege = self.privilege();
+
+        let mstatus = csrs[MSTATUS as usize];
+        if delegated {
+            csrs[SEPC as usize] = pc;
+            csrs[SCAUSE as usize] = cause;
+            csrs[STVAL as usize] = tval;
+
+            let spie = if mstatus & MSTATUS_SIE != 0 {
+                MSTATUS_SPIE
+            } else {
+                0
+            };
+            let spp = if privilege == PrivilegeMode::Supervisor {
+                MSTATUS_SPP
+            } else {
+                0
+            };
+            csrs[MSTATUS as usize] = (mstatus
+                & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP))
+                | spie
+                | spp;
+
+            self.privilege.replace(PrivilegeMode::Supervisor);
+
+            csrs[STVEC as usize]
+        } else {
+            csrs[MEPC as usize] = pc;
+            csrs[MCAUSE as usize] = cause;
+            csrs[MTVAL as usize] = tval;
+
+            let mpie = if mstatus & MSTATUS_MIE != 0 {
+                MSTATUS_MPIE
+            } else {
+                0
+            };
+            csrs[MSTATUS as usize] = (mstatus
+                & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP))
+                | mpie
+                | ((privilege as u32) << MSTATUS_MPP_SHIFT);
+
+            self.privilege.replace(PrivilegeMode::Machine);
+
+            csrs[MTVEC as usize]
+        }
+    }
+
+    // mret, returns the address to resume execution at
+    pub fn trap_return(&self) -> u32 {
+        let mut csrs = self.csrs.borrow_mut();
+
+        let mstatus = csrs[MSTATUS as usize];
+        let mie = if mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        let mpp = PrivilegeMode::from_bits(
+            mstatus >> MSTATUS_MPP_SHIFT,
+        )
+        .unwrap_or(PrivilegeMode::User);
+
+        // MPRV only stays set when returning to M-mode
+        let mprv = if mpp == PrivilegeMode::Machine {
+            mstatus & MSTATUS_MPRV
+        } else {
+            0
+        };
+
+        // MPP is left holding the least-privileged mode
+        csrs[MSTATUS as usize] = (mstatus
+            & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV))
+            | mie
+            | mprv
+            | MSTATUS_MPIE;
+        self.privilege.replace(mpp);
+
+        csrs[MEPC as usize]
+    }
+
+    // sret, returns the address to resume execution at
+    pub fn supervisor_trap_return(&self) -> u32 {
+        let mut csrs = self.csrs.borrow_mut();
+
+        let mstatus = csrs[MSTATUS as usize];
+        let sie = if mstatus & MSTATUS_SPIE != 0 {
+            MSTATUS_SIE
+        } else {
+            0
+        };
+        let spp = if mstatus & MSTATUS_SPP != 0 {
+            PrivilegeMode::Supervisor
+        } else {
+            PrivilegeMode::User
+        };
+
+        csrs[MSTATUS as usize] = (mstatus
+            & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV))
+            | sie
+            | MSTATUS_SPIE;
+        self.privilege.replace(spp);
+
+        csrs[SEPC as usize]
+    }
+
+    pub fn tick(&self) {
+        self.cycle.replace_with(|&mut c| c.wrapping_add(1));
+    }
+
+    pub fn retire(&self) {
+        self.instret.replace_with(|&mut c| c.wrapping_add(1));
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let csr_file = CsrFile::new();
+
+    csr_file.write(MSCRATCH, 0xdead_beef).unwrap();
+    assert_eq!(csr_file.read(MSCRATCH).unwrap(), 0xdead_beef);
+
+    assert!(matches!(
+        csr_file.write(CYCLE, 1),
+        Err(CsrError::WriteReadOnly(CYCLE))
+    ));
+    assert!(matches!(
+        csr_file.read(0x7ff),
+        Err(CsrError::NotImplemented(0x7ff))
+    ));
+
+    csr_file.write(MISA, 0).unwrap();
+    assert_eq!(csr_file.read(MISA).unwrap(), MISA_VALUE);
+
+    csr_file.write(MCYCLE, u32::MAX).unwrap();
+    csr_file.tick();
+    assert_eq!(csr_file.read(CYCLE).unwrap(), 0);
+    assert_eq!(csr_file.read(CYCLEH).unwrap(), 1);
+    // time comes from the platform timer
+    assert_eq!(csr_file.read(TIMEH).unwrap(), 0);
+    csr_file.set_time(1 << 32);
+    assert_eq!(csr_file.read(TIMEH).unwrap(), 1);
+
+    csr_file.retire();
+    csr_file.retire();
+    assert_eq!(csr_file.read(INSTRET).unwrap(), 2);
+
+    // WARL fields
+    csr_file.write(MTVEC, 0x8000_0107).unwrap();
+    assert_eq!(csr_file.read(MTVEC).unwrap(), 0x8000_0105);
+    csr_file.write(MEPC, 0x8000_0003).unwrap();
+    assert_eq!(csr_file.read(MEPC).unwrap(), 0x8000_0002);
+    csr_file.write(MSTATUS, u32::MAX).unwrap();
+    assert_eq!(
+        csr_file.read(MSTATUS).unwrap(),
+        MSTATUS_MASK | MSTATUS_SD // SD summarizes FS = dirty
+    );
+    csr_file.write(MSTATUS, 0b10 << MSTATUS_MPP_SHIFT).unwrap();
+    assert_eq!(csr_file.read(MSTATUS).unwrap(), MSTATUS_MPP);
+    csr_file.write(MEDELEG, u32::MAX).unwrap();
+    assert_eq!(csr_file.read(MEDELEG).unwrap() & (1 << 11), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_privilege() {
+    use crate::trap::Exception;
+
+    let csr_file = CsrFile::new();
+    csr_file.write(MTVEC, 0x100).unwrap();
+    csr_file.write(STVEC, 0x200).unwrap();
+    csr_file.write(MEDELEG, 1 << 8).unwrap();
+
+    // drop to U-mode
+    csr_file.write(MEPC, 0x1000).unwrap();
+    csr_file.write(MSTATUS, 0).unwrap();
+    assert_eq!(csr_file.trap_return(), 0x1000);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::User);
+
+    assert!(matches!(
+        csr_file.read(SSTATUS),
+        Err(CsrError::PrivilegeViolation(SSTATUS))
+    ));
+    assert!(matches!(
+        csr_file.read(CYCLE),
+        Err(CsrError::PrivilegeViolation(CYCLE))
+    ));
+
+    // ecall from U-mode is delegated to S-mode
+    let trap = Trap::new(Exception::EcallFromUMode, 0);
+    assert_eq!(csr_file.trap_enter(0x1004, trap), 0x200);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::Supervisor);
+    assert_eq!(csr_file.read(SEPC).unwrap(), 0x1004);
+    assert_eq!(csr_file.read(SCAUSE).unwrap(), 8);
+    assert_eq!(csr_file.read(SSTATUS).unwrap() & MSTATUS_SPP, 0);
+    assert!(matches!(
+        csr_file.read(MSTATUS),
+        Err(CsrError::PrivilegeViolation(MSTATUS))
+    ));
+
+    csr_file.write(SATP, 0x8000_1234).unwrap();
+    assert_eq!(csr_file.satp(), 0x8000_1234);
+
+    // not delegated, goes to M-mode and records S in MPP
+    let trap = Trap::new(Exception::IllegalInstruction, 0);
+    assert_eq!(csr_file.trap_enter(0x2000, trap), 0x100);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::Machine);
+    assert_eq!(
+        csr_file.read(MSTATUS).unwrap() & MSTATUS_MPP,
+        (PrivilegeMode::Supervisor as u32) << MSTATUS_MPP_SHIFT
+    );
+
+    assert_eq!(csr_file.trap_return(), 0x2000);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::Supervisor);
+    assert_eq!(csr_file.supervisor_trap_return(), 0x1004);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::User);
+}
+
+#[cfg(test)]
+#[test]
+fn test_interrupts() {
+    let csr_file = CsrFile::new();
+    csr_file.write(MTVEC, 0x101).unwrap(); // vectored
+    csr_file.write(STVEC, 0x200).unwrap();
+
+    csr_file.set_irq_line(Interrupt::MachineTimer, true);
+    assert_eq!(csr_file.read(MIP).unwrap(), MIP_MTIP);
+    // the line, not software, clears MTIP
+    csr_file.write(MIP, 0).unwrap();
+    assert_eq!(csr_file.read(MIP).unwrap(), MIP_MTIP);
+
+    // masked by mie, then by mstatus.MIE in M-mode
+    assert_eq!(csr_file.pending_interrupt(), None);
+    csr_file.write(MIE, u32::MAX).unwrap();
+    assert_eq!(csr_file.read(MIE).unwrap(), 0xaaa);
+    assert_eq!(csr_file.pending_interrupt(), None);
+    csr_file.write(MSTATUS, MSTATUS_MIE).unwrap();
+    assert_eq!(
+        csr_file.pending_interrupt(),
+        Some(Interrupt::MachineTimer)
+    );
+
+    // external interrupts come first
+    csr_file.set_irq_line(Interrupt::MachineExternal, true);
+    assert_eq!(
+        csr_file.pending_interrupt(),
+        Some(Interrupt::MachineExternal)
+    );
+    csr_file.set_irq_line(Interrupt::MachineExternal, false);
+
+    let handler = csr_file
+        .interrupt_enter(0x1000, Interrupt::MachineTimer);
+    assert_eq!(handler, 0x100 + 4 * 7);
+    assert_eq!(csr_file.read(MEPC).unwrap(), 0x1000);
+    assert_eq!(csr_file.read(MCAUSE).unwrap(), 0x8000_0007);
+    assert_eq!(csr_file.read(MSTATUS).unwrap() & MSTATUS_MIE, 0);
+    assert_eq!(csr_file.pending_interrupt(), None);
+    csr_file.set_irq_line(Interrupt::MachineTimer, false);
+
+    // a delegated one is not taken in M-mode, even with
+    // mstatus.SIE set
+    csr_file.write(MIDELEG, MIP_SSIP).unwrap();
+    csr_file.write(MIP, MIP_SSIP).unwrap();
+    csr_file
+        .write(MSTATUS, MSTATUS_MIE | MSTATUS_SIE)
+        .unwrap();
+    assert_eq!(csr_file.pending_interrupt(), None);
+    assert_eq!(csr_file.read(SIP).unwrap(), MIP_SSIP);
+    assert_eq!(csr_file.read(SIE).unwrap(), MIP_SSIP);
+
+    // but always in U-mode
+    csr_file.write(MEPC, 0x2000).unwrap();
+    csr_file.write(MSTATUS, 0).unwrap();
+    assert_eq!(csr_file.trap_return(), 0x2000);
+    assert_eq!(
+        csr_file.pending_interrupt(),
+        Some(Interrupt::SupervisorSoftware)
+    );
+    let handler = csr_file
+        .interrupt_enter(0x2000, Interrupt::SupervisorSoftware);
+    assert_eq!(handler, 0x200);
+    assert_eq!(csr_file.privilege(), PrivilegeMode::Supervisor);
+    assert_eq!(csr_file.read(SCAUSE).unwrap(), 0x8000_0001);
+
+    csr_file.write(SIP, 0).unwrap();
+    assert_eq!(csr_file.read(SIP).unwrap(), 0);
+}
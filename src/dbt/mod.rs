@@ -0,0 +1,412 @@
+use crate::{
+    bus::Bus,
+    mmu::{AccessType, FetchContext, Mmu},
+    pipeline::{
+        compressed::is_compressed, decode,
+        decode_cache::DecodeCache,
+        memory_access::MemoryAccessWidth,
+    },
+    register::RegFile,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+    sync::Arc,
+};
+
+mod translate;
+
+// interpreted this many times, a pc starts a block
+pub const HOT_THRESHOLD: u32 = 16;
+pub const MAX_BLOCK_SIZE: usize = 64;
+// chained blocks run before control goes back to the system
+const CHAIN_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DbtStats {
+    pub blocks_translated: u64,
+    pub blocks_executed: u64,
+    // block exits that found the next block through a link
+    pub chained: u64,
+    // blocks dropped after a store to their page or fence.i
+    pub invalidations: u64,
+}
+
+// how a translated instruction went on
+enum Flow {
+    Next,
+    // the last instruction of a block
+    Jump(u32),
+    // not executed, the interpreter has to run it, e.g. to
+    // take a trap
+    Exit,
+    // executed, but a store hit translated code
+    Stop,
+}
+
+type Op = Box<dyn Fn(&Dbt) -> Flow>;
+
+// straight-line code up to the first branch or jump, always
+// within one page
+struct Block {
+    pc: u32,
+    paddr: u32,
+    // pc maps to paddr for as long as this stays the same
+    context: FetchContext,
+    ops: Vec<Op>,
+    // of every instruction and of the one after the block
+    pcs: Vec<u32>,
+    is_valid: Cell<bool>,
+    // the blocks last reached by falling through and by
+    // taking the final branch or jump
+    links: RefCell<[Weak<Block>; 2]>,
+}
+
+// translates hot basic blocks of RV32IM into closures. The
+// interpreter runs everything else, including the first
+// instruction of a block that would trap
+pub struct Dbt {
+    bus: Arc<Bus>,
+    mmu: Arc<Mmu>,
+    reg_file: Arc<RegFile>,
+    decode_cache: Arc<DecodeCache>,
+
+    // by virtual pc, the context is checked on entry
+    blocks: RefCell<HashMap<u32, Rc<Block>>>,
+    heat: RefCell<HashMap<u32, u32>>,
+
+    stats: RefCell<DbtStats>,
+}
+
+impl Dbt {
+    pub fn new(
+        bus: Arc<Bus>,
+        mmu: Arc<Mmu>,
+        reg_file: Arc<RegFile>,
+        decode_cache: Arc<DecodeCache>,
+    ) -> Self {
+        Self {
+            bus,
+            mmu,
+            reg_file,
+            decode_cache,
+
+            blocks: RefCell::new(HashMap::new()),
+            heat: RefCell::new(HashMap::new()),
+
+            stats: RefCell::new(DbtStats::default()),
+        }
+    }
+
+    pub fn get_stats(&self) -> DbtStats {
+        self.stats.borrow().to_owned()
+    }
+
+    // runs the block at pc and the ones chained after it,
+    // retiring at most budget instructions. None if there is
+    // no hot block there, otherwise the number of
+    // instructions retired and where execution goes on
+    pub fn run(
+        &self,
+        pc: u32,
+        budget: u64,
+    ) -> Option<(u64, u32)> {
+        self.drop_written_blocks();
+        let mut block = self.lookup(pc)?;
+
+        let mut retired = 0;
+        for _ in 0..CHAIN_LIMIT {
+            self.stats.borrow_mut().blocks_executed += 1;
+            let (count, pc, is_chainable) =
+                self.execute_block(&block, budget - retired);
+            retired += count;
+            if !is_chainable || retired == budget {
+                return Some((retired, pc));
+            }
+            match self.follow(&block, pc) {
+                Some(next) => block = next,
+                None => return Some((retired, pc)),
+            }
+        }
+
+        Some((retired, block.pc))
+    }
+
+    fn execute_block(
+        &self,
+        block: &Block,
+        budget: u64,
+    ) -> (u64, u32, bool) {
+        let count = block.ops.len().min(budget as usize);
+        for (i, op) in block.ops[..count].iter().enumerate() {
+            match op(self) {
+                Flow::Next => {}
+                Flow::Jump(pc) => {
+                    return (i as u64 + 1, pc, true)
+                }
+                Flow::Exit => {
+                    return (i as u64, block.pcs[i], false)
+                }
+                Flow::Stop => {
+                    return (
+                        i as u64 + 1,
+                        block.pcs[i + 1],
+                        false,
+                    )
+                }
+            }
+        }
+
+        // cut short by the budget
+        let is_complete = count == block.ops.len();
+        (count as u64, block.pcs[count], is_complete)
+    }
+
+    // the block at pc if it was translated in the current
+    // context, so pc is still mapped at the same physical
+    // address
+    fn find(&self, pc: u32) -> Option<Rc<Block>> {
+        let context = self.mmu.fetch_context();
+        self.blocks
+            .borrow()
+            .get(&pc)
+            .filter(|block| block.context == context)
+            .cloned()
+    }
+
+    // translates pc once it has become hot
+    fn lookup(&self, pc: u32) -> Option<Rc<Block>> {
+        if let Some(block) = self.find(pc) {
+            return Some(block);
+        }
+
+        let mut heat = self.heat.borrow_mut();
+        let count = heat.entry(pc).or_insert(0);
+        *count += 1;
+        if *count < HOT_THRESHOLD {
+            return None;
+        }
+        // also retried later if pc cannot be translated
+        *count = 0;
+        drop(heat);
+
+        let paddr = self
+            .mmu
+            .translate(pc, AccessType::Fetch)
+            .ok()?;
+        let block =
+            Rc::new(self.translate_block(pc, paddr)?);
+        self.decode_cache.watch_page(paddr >> 12);
+        self.blocks.borrow_mut().insert(pc, block.clone());
+        self.stats.borrow_mut().blocks_translated += 1;
+        Some(block)
+    }
+
+    // the link from the previous block saves the lookup
+    fn follow(
+        &self,
+        from: &Block,
+        pc: u32,
+    ) -> Option<Rc<Block>> {
+        let slot =
+            (pc != *from.pcs.last().unwrap()) as usize;
+        // blocks cannot change the context, so a link made
+        // in it stays usable
+        let linked = from.links.borrow()[slot]
+            .upgrade()
+            .filter(|block| {
+                block.pc == pc
+                    && block.is_valid.get()
+                    && block.context == from.context
+            });
+        if let Some(block) = linked {
+            self.stats.borrow_mut().chained += 1;
+            return Some(block);
+        }
+
+        let block = self.find(pc)?;
+        from.links.borrow_mut()[slot] =
+            Rc::downgrade(&block);
+        Some(block)
+    }
+
+    fn fetch_half_word(&self, addr: u32) -> Option<u32> {
+        let word =
+            self.bus.fetch(addr as usize & !0b11).ok()?;
+        Some((word >> ((addr & 0b10) * 8)) & 0xffff)
+    }
+
+    fn translate_block(
+        &self,
+        pc: u32,
+        paddr: u32,
+    ) -> Option<Block> {
+        let mut ops = Vec::new();
+        let mut pcs = Vec::new();
+        let (mut pc_next, mut addr) = (pc, paddr);
+
+        while ops.len() < MAX_BLOCK_SIZE && addr & 0b1 == 0
+        {
+            let low = match self.fetch_half_word(addr) {
+                Some(low) => low,
+                None => break,
+            };
+            let high = if is_compressed(low) {
+                None
+            } else if addr & 0xfff <= 0xffc {
+                self.fetch_half_word(addr + 2)
+            } else {
+                break; // straddles a page
+            };
+            let (instruction, length) = match high {
+                None if is_compressed(low) => (low, 2),
+                Some(high) => ((high << 16) | low, 4),
+                None => break,
+            };
+            // devices may change what a fetch returns
+            if !self
+                .bus
+                .is_memory(addr as usize, length as usize)
+            {
+                break;
+            }
+
+            let val =
+                decode::decode_instruction(instruction);
+            let pc = pc_next;
+            let op = match translate::translate(
+                &val,
+                pc,
+                pc.wrapping_add(length),
+            ) {
+                Some(op) => op,
+                None => break,
+            };
+            pcs.push(pc);
+            ops.push(op);
+
+            pc_next = pc.wrapping_add(length);
+            addr += length;
+            if val.is_branch
+                || val.is_jal
+                || val.is_jalr
+                || addr & 0xfff == 0
+            {
+                break;
+            }
+        }
+
+        if ops.is_empty() {
+            return None;
+        }
+        pcs.push(pc_next);
+        Some(Block {
+            pc,
+            paddr,
+            context: self.mmu.fetch_context(),
+            ops,
+            pcs,
+            is_valid: Cell::new(true),
+            links: RefCell::new([Weak::new(), Weak::new()]),
+        })
+    }
+
+    fn drop_written_blocks(&self) {
+        if !self.decode_cache.has_written_pages() {
+            return;
+        }
+
+        let pages = self.decode_cache.take_written_pages();
+        let mut stats = self.stats.borrow_mut();
+        self.blocks.borrow_mut().retain(|_, block| {
+            let is_written =
+                pages.contains(&(block.paddr >> 12));
+            if is_written {
+                block.is_valid.set(false);
+                stats.invalidations += 1;
+            }
+            !is_written
+        });
+    }
+}
+
+// the state translated code works on
+impl Dbt {
+    fn x(&self, reg: u32) -> u32 {
+        if reg == 0 {
+            0
+        } else {
+            self.reg_file.borrow()[reg as usize].0
+        }
+    }
+
+    fn set_x(&self, reg: u32, val: u32) {
+        if reg != 0 {
+            self.reg_file.borrow_mut()[reg as usize].0 =
+                val;
+        }
+    }
+
+    // misaligned accesses and faults are left to the
+    // interpreter, which knows the policies for them. So are
+    // device accesses, which may read the time or raise an
+    // interrupt
+    fn load(
+        &self,
+        vaddr: u32,
+        width: MemoryAccessWidth,
+    ) -> Option<u32> {
+        if !(vaddr as usize).is_multiple_of(width.size()) {
+            return None;
+        }
+        let addr = self
+            .mmu
+            .translate(vaddr, AccessType::Load)
+            .ok()?;
+        if !self.bus.is_memory(addr as usize, width.size()) {
+            return None;
+        }
+        self.bus.read(addr as usize, width).ok()
+    }
+
+    fn store(
+        &self,
+        vaddr: u32,
+        val: u32,
+        width: MemoryAccessWidth,
+    ) -> Flow {
+        if !(vaddr as usize).is_multiple_of(width.size()) {
+            return Flow::Exit;
+        }
+        let addr = match self
+            .mmu
+            .translate(vaddr, AccessType::Store)
+        {
+            Ok(addr) => addr,
+            Err(_) => return Flow::Exit,
+        };
+        if !self.bus.is_memory(addr as usize, width.size()) {
+            return Flow::Exit;
+        }
+
+        let size = width.size() as u32;
+        self.decode_cache.invalidate(addr, size);
+        if self
+            .bus
+            .write(addr as usize, val, width)
+            .is_err()
+        {
+            return Flow::Exit;
+        }
+
+        // a store into translated code or to tohost
+        if self.decode_cache.has_written_pages()
+            || self.bus.get_tohost_exit().is_some()
+        {
+            Flow::Stop
+        } else {
+            Flow::Next
+        }
+    }
+}
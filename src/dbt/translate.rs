@@ -0,0 +1,165 @@
+use super::{Dbt, Flow, Op};
+use crate::pipeline::{
+    decode::DecodedValues,
+    execute::{mul_div, MulDivOperation},
+    memory_access::MemoryAccessWidth,
+};
+
+type Alu = fn(u32, u32) -> u32;
+
+fn sign_extend(val: u32, width: MemoryAccessWidth) -> u32 {
+    match width {
+        MemoryAccessWidth::Byte => val as i8 as i32 as u32,
+        MemoryAccessWidth::HalfWord => {
+            val as i16 as i32 as u32
+        }
+        _ => val,
+    }
+}
+
+// the same operations as the Execute stage's ALU, with the
+// funct3 and funct7 dispatch done once
+fn alu(
+    funct3: u32,
+    is_register_op: bool,
+    is_alternate: bool,
+) -> Alu {
+    match funct3 {
+        0b000 if is_register_op && is_alternate => {
+            u32::wrapping_sub
+        }
+        0b000 => u32::wrapping_add,
+        0b001 => |a, b| a << (b & 0x1f),
+        0b010 => |a, b| ((a as i32) < (b as i32)) as u32,
+        0b011 => |a, b| (a < b) as u32,
+        0b100 => |a, b| a ^ b,
+        0b101 if is_alternate => {
+            |a, b| ((a as i32) >> (b & 0x1f)) as u32
+        }
+        0b101 => |a, b| a >> (b & 0x1f),
+        0b110 => |a, b| a | b,
+        _ => |a, b| a & b,
+    }
+}
+
+fn branch_condition(
+    funct3: u32,
+) -> Option<fn(u32, u32) -> bool> {
+    match funct3 {
+        0b000 => Some(|a, b| a == b),
+        0b001 => Some(|a, b| a != b),
+        0b100 => Some(|a, b| (a as i32) < (b as i32)),
+        0b101 => Some(|a, b| (a as i32) >= (b as i32)),
+        0b110 => Some(|a, b| a < b),
+        0b111 => Some(|a, b| a >= b),
+        _ => None,
+    }
+}
+
+// the closure running the instruction at pc, None for what
+// is left to the interpreter: CSRs, system instructions,
+// fences, AMOs, FP and anything that always traps
+pub fn translate(
+    val: &DecodedValues,
+    pc: u32,
+    pc_next: u32,
+) -> Option<Op> {
+    if val.trap.is_some() {
+        return None;
+    }
+
+    let (rd, rs1, rs2) =
+        (val.rd, val.rs1_addr, val.rs2_addr);
+    let imm = val.imm32 as u32;
+
+    let op: Op = if val.is_mul_div {
+        let op =
+            MulDivOperation::try_from(val.funct3).ok()?;
+        Box::new(move |dbt: &Dbt| {
+            dbt.set_x(
+                rd,
+                mul_div(op, dbt.x(rs1), dbt.x(rs2)),
+            );
+            Flow::Next
+        })
+    } else if val.is_alu_operation {
+        let is_register_op = (val.opcode >> 5) & 1 == 1;
+        let is_alternate = (val.imm11_0 >> 10) & 1 == 1;
+        let alu =
+            alu(val.funct3, is_register_op, is_alternate);
+        if is_register_op {
+            Box::new(move |dbt: &Dbt| {
+                dbt.set_x(rd, alu(dbt.x(rs1), dbt.x(rs2)));
+                Flow::Next
+            })
+        } else {
+            Box::new(move |dbt: &Dbt| {
+                dbt.set_x(rd, alu(dbt.x(rs1), imm));
+                Flow::Next
+            })
+        }
+    } else if val.is_lui || val.is_auipc {
+        let result = if val.is_lui {
+            imm
+        } else {
+            pc.wrapping_add(imm)
+        };
+        Box::new(move |dbt: &Dbt| {
+            dbt.set_x(rd, result);
+            Flow::Next
+        })
+    } else if val.is_load {
+        let width =
+            MemoryAccessWidth::try_from(val.funct3 & 0b11)
+                .ok()?;
+        let signed_extend = val.funct3 & 0b100 == 0;
+        Box::new(move |dbt: &Dbt| {
+            let vaddr = dbt.x(rs1).wrapping_add(imm);
+            match dbt.load(vaddr, width) {
+                Some(val) if signed_extend => {
+                    dbt.set_x(rd, sign_extend(val, width));
+                    Flow::Next
+                }
+                Some(val) => {
+                    dbt.set_x(rd, val);
+                    Flow::Next
+                }
+                None => Flow::Exit,
+            }
+        })
+    } else if val.is_store {
+        let width =
+            MemoryAccessWidth::try_from(val.funct3 & 0b11)
+                .ok()?;
+        Box::new(move |dbt: &Dbt| {
+            let vaddr = dbt.x(rs1).wrapping_add(imm);
+            dbt.store(vaddr, dbt.x(rs2), width)
+        })
+    } else if val.is_branch {
+        let condition = branch_condition(val.funct3)?;
+        let target = pc.wrapping_add(imm);
+        Box::new(move |dbt: &Dbt| {
+            if condition(dbt.x(rs1), dbt.x(rs2)) {
+                Flow::Jump(target)
+            } else {
+                Flow::Jump(pc_next)
+            }
+        })
+    } else if val.is_jal {
+        let target = pc.wrapping_add(imm);
+        Box::new(move |dbt: &Dbt| {
+            dbt.set_x(rd, pc_next);
+            Flow::Jump(target)
+        })
+    } else if val.is_jalr {
+        Box::new(move |dbt: &Dbt| {
+            let target = dbt.x(rs1).wrapping_add(imm) & !1;
+            dbt.set_x(rd, pc_next);
+            Flow::Jump(target)
+        })
+    } else {
+        return None;
+    };
+
+    Some(op)
+}
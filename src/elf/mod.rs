@@ -0,0 +1,383 @@
+// ELF32 loader: the PT_LOAD segments of a little-endian
+// RISC-V executable are placed at their physical addresses,
+// and the symbol table is kept for tracing and debugging
+use std::fmt;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf32LittleEndian,
+    NotExecutable(u16),
+    NotRiscV(u16),
+    // file size larger than memory size
+    BadSegment(u32),
+    // the segment does not fit in RAM
+    SegmentOutOfMemory(u32, u32),
+}
+
+impl std::error::Error for ElfError {}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "Truncated ELF"),
+            ElfError::BadMagic => write!(f, "Not an ELF file"),
+            ElfError::NotElf32LittleEndian => {
+                write!(f, "Not a little-endian ELF32 file")
+            }
+            ElfError::NotExecutable(e_type) => {
+                write!(f, "Not an executable: {}", e_type)
+            }
+            ElfError::NotRiscV(machine) => {
+                write!(f, "Not a RISC-V file: {}", machine)
+            }
+            ElfError::BadSegment(paddr) => {
+                write!(f, "Bad segment at {:#010x}", paddr)
+            }
+            ElfError::SegmentOutOfMemory(paddr, size) => {
+                write!(
+                    f,
+                    "Segment out of RAM at {:#010x}, size {:#x}",
+                    paddr, size
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub paddr: u32,
+    pub data: Vec<u8>,
+    // anything past data, i.e. .bss, is zeroed
+    pub mem_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+// sorted by address
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|sym| sym.addr);
+        Self { symbols }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|sym| sym.name == name)
+    }
+
+    // the closest symbol at or below addr, which must also
+    // cover addr if its size is known
+    pub fn lookup(&self, addr: u32) -> Option<&Symbol> {
+        let idx = self
+            .symbols
+            .partition_point(|sym| sym.addr <= addr);
+        let sym = self.symbols[..idx].last()?;
+        (sym.size == 0 || addr - sym.addr < sym.size)
+            .then_some(sym)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ElfFile {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: SymbolTable,
+}
+
+fn read_u16(
+    file: &[u8],
+    offset: usize,
+) -> Result<u16, ElfError> {
+    file.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u32(
+    file: &[u8],
+    offset: usize,
+) -> Result<u32, ElfError> {
+    file.get(offset..offset + 4)
+        .map(|b| {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        })
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_bytes(
+    file: &[u8],
+    offset: u32,
+    size: u32,
+) -> Result<&[u8], ElfError> {
+    let start = offset as usize;
+    file.get(start..start + size as usize)
+        .ok_or(ElfError::Truncated)
+}
+
+// NUL-terminated string at offset in a string table
+fn read_str(strtab: &[u8], offset: u32) -> String {
+    let bytes =
+        strtab.get(offset as usize..).unwrap_or(&[]);
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+impl ElfFile {
+    pub fn is_elf(file: &[u8]) -> bool {
+        file.starts_with(&ELF_MAGIC)
+    }
+
+    pub fn parse(file: &[u8]) -> Result<Self, ElfError> {
+        if file.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if !Self::is_elf(file) {
+            return Err(ElfError::BadMagic);
+        }
+        if file[4] != ELFCLASS32 || file[5] != ELFDATA2LSB {
+            return Err(ElfError::NotElf32LittleEndian);
+        }
+
+        let e_type = read_u16(file, 16)?;
+        if e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable(e_type));
+        }
+        let machine = read_u16(file, 18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::NotRiscV(machine));
+        }
+
+        Ok(Self {
+            entry: read_u32(file, 24)?,
+            segments: Self::parse_segments(file)?,
+            symbols: Self::parse_symbols(file)?,
+        })
+    }
+
+    fn parse_segments(
+        file: &[u8],
+    ) -> Result<Vec<Segment>, ElfError> {
+        let phoff = read_u32(file, 28)? as usize;
+        let phnum = read_u16(file, 44)? as usize;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let phdr = phoff + i * PHDR_SIZE;
+            if read_u32(file, phdr)? != PT_LOAD {
+                continue;
+            }
+
+            let offset = read_u32(file, phdr + 4)?;
+            let paddr = read_u32(file, phdr + 12)?;
+            let file_size = read_u32(file, phdr + 16)?;
+            let mem_size = read_u32(file, phdr + 20)?;
+            if file_size > mem_size {
+                return Err(ElfError::BadSegment(paddr));
+            }
+
+            segments.push(Segment {
+                paddr,
+                data: read_bytes(file, offset, file_size)?
+                    .to_vec(),
+                mem_size,
+            });
+        }
+
+        Ok(segments)
+    }
+
+    // a stripped file has no symbol table, which is fine
+    fn parse_symbols(
+        file: &[u8],
+    ) -> Result<SymbolTable, ElfError> {
+        let shoff = read_u32(file, 32)? as usize;
+        let shnum = read_u16(file, 48)? as usize;
+        let section = |i: usize, field: usize| {
+            read_u32(file, shoff + i * SHDR_SIZE + field)
+        };
+
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            if section(i, 4)? != SHT_SYMTAB {
+                continue;
+            }
+
+            let symtab = read_bytes(
+                file,
+                section(i, 16)?,
+                section(i, 20)?,
+            )?;
+            let strtab_idx = section(i, 24)? as usize;
+            let strtab = read_bytes(
+                file,
+                section(strtab_idx, 16)?,
+                section(strtab_idx, 20)?,
+            )?;
+
+            for sym in symtab.chunks_exact(SYM_SIZE) {
+                let name =
+                    read_str(strtab, read_u32(sym, 0)?);
+                let sym_type = sym[12] & 0xf;
+                if name.is_empty()
+                    || sym_type == STT_SECTION
+                    || sym_type == STT_FILE
+                {
+                    continue;
+                }
+
+                symbols.push(Symbol {
+                    name,
+                    addr: read_u32(sym, 4)?,
+                    size: read_u32(sym, 8)?,
+                });
+            }
+        }
+
+        Ok(SymbolTable::new(symbols))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    fn push_u16(buf: &mut Vec<u8>, val: u16) {
+        buf.extend_from_slice(&val.to_le_bytes());
+    }
+    fn push_u32(buf: &mut Vec<u8>, val: u32) {
+        buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // header, one PT_LOAD, the code, a symbol table with its
+    // string table, and the section headers
+    let code = [0x13_u8, 0, 0, 0, 0, 0, 0, 0]; // nop; halt
+    let strtab = b"\0main\0_start\0";
+    let phoff = EHDR_SIZE as u32;
+    let code_off = phoff + PHDR_SIZE as u32;
+    let symtab_off = code_off + code.len() as u32;
+    let strtab_off = symtab_off + 3 * SYM_SIZE as u32;
+    let shoff = strtab_off + strtab.len() as u32;
+
+    let mut file = Vec::new();
+    file.extend_from_slice(&ELF_MAGIC);
+    file.extend_from_slice(&[ELFCLASS32, ELFDATA2LSB, 1]);
+    file.resize(16, 0);
+    push_u16(&mut file, ET_EXEC);
+    push_u16(&mut file, EM_RISCV);
+    push_u32(&mut file, 1); // e_version
+    push_u32(&mut file, 0x8000_0004); // e_entry
+    push_u32(&mut file, phoff);
+    push_u32(&mut file, shoff);
+    push_u32(&mut file, 0); // e_flags
+    push_u16(&mut file, EHDR_SIZE as u16);
+    push_u16(&mut file, PHDR_SIZE as u16);
+    push_u16(&mut file, 1); // e_phnum
+    push_u16(&mut file, SHDR_SIZE as u16);
+    push_u16(&mut file, 3); // e_shnum
+    push_u16(&mut file, 0); // e_shstrndx
+
+    for val in [PT_LOAD, code_off, 0x8000_0000, 0x8000_0000]
+    {
+        push_u32(&mut file, val);
+    }
+    for val in [code.len() as u32, 0x20, 0b101, 4] {
+        push_u32(&mut file, val);
+    }
+    file.extend_from_slice(&code);
+
+    // null symbol, then main and _start
+    file.resize(file.len() + SYM_SIZE, 0);
+    for (name, addr, size, info) in [
+        (1, 0x8000_0000, 8, 0x12),
+        (6, 0x8000_0004, 0, 0x10),
+    ] {
+        push_u32(&mut file, name);
+        push_u32(&mut file, addr);
+        push_u32(&mut file, size);
+        file.extend_from_slice(&[info, 0, 1, 0]);
+    }
+    file.extend_from_slice(strtab);
+
+    // null section, .symtab, .strtab
+    file.resize(file.len() + SHDR_SIZE, 0);
+    let symtab_size = 3 * SYM_SIZE as u32;
+    for val in
+        [0, SHT_SYMTAB, 0, 0, symtab_off, symtab_size]
+    {
+        push_u32(&mut file, val);
+    }
+    for val in [2, 1, 4, SYM_SIZE as u32] {
+        push_u32(&mut file, val);
+    }
+    for val in [0, 3, 0, 0, strtab_off, strtab.len() as u32]
+    {
+        push_u32(&mut file, val);
+    }
+    for val in [0, 0, 1, 0] {
+        push_u32(&mut file, val);
+    }
+
+    let elf = ElfFile::parse(&file).unwrap();
+    assert_eq!(elf.entry, 0x8000_0004);
+    assert_eq!(elf.segments.len(), 1);
+    assert_eq!(elf.segments[0].paddr, 0x8000_0000);
+    assert_eq!(elf.segments[0].data, code);
+    assert_eq!(elf.segments[0].mem_size, 0x20);
+
+    let main = elf.symbols.find("main").unwrap();
+    assert_eq!((main.addr, main.size), (0x8000_0000, 8));
+    assert_eq!(elf.symbols.lookup(0x8000_0002), Some(main));
+    assert_eq!(
+        elf.symbols.lookup(0x8000_0010).unwrap().name,
+        "_start"
+    );
+    assert_eq!(elf.symbols.lookup(0x7fff_fffc), None);
+
+    file[18] = 0x3e; // EM_X86_64
+    assert!(matches!(
+        ElfFile::parse(&file),
+        Err(ElfError::NotRiscV(0x3e))
+    ));
+    assert!(matches!(
+        ElfFile::parse(&file[..40]),
+        Err(ElfError::Truncated)
+    ));
+    assert!(matches!(
+        ElfFile::parse(&[0; EHDR_SIZE]),
+        Err(ElfError::BadMagic)
+    ));
+}
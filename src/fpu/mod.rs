@@ -0,0 +1,329 @@
+use num_enum::TryFromPrimitive;
+
+use self::softfloat::Format;
+
+pub mod softfloat;
+
+pub const FFLAG_NX: u32 = 1 << 0; // inexact
+pub const FFLAG_UF: u32 = 1 << 1; // underflow
+pub const FFLAG_OF: u32 = 1 << 2; // overflow
+pub const FFLAG_DZ: u32 = 1 << 3; // divide by zero
+pub const FFLAG_NV: u32 = 1 << 4; // invalid operation
+
+// rm = 0b111 selects the dynamic rounding mode in frm
+const RM_DYNAMIC: u32 = 0b111;
+
+// single-precision values live in the low half of the 64-bit
+// registers, with the upper half all ones
+const NAN_BOX: u64 = 0xffff_ffff_0000_0000;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive,
+)]
+#[repr(u32)]
+pub enum RoundingMode {
+    RNE = 0b000, // to nearest, ties to even
+    RTZ = 0b001, // towards zero
+    RDN = 0b010, // down
+    RUP = 0b011, // up
+    RMM = 0b100, // to nearest, ties away from zero
+}
+
+pub const OPCODE_LOAD_FP: u32 = 0b000_0111;
+pub const OPCODE_STORE_FP: u32 = 0b010_0111;
+const OPCODE_MADD: u32 = 0b100_0011;
+const OPCODE_MSUB: u32 = 0b100_0111;
+const OPCODE_NMSUB: u32 = 0b100_1011;
+const OPCODE_NMADD: u32 = 0b100_1111;
+const OPCODE_OP_FP: u32 = 0b101_0011;
+
+// funct5 of OP-FP, bits 31:27
+#[derive(Debug, Clone, Copy, TryFromPrimitive)]
+#[repr(u32)]
+#[allow(clippy::upper_case_acronyms)]
+enum FpOperation {
+    FADD = 0b00000,
+    FSUB = 0b00001,
+    FMUL = 0b00010,
+    FDIV = 0b00011,
+    FSGNJ = 0b00100,
+    FMINMAX = 0b00101,
+    FCVTFF = 0b01000, // between single and double
+    FSQRT = 0b01011,
+    FCMP = 0b10100,
+    FCVTWF = 0b11000, // to an integer
+    FCVTFW = 0b11010, // from an integer
+    FMVXF = 0b11100,  // also fclass
+    FMVFX = 0b11110,
+}
+
+// fields of an instruction from one of the FP opcodes
+struct FpInstruction {
+    opcode: u32,
+    op: Option<FpOperation>,
+    format: Option<Format>,
+    funct3: u32,
+    rs2: u32,
+}
+
+impl FpInstruction {
+    fn new(instruction: u32) -> Self {
+        let format = match (instruction >> 25) & 0b11 {
+            0b00 => Some(Format::Single),
+            0b01 => Some(Format::Double),
+            _ => None,
+        };
+
+        Self {
+            opcode: instruction & 0x7f,
+            op: FpOperation::try_from(instruction >> 27)
+                .ok(),
+            format,
+            funct3: (instruction >> 12) & 0b111,
+            rs2: (instruction >> 20) & 0x1f,
+        }
+    }
+
+    fn is_fma(&self) -> bool {
+        matches!(
+            self.opcode,
+            OPCODE_MADD
+                | OPCODE_MSUB
+                | OPCODE_NMSUB
+                | OPCODE_NMADD
+        )
+    }
+
+    // whether funct3 holds a rounding mode
+    fn uses_rm(&self) -> bool {
+        self.is_fma()
+            || matches!(
+                self.op,
+                Some(
+                    FpOperation::FADD
+                        | FpOperation::FSUB
+                        | FpOperation::FMUL
+                        | FpOperation::FDIV
+                        | FpOperation::FSQRT
+                        | FpOperation::FCVTFF
+                        | FpOperation::FCVTWF
+                        | FpOperation::FCVTFW
+                )
+            )
+    }
+}
+
+// whether the instruction is an FMA or OP-FP instruction
+// this implementation knows, funct3 = 0b101 and 0b110 are
+// reserved rounding modes
+pub fn is_valid(instruction: u32) -> bool {
+    let inst = FpInstruction::new(instruction);
+    if inst.format.is_none()
+        || (inst.uses_rm()
+            && matches!(inst.funct3, 0b101 | 0b110))
+    {
+        return false;
+    }
+    if inst.is_fma() {
+        return true;
+    }
+    if inst.opcode != OPCODE_OP_FP {
+        return false;
+    }
+
+    let is_single = inst.format == Some(Format::Single);
+    match inst.op {
+        Some(
+            FpOperation::FADD
+            | FpOperation::FSUB
+            | FpOperation::FMUL
+            | FpOperation::FDIV,
+        ) => true,
+        Some(FpOperation::FSQRT) => inst.rs2 == 0,
+        Some(FpOperation::FSGNJ) => inst.funct3 <= 0b010,
+        Some(FpOperation::FMINMAX) => inst.funct3 <= 0b001,
+        // fcvt.s.d and fcvt.d.s
+        Some(FpOperation::FCVTFF) => {
+            inst.rs2 == is_single as u32
+        }
+        Some(FpOperation::FCMP) => inst.funct3 <= 0b010,
+        Some(FpOperation::FCVTWF | FpOperation::FCVTFW) => {
+            inst.rs2 <= 1
+        }
+        // fmv.x.w is single only, there is no fmv.x.d on RV32
+        Some(FpOperation::FMVXF) => {
+            inst.rs2 == 0
+                && (inst.funct3 == 0b001
+                    || (inst.funct3 == 0b000 && is_single))
+        }
+        Some(FpOperation::FMVFX) => {
+            inst.rs2 == 0 && inst.funct3 == 0 && is_single
+        }
+        None => false,
+    }
+}
+
+// FMA and OP-FP instructions, rs1 is an integer register for
+// fcvt.s.w and fmv.w.x, rd is one for compares, fclass,
+// fcvt.w.s and fmv.x.w
+pub fn writes_int_reg(instruction: u32) -> bool {
+    let inst = FpInstruction::new(instruction);
+    inst.opcode == OPCODE_OP_FP
+        && matches!(
+            inst.op,
+            Some(
+                FpOperation::FCMP
+                    | FpOperation::FCVTWF
+                    | FpOperation::FMVXF
+            )
+        )
+}
+
+// resolves rm = 0b111 to frm, None for a reserved rounding
+// mode. Instructions without a rounding mode get RNE, which
+// they ignore
+pub fn rounding_mode(
+    instruction: u32,
+    frm: u32,
+) -> Option<RoundingMode> {
+    let inst = FpInstruction::new(instruction);
+    if !inst.uses_rm() {
+        return Some(RoundingMode::RNE);
+    }
+
+    let rm = if inst.funct3 == RM_DYNAMIC {
+        frm
+    } else {
+        inst.funct3
+    };
+    RoundingMode::try_from(rm).ok()
+}
+
+pub fn nan_box(val: u32) -> u64 {
+    NAN_BOX | val as u64
+}
+
+// an improperly boxed single is read as the canonical NaN
+fn unbox(val: u64) -> u64 {
+    if val & NAN_BOX == NAN_BOX {
+        val & 0xffff_ffff
+    } else {
+        Format::Single.canonical_nan()
+    }
+}
+
+// returns the result, NaN-boxed when it is a single, and the
+// exception flags raised
+pub fn execute(
+    instruction: u32,
+    rs1: u32,
+    frs1: u64,
+    frs2: u64,
+    frs3: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let inst = FpInstruction::new(instruction);
+    let fmt = inst.format.expect("Invalid FP format");
+
+    let operand = |val: u64| match fmt {
+        Format::Single => unbox(val),
+        Format::Double => val,
+    };
+    let result = |(val, flags): (u64, u32)| match fmt {
+        Format::Single => (nan_box(val as u32), flags),
+        Format::Double => (val, flags),
+    };
+    let int_result =
+        |(val, flags): (u32, u32)| (val as u64, flags);
+    let (a, b, c) =
+        (operand(frs1), operand(frs2), operand(frs3));
+    let sign_bit = match fmt {
+        Format::Single => 1 << 31,
+        Format::Double => 1 << 63,
+    };
+
+    if inst.is_fma() {
+        let (a, c) = match inst.opcode {
+            OPCODE_MADD => (a, c),
+            OPCODE_MSUB => (a, c ^ sign_bit),
+            OPCODE_NMSUB => (a ^ sign_bit, c),
+            _ => (a ^ sign_bit, c ^ sign_bit), // NMADD
+        };
+        return result(softfloat::mul_add(
+            fmt, a, b, c, rm,
+        ));
+    }
+
+    match inst.op.expect("Invalid FP operation") {
+        FpOperation::FADD => {
+            result(softfloat::add(fmt, a, b, rm))
+        }
+        FpOperation::FSUB => {
+            result(softfloat::sub(fmt, a, b, rm))
+        }
+        FpOperation::FMUL => {
+            result(softfloat::mul(fmt, a, b, rm))
+        }
+        FpOperation::FDIV => {
+            result(softfloat::div(fmt, a, b, rm))
+        }
+        FpOperation::FSQRT => {
+            result(softfloat::sqrt(fmt, a, rm))
+        }
+        FpOperation::FSGNJ => {
+            let sign = match inst.funct3 {
+                0b000 => b & sign_bit,
+                0b001 => !b & sign_bit,
+                _ => (a ^ b) & sign_bit, // fsgnjx
+            };
+            result(((a & !sign_bit) | sign, 0))
+        }
+        FpOperation::FMINMAX => result(softfloat::min_max(
+            fmt,
+            a,
+            b,
+            inst.funct3 == 0b001,
+        )),
+        FpOperation::FCVTFF => match fmt {
+            // fcvt.s.d
+            Format::Single => result(softfloat::convert(
+                Format::Double,
+                Format::Single,
+                frs1,
+                rm,
+            )),
+            // fcvt.d.s
+            Format::Double => softfloat::convert(
+                Format::Single,
+                Format::Double,
+                unbox(frs1),
+                rm,
+            ),
+        },
+        FpOperation::FCMP => {
+            int_result(match inst.funct3 {
+                0b000 => softfloat::le(fmt, a, b),
+                0b001 => softfloat::lt(fmt, a, b),
+                _ => softfloat::eq(fmt, a, b),
+            })
+        }
+        FpOperation::FCVTWF => int_result(
+            softfloat::to_int(fmt, a, inst.rs2 == 0, rm),
+        ),
+        FpOperation::FCVTFW => result(softfloat::from_int(
+            fmt,
+            rs1,
+            inst.rs2 == 0,
+            rm,
+        )),
+        FpOperation::FMVXF => {
+            if inst.funct3 == 0b001 {
+                (softfloat::classify(fmt, a) as u64, 0)
+            } else {
+                // fmv.x.w moves the raw bits, boxed or not
+                (frs1 & 0xffff_ffff, 0)
+            }
+        }
+        FpOperation::FMVFX => (nan_box(rs1), 0),
+    }
+}
@@ -0,0 +1,938 @@
+use std::cmp::Ordering;
+
+use super::{
+    RoundingMode, FFLAG_DZ, FFLAG_NV, FFLAG_NX, FFLAG_OF,
+    FFLAG_UF,
+};
+
+// The host FPU only rounds to nearest-even and does not
+// report exception flags, so every operation is carried out
+// on integer significands and rounded exactly once, by
+// round_pack.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Single,
+    Double,
+}
+
+impl Format {
+    fn frac_bits(self) -> i32 {
+        match self {
+            Format::Single => 23,
+            Format::Double => 52,
+        }
+    }
+
+    fn exp_bits(self) -> i32 {
+        match self {
+            Format::Single => 8,
+            Format::Double => 11,
+        }
+    }
+
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits() - 1)) - 1
+    }
+
+    fn emin(self) -> i32 {
+        1 - self.bias()
+    }
+
+    fn max_biased_exp(self) -> u64 {
+        (1 << self.exp_bits()) - 1
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits()) - 1
+    }
+
+    fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits() + self.frac_bits())
+    }
+
+    fn sign(self, sign: bool) -> u64 {
+        if sign {
+            self.sign_bit()
+        } else {
+            0
+        }
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        match self {
+            Format::Single => 0x7fc0_0000,
+            Format::Double => 0x7ff8_0000_0000_0000,
+        }
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        self.sign(sign)
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.sign(sign)
+            | (self.max_biased_exp() << self.frac_bits())
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.infinity(sign) - 1
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Zero(bool),
+    Infinity(bool),
+    NaN { is_signaling: bool },
+    // sign, exp, sig: the value is sig * 2^exp
+    Finite(bool, i32, u128),
+}
+
+fn unpack(fmt: Format, bits: u64) -> Value {
+    let sign = bits & fmt.sign_bit() != 0;
+    let biased =
+        (bits >> fmt.frac_bits()) & fmt.max_biased_exp();
+    let frac = bits & fmt.frac_mask();
+
+    if biased == fmt.max_biased_exp() {
+        if frac == 0 {
+            Value::Infinity(sign)
+        } else {
+            let quiet_bit = 1 << (fmt.frac_bits() - 1);
+            Value::NaN {
+                is_signaling: frac & quiet_bit == 0,
+            }
+        }
+    } else if biased == 0 {
+        if frac == 0 {
+            Value::Zero(sign)
+        } else {
+            let exp = fmt.emin() - fmt.frac_bits();
+            Value::Finite(sign, exp, frac as u128)
+        }
+    } else {
+        let exp =
+            biased as i32 - fmt.bias() - fmt.frac_bits();
+        let sig = frac | (1 << fmt.frac_bits());
+        Value::Finite(sign, exp, sig as u128)
+    }
+}
+
+fn bit_length(sig: u128) -> i32 {
+    128 - sig.leading_zeros() as i32
+}
+
+// drops the low `shift` bits of sig, returns the rounded
+// value and whether anything non-zero was dropped
+fn round_bits(
+    sig: u128,
+    shift: i32,
+    rm: RoundingMode,
+    sign: bool,
+) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+
+    let shift = shift as u32;
+    let (kept, rem) = if shift >= 128 {
+        (0, sig)
+    } else {
+        (sig >> shift, sig & ((1 << shift) - 1))
+    };
+    if rem == 0 {
+        return (kept, false);
+    }
+
+    let half = if shift > 128 {
+        Ordering::Less
+    } else {
+        rem.cmp(&(1 << (shift - 1)))
+    };
+    let round_up = match rm {
+        RoundingMode::RNE => {
+            half == Ordering::Greater
+                || (half == Ordering::Equal
+                    && kept & 1 == 1)
+        }
+        RoundingMode::RTZ => false,
+        RoundingMode::RDN => sign,
+        RoundingMode::RUP => !sign,
+        RoundingMode::RMM => half != Ordering::Less,
+    };
+
+    (kept + round_up as u128, true)
+}
+
+// rounds sig * 2^exp (sig != 0) to the format
+fn round_pack(
+    fmt: Format,
+    sign: bool,
+    exp: i32,
+    sig: u128,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let precision = fmt.frac_bits() + 1;
+    let top = exp + bit_length(sig) - 1;
+    let lsb = (top - (precision - 1))
+        .max(fmt.emin() - fmt.frac_bits());
+
+    let (mut kept, inexact) =
+        round_bits(sig, lsb - exp, rm, sign);
+    let mut lsb = lsb;
+    if kept >> precision != 0 {
+        kept >>= 1;
+        lsb += 1;
+    }
+
+    let mut flags = 0;
+    if inexact {
+        flags |= FFLAG_NX;
+
+        // tininess is detected after rounding, as if the
+        // exponent range were unbounded
+        if top < fmt.emin() {
+            let (unbounded, _) = round_bits(
+                sig,
+                top - (precision - 1) - exp,
+                rm,
+                sign,
+            );
+            let carries = unbounded >> precision != 0;
+            if !(carries && top + 1 == fmt.emin()) {
+                flags |= FFLAG_UF;
+            }
+        }
+    }
+
+    // subnormal, or zero after rounding
+    if kept >> (precision - 1) == 0 {
+        return (fmt.sign(sign) | kept as u64, flags);
+    }
+
+    let biased =
+        (lsb + fmt.frac_bits() + fmt.bias()) as u64;
+    if biased >= fmt.max_biased_exp() {
+        let to_infinity = match rm {
+            RoundingMode::RNE | RoundingMode::RMM => true,
+            RoundingMode::RTZ => false,
+            RoundingMode::RDN => sign,
+            RoundingMode::RUP => !sign,
+        };
+        let bits = if to_infinity {
+            fmt.infinity(sign)
+        } else {
+            fmt.max_finite(sign)
+        };
+        return (bits, FFLAG_OF | FFLAG_NX);
+    }
+
+    let bits = fmt.sign(sign)
+        | (biased << fmt.frac_bits())
+        | (kept as u64 & fmt.frac_mask());
+    (bits, flags)
+}
+
+// an exact zero result of x + y is +0, or -0 when rounding down
+fn zero_sum(fmt: Format, rm: RoundingMode) -> u64 {
+    fmt.zero(rm == RoundingMode::RDN)
+}
+
+fn nan_flags(values: &[Value]) -> u32 {
+    let is_signaling = values.iter().any(|v| {
+        matches!(v, Value::NaN { is_signaling: true })
+    });
+    if is_signaling {
+        FFLAG_NV
+    } else {
+        0
+    }
+}
+
+fn add_finite(
+    fmt: Format,
+    a: (bool, i32, u128),
+    b: (bool, i32, u128),
+    rm: RoundingMode,
+) -> (u64, u32) {
+    // both operands are placed in a 127-bit window below the
+    // larger one. Bits that fall out of the window are jammed
+    // into its lowest bit, far below the rounding position
+    let top = |(_, exp, sig): (bool, i32, u128)| {
+        exp + bit_length(sig)
+    };
+    let base = top(a).max(top(b)) - 126;
+    let align = |(_, exp, sig): (bool, i32, u128)| {
+        if exp >= base {
+            sig << (exp - base)
+        } else {
+            let shift = base - exp;
+            if shift >= 128 {
+                (sig != 0) as u128
+            } else {
+                let lost = sig & ((1 << shift) - 1);
+                (sig >> shift) | (lost != 0) as u128
+            }
+        }
+    };
+
+    let (sig_a, sig_b) = (align(a), align(b));
+    let (sign, sig) = if a.0 == b.0 {
+        (a.0, sig_a + sig_b)
+    } else {
+        match sig_a.cmp(&sig_b) {
+            Ordering::Greater => (a.0, sig_a - sig_b),
+            Ordering::Less => (b.0, sig_b - sig_a),
+            Ordering::Equal => {
+                return (zero_sum(fmt, rm), 0)
+            }
+        }
+    };
+
+    round_pack(fmt, sign, base, sig, rm)
+}
+
+pub fn add(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+
+    match (va, vb) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => {
+            (fmt.canonical_nan(), nan_flags(&[va, vb]))
+        }
+        (Value::Infinity(sa), Value::Infinity(sb)) => {
+            if sa == sb {
+                (a, 0)
+            } else {
+                (fmt.canonical_nan(), FFLAG_NV)
+            }
+        }
+        (Value::Infinity(_), _) => (a, 0),
+        (_, Value::Infinity(_)) => (b, 0),
+        (Value::Zero(sa), Value::Zero(sb)) => {
+            if sa == sb {
+                (a, 0)
+            } else {
+                (zero_sum(fmt, rm), 0)
+            }
+        }
+        (Value::Zero(_), _) => (b, 0),
+        (_, Value::Zero(_)) => (a, 0),
+        (
+            Value::Finite(sa, ea, ma),
+            Value::Finite(sb, eb, mb),
+        ) => {
+            add_finite(fmt, (sa, ea, ma), (sb, eb, mb), rm)
+        }
+    }
+}
+
+pub fn sub(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    add(fmt, a, b ^ fmt.sign_bit(), rm)
+}
+
+pub fn mul(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    let sign = (a ^ b) & fmt.sign_bit() != 0;
+
+    match (va, vb) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => {
+            (fmt.canonical_nan(), nan_flags(&[va, vb]))
+        }
+        (Value::Infinity(_), Value::Zero(_))
+        | (Value::Zero(_), Value::Infinity(_)) => {
+            (fmt.canonical_nan(), FFLAG_NV)
+        }
+        (Value::Infinity(_), _)
+        | (_, Value::Infinity(_)) => {
+            (fmt.infinity(sign), 0)
+        }
+        (Value::Zero(_), _) | (_, Value::Zero(_)) => {
+            (fmt.zero(sign), 0)
+        }
+        (
+            Value::Finite(_, ea, ma),
+            Value::Finite(_, eb, mb),
+        ) => round_pack(fmt, sign, ea + eb, ma * mb, rm),
+    }
+}
+
+pub fn div(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let (va, vb) = (unpack(fmt, a), unpack(fmt, b));
+    let sign = (a ^ b) & fmt.sign_bit() != 0;
+
+    match (va, vb) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => {
+            (fmt.canonical_nan(), nan_flags(&[va, vb]))
+        }
+        (Value::Infinity(_), Value::Infinity(_))
+        | (Value::Zero(_), Value::Zero(_)) => {
+            (fmt.canonical_nan(), FFLAG_NV)
+        }
+        (Value::Infinity(_), _) => (fmt.infinity(sign), 0),
+        (_, Value::Zero(_)) => {
+            (fmt.infinity(sign), FFLAG_DZ)
+        }
+        (Value::Zero(_), _) | (_, Value::Infinity(_)) => {
+            (fmt.zero(sign), 0)
+        }
+        (
+            Value::Finite(_, ea, ma),
+            Value::Finite(_, eb, mb),
+        ) => {
+            // at least 72 quotient bits, the remainder becomes
+            // a sticky bit below them
+            let shift = 125 - bit_length(ma);
+            let num = ma << shift;
+            let (quot, rem) = (num / mb, num % mb);
+            let sig = (quot << 1) | (rem != 0) as u128;
+            round_pack(
+                fmt,
+                sign,
+                ea - shift - eb - 1,
+                sig,
+                rm,
+            )
+        }
+    }
+}
+
+fn isqrt(n: u128) -> u128 {
+    let mut rem = n;
+    let mut root = 0_u128;
+    let mut bit = 1_u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    root
+}
+
+pub fn sqrt(
+    fmt: Format,
+    a: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    match unpack(fmt, a) {
+        va @ Value::NaN { .. } => {
+            (fmt.canonical_nan(), nan_flags(&[va]))
+        }
+        Value::Zero(_) | Value::Infinity(false) => (a, 0),
+        Value::Infinity(true)
+        | Value::Finite(true, _, _) => {
+            (fmt.canonical_nan(), FFLAG_NV)
+        }
+        Value::Finite(false, exp, sig) => {
+            // an even exponent, and a 124-bit radicand for a
+            // root of at least 62 bits
+            let mut shift = 124 - bit_length(sig);
+            if (exp - shift) % 2 != 0 {
+                shift += 1;
+            }
+            let radicand = sig << shift;
+            let root = isqrt(radicand);
+            let sticky = radicand != root * root;
+            let sig = (root << 1) | sticky as u128;
+            round_pack(
+                fmt,
+                false,
+                (exp - shift) / 2 - 1,
+                sig,
+                rm,
+            )
+        }
+    }
+}
+
+// (a * b) + c with a single rounding
+pub fn mul_add(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    c: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let (va, vb, vc) =
+        (unpack(fmt, a), unpack(fmt, b), unpack(fmt, c));
+    let sign = (a ^ b) & fmt.sign_bit() != 0;
+
+    // invalid even when the addend is a quiet NaN
+    let is_invalid_product = matches!(
+        (va, vb),
+        (Value::Infinity(_), Value::Zero(_))
+            | (Value::Zero(_), Value::Infinity(_))
+    );
+    if is_invalid_product {
+        return (fmt.canonical_nan(), FFLAG_NV);
+    }
+
+    match (va, vb, vc) {
+        (Value::NaN { .. }, _, _)
+        | (_, Value::NaN { .. }, _)
+        | (_, _, Value::NaN { .. }) => {
+            (fmt.canonical_nan(), nan_flags(&[va, vb, vc]))
+        }
+        (Value::Infinity(_), _, _)
+        | (_, Value::Infinity(_), _) => match vc {
+            Value::Infinity(sc) if sc != sign => {
+                (fmt.canonical_nan(), FFLAG_NV)
+            }
+            _ => (fmt.infinity(sign), 0),
+        },
+        (_, _, Value::Infinity(_)) => (c, 0),
+        (Value::Zero(_), _, _) | (_, Value::Zero(_), _) => {
+            add(fmt, fmt.zero(sign), c, rm)
+        }
+        (
+            Value::Finite(_, ea, ma),
+            Value::Finite(_, eb, mb),
+            Value::Zero(_),
+        ) => round_pack(fmt, sign, ea + eb, ma * mb, rm),
+        (
+            Value::Finite(_, ea, ma),
+            Value::Finite(_, eb, mb),
+            Value::Finite(sc, ec, mc),
+        ) => add_finite(
+            fmt,
+            (sign, ea + eb, ma * mb),
+            (sc, ec, mc),
+            rm,
+        ),
+    }
+}
+
+pub fn convert(
+    from: Format,
+    to: Format,
+    a: u64,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    match unpack(from, a) {
+        va @ Value::NaN { .. } => {
+            (to.canonical_nan(), nan_flags(&[va]))
+        }
+        Value::Zero(sign) => (to.zero(sign), 0),
+        Value::Infinity(sign) => (to.infinity(sign), 0),
+        Value::Finite(sign, exp, sig) => {
+            round_pack(to, sign, exp, sig, rm)
+        }
+    }
+}
+
+pub fn from_int(
+    fmt: Format,
+    val: u32,
+    is_signed: bool,
+    rm: RoundingMode,
+) -> (u64, u32) {
+    let sign = is_signed && (val as i32) < 0;
+    let magnitude = if sign {
+        (val as i32).unsigned_abs()
+    } else {
+        val
+    };
+
+    if magnitude == 0 {
+        (fmt.zero(false), 0)
+    } else {
+        round_pack(fmt, sign, 0, magnitude as u128, rm)
+    }
+}
+
+// out-of-range values and NaNs saturate and raise NV
+pub fn to_int(
+    fmt: Format,
+    a: u64,
+    is_signed: bool,
+    rm: RoundingMode,
+) -> (u32, u32) {
+    let saturated = |sign: bool| match (is_signed, sign) {
+        (true, false) => i32::MAX as u32,
+        (true, true) => i32::MIN as u32,
+        (false, false) => u32::MAX,
+        (false, true) => 0,
+    };
+
+    let (sign, exp, sig) = match unpack(fmt, a) {
+        Value::NaN { .. } => {
+            return (saturated(false), FFLAG_NV)
+        }
+        Value::Infinity(sign) => {
+            return (saturated(sign), FFLAG_NV)
+        }
+        Value::Zero(_) => return (0, 0),
+        Value::Finite(sign, exp, sig) => (sign, exp, sig),
+    };
+
+    // anything shifted left by more than 32 is out of range
+    let (magnitude, inexact) = if exp > 32 {
+        return (saturated(sign), FFLAG_NV);
+    } else {
+        round_bits(sig, -exp, rm, sign)
+    };
+
+    let limit = match (is_signed, sign) {
+        (true, false) => i32::MAX as u128,
+        (true, true) => 1 << 31,
+        (false, false) => u32::MAX as u128,
+        (false, true) => 0,
+    };
+    if magnitude > limit {
+        return (saturated(sign), FFLAG_NV);
+    }
+
+    let val = if sign {
+        (magnitude as u32).wrapping_neg()
+    } else {
+        magnitude as u32
+    };
+    (val, if inexact { FFLAG_NX } else { 0 })
+}
+
+fn is_nan(fmt: Format, a: u64) -> bool {
+    matches!(unpack(fmt, a), Value::NaN { .. })
+}
+
+fn is_signaling_nan(fmt: Format, a: u64) -> bool {
+    matches!(
+        unpack(fmt, a),
+        Value::NaN { is_signaling: true }
+    )
+}
+
+// total order on non-NaN values, -0 and +0 compare equal
+fn compare(fmt: Format, a: u64, b: u64) -> Ordering {
+    let key = |bits: u64| -> i128 {
+        match unpack(fmt, bits) {
+            Value::Zero(_) => 0,
+            _ if bits & fmt.sign_bit() != 0 => {
+                -((bits & !fmt.sign_bit()) as i128)
+            }
+            _ => bits as i128,
+        }
+    };
+    key(a).cmp(&key(b))
+}
+
+// feq is quiet, flt and fle signal on any NaN
+pub fn eq(fmt: Format, a: u64, b: u64) -> (u32, u32) {
+    if is_nan(fmt, a) || is_nan(fmt, b) {
+        let is_signaling = is_signaling_nan(fmt, a)
+            || is_signaling_nan(fmt, b);
+        return (
+            0,
+            if is_signaling { FFLAG_NV } else { 0 },
+        );
+    }
+    ((compare(fmt, a, b) == Ordering::Equal) as u32, 0)
+}
+
+pub fn lt(fmt: Format, a: u64, b: u64) -> (u32, u32) {
+    if is_nan(fmt, a) || is_nan(fmt, b) {
+        return (0, FFLAG_NV);
+    }
+    ((compare(fmt, a, b) == Ordering::Less) as u32, 0)
+}
+
+pub fn le(fmt: Format, a: u64, b: u64) -> (u32, u32) {
+    if is_nan(fmt, a) || is_nan(fmt, b) {
+        return (0, FFLAG_NV);
+    }
+    ((compare(fmt, a, b) != Ordering::Greater) as u32, 0)
+}
+
+// a single NaN operand is ignored, -0 is smaller than +0
+pub fn min_max(
+    fmt: Format,
+    a: u64,
+    b: u64,
+    is_max: bool,
+) -> (u64, u32) {
+    let flags = if is_signaling_nan(fmt, a)
+        || is_signaling_nan(fmt, b)
+    {
+        FFLAG_NV
+    } else {
+        0
+    };
+
+    let val = match (is_nan(fmt, a), is_nan(fmt, b)) {
+        (true, true) => fmt.canonical_nan(),
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let order =
+                compare(fmt, a, b).then_with(|| {
+                    // only zeros of different signs are left
+                    let sign_a = a & fmt.sign_bit() != 0;
+                    let sign_b = b & fmt.sign_bit() != 0;
+                    sign_b.cmp(&sign_a)
+                });
+            if (order == Ordering::Less) != is_max {
+                a
+            } else {
+                b
+            }
+        }
+    };
+
+    (val, flags)
+}
+
+pub fn classify(fmt: Format, a: u64) -> u32 {
+    let is_subnormal =
+        (a >> fmt.frac_bits()) & fmt.max_biased_exp() == 0
+            && a & fmt.frac_mask() != 0;
+
+    let bit = match unpack(fmt, a) {
+        Value::Infinity(true) => 0,
+        Value::Finite(true, _, _) if !is_subnormal => 1,
+        Value::Finite(true, _, _) => 2,
+        Value::Zero(true) => 3,
+        Value::Zero(false) => 4,
+        Value::Finite(false, _, _) if is_subnormal => 5,
+        Value::Finite(false, _, _) => 6,
+        Value::Infinity(false) => 7,
+        Value::NaN { is_signaling: true } => 8,
+        Value::NaN {
+            is_signaling: false,
+        } => 9,
+    };
+    1 << bit
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use RoundingMode::*;
+
+    let s = Format::Single;
+    let d = Format::Double;
+    // the host does not produce canonical NaNs
+    let single = |x: f32| {
+        if x.is_nan() {
+            s.canonical_nan()
+        } else {
+            x.to_bits() as u64
+        }
+    };
+    let double = |x: f64| {
+        if x.is_nan() {
+            d.canonical_nan()
+        } else {
+            x.to_bits()
+        }
+    };
+
+    // agrees with the host, which rounds to nearest-even
+    let samples = [
+        1.0_f64,
+        -2.5,
+        3.0e-310,
+        0.1,
+        1.0e300,
+        -7.0,
+        1.0 / 3.0,
+        6.02e23,
+        -1.0e-5,
+        5.0e-324,
+    ];
+    for &x in samples.iter() {
+        for &y in samples.iter() {
+            assert_eq!(
+                add(d, double(x), double(y), RNE).0,
+                double(x + y)
+            );
+            assert_eq!(
+                mul(d, double(x), double(y), RNE).0,
+                double(x * y)
+            );
+            assert_eq!(
+                div(d, double(x), double(y), RNE).0,
+                double(x / y)
+            );
+            assert_eq!(
+                mul_add(
+                    d,
+                    double(x),
+                    double(y),
+                    double(x),
+                    RNE
+                )
+                .0,
+                double(x.mul_add(y, x))
+            );
+
+            let (x, y) = (x as f32, y as f32);
+            assert_eq!(
+                sub(s, single(x), single(y), RNE).0,
+                single(x - y)
+            );
+            assert_eq!(
+                div(s, single(x), single(y), RNE).0,
+                single(x / y)
+            );
+        }
+        assert_eq!(
+            sqrt(d, double(x.abs()), RNE).0,
+            double(x.abs().sqrt())
+        );
+    }
+
+    // directed rounding and flags
+    let third = |rm| div(s, single(1.0), single(3.0), rm);
+    assert_eq!(third(RNE), (single(1.0 / 3.0), FFLAG_NX));
+    assert_eq!(third(RTZ).0, single(1.0 / 3.0) - 1);
+    assert_eq!(third(RUP).0, single(1.0 / 3.0));
+    let minus_third =
+        |rm| div(s, single(-1.0), single(3.0), rm);
+    assert_eq!(minus_third(RDN).0, single(-1.0 / 3.0));
+    assert_eq!(minus_third(RUP).0, single(-1.0 / 3.0) - 1);
+    assert_eq!(
+        add(s, single(1.0), single(1.0), RUP),
+        (single(2.0), 0)
+    );
+    assert_eq!(
+        add(s, single(2.5), single(0.0), RMM),
+        (single(2.5), 0)
+    );
+    assert_eq!(
+        sub(d, double(1.0), double(1.0), RDN).0,
+        double(-0.0)
+    );
+    assert_eq!(
+        div(d, double(1.0), double(0.0), RNE),
+        (double(f64::INFINITY), FFLAG_DZ)
+    );
+    assert_eq!(
+        mul(s, single(f32::MAX), single(2.0), RTZ),
+        (single(f32::MAX), FFLAG_OF | FFLAG_NX)
+    );
+    assert_eq!(
+        mul(s, single(f32::MAX), single(2.0), RNE).0,
+        single(f32::INFINITY)
+    );
+    assert_eq!(
+        mul(s, single(1.0e-30), single(1.0e-30), RNE),
+        (0, FFLAG_UF | FFLAG_NX)
+    );
+    assert_eq!(
+        sqrt(s, single(-1.0), RNE),
+        (s.canonical_nan(), FFLAG_NV)
+    );
+    assert_eq!(
+        add(s, 0x7f80_0001, single(1.0), RNE),
+        (s.canonical_nan(), FFLAG_NV)
+    );
+
+    // conversions
+    assert_eq!(
+        to_int(s, single(2.5), true, RNE),
+        (2, FFLAG_NX)
+    );
+    assert_eq!(
+        to_int(s, single(2.5), true, RMM),
+        (3, FFLAG_NX)
+    );
+    assert_eq!(
+        to_int(s, single(-2.5), true, RDN),
+        (-3_i32 as u32, FFLAG_NX)
+    );
+    assert_eq!(
+        to_int(d, double(-1.0), false, RTZ),
+        (0, FFLAG_NV)
+    );
+    assert_eq!(
+        to_int(d, double(-0.5), false, RTZ),
+        (0, FFLAG_NX)
+    );
+    assert_eq!(
+        to_int(d, double(3.0e9), true, RTZ),
+        (i32::MAX as u32, FFLAG_NV)
+    );
+    assert_eq!(
+        to_int(d, double(3.0e9), false, RTZ),
+        (3_000_000_000, 0)
+    );
+    assert_eq!(
+        to_int(s, s.canonical_nan(), true, RTZ),
+        (i32::MAX as u32, FFLAG_NV)
+    );
+    assert_eq!(
+        from_int(s, 16_777_217, false, RNE),
+        (single(16_777_216.0), FFLAG_NX)
+    );
+    assert_eq!(
+        from_int(s, 16_777_217, false, RUP).0,
+        single(16_777_218.0)
+    );
+    assert_eq!(
+        from_int(d, -5_i32 as u32, true, RNE),
+        (double(-5.0), 0)
+    );
+    assert_eq!(
+        convert(d, s, double(0.1), RNE),
+        (single(0.1), FFLAG_NX)
+    );
+    assert_eq!(
+        convert(s, d, single(0.1), RNE),
+        (double(0.1_f32 as f64), 0)
+    );
+
+    // comparisons, min/max and classification
+    assert_eq!(eq(s, single(0.0), single(-0.0)), (1, 0));
+    assert_eq!(
+        eq(s, s.canonical_nan(), single(1.0)),
+        (0, 0)
+    );
+    assert_eq!(
+        lt(s, s.canonical_nan(), single(1.0)),
+        (0, FFLAG_NV)
+    );
+    assert_eq!(le(d, double(-1.0), double(-1.0)), (1, 0));
+    assert_eq!(
+        min_max(s, single(0.0), single(-0.0), false).0,
+        single(-0.0)
+    );
+    assert_eq!(
+        min_max(s, single(0.0), single(-0.0), true).0,
+        single(0.0)
+    );
+    assert_eq!(
+        min_max(s, s.canonical_nan(), single(2.0), true),
+        (single(2.0), 0)
+    );
+    assert_eq!(classify(d, double(-0.0)), 1 << 3);
+    assert_eq!(classify(d, double(5.0e-324)), 1 << 5);
+    assert_eq!(classify(s, s.canonical_nan()), 1 << 9);
+}
@@ -0,0 +1,907 @@
+// GDB remote serial protocol stub, for `target remote`.
+// Breakpoints and watchpoints are checked between
+// instructions, the guest memory is never patched
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    collections::HashSet,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    csr::{FCSR, FFLAGS, FRM},
+    register::NUM_REGISTER,
+    rv_system::{
+        error::EmulatorError, HaltReason, RV32System,
+    },
+};
+
+// GDB's register numbers for RISC-V
+const PC_REGNUM: usize = 32;
+const FPR_REGNUM: usize = 33;
+const CSR_REGNUM: usize = 65;
+const FP_CSRS: [u32; 3] = [FFLAGS, FRM, FCSR];
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// ^C from GDB while the guest is running
+const INTERRUPT: u8 = 0x03;
+// instructions run between two checks for an interrupt
+const INTERRUPT_INTERVAL: u64 = 0x1000;
+
+pub trait Connection: Read + Write {
+    fn set_nonblocking(
+        &self,
+        nonblocking: bool,
+    ) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(
+        &self,
+        nonblocking: bool,
+    ) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(
+        &self,
+        nonblocking: bool,
+    ) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Detached,
+    Killed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u32,
+    len: u32,
+}
+
+enum StopReason {
+    Step,
+    SwBreak,
+    HwBreak,
+    Watch(WatchKind, u32),
+    Interrupted,
+    Error(EmulatorError),
+    // with the low byte of the guest's exit code
+    Exited(u8),
+    Disconnected,
+}
+
+enum Response {
+    Reply(String),
+    End(SessionEnd, Option<&'static str>),
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()
+        })
+        .collect()
+}
+
+// registers are sent in target byte order
+fn to_hex_le(val: u64, size: usize) -> String {
+    to_hex(&val.to_le_bytes()[..size])
+}
+
+fn from_hex_le(hex: &str) -> Option<u64> {
+    let bytes = from_hex(hex)?;
+    (bytes.len() <= 8).then(|| {
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |val, &b| (val << 8) | b as u64)
+    })
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+// "addr,len"
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for i in 0..NUM_REGISTER {
+        xml += &format!(
+            "<reg name=\"x{}\" bitsize=\"32\" type=\"int\" \
+             regnum=\"{}\"/>",
+            i, i
+        );
+    }
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" \
+         regnum=\"{}\"/></feature>\
+         <feature name=\"org.gnu.gdb.riscv.fpu\">",
+        PC_REGNUM
+    );
+    for i in 0..NUM_REGISTER {
+        xml += &format!(
+            "<reg name=\"f{}\" bitsize=\"64\" \
+             type=\"ieee_double\" regnum=\"{}\"/>",
+            i,
+            FPR_REGNUM + i
+        );
+    }
+    for (name, csr) in
+        ["fflags", "frm", "fcsr"].iter().zip(FP_CSRS)
+    {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" \
+             regnum=\"{}\"/>",
+            name,
+            CSR_REGNUM + csr as usize
+        );
+    }
+    xml + "</feature></target>"
+}
+
+pub struct GdbStub<'a, C: Connection> {
+    rv32_sys: &'a RV32System,
+    conn: C,
+    no_ack: bool,
+
+    sw_breakpoints: HashSet<u32>,
+    hw_breakpoints: HashSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+// `target` is either a TCP port on localhost or the path of a
+// Unix socket, a single GDB connection is served
+pub fn serve(
+    rv32_sys: &RV32System,
+    target: &str,
+) -> io::Result<SessionEnd> {
+    if let Ok(port) = target.parse::<u16>() {
+        let listener =
+            TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on localhost:{}", port);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        return GdbStub::new(rv32_sys, stream).run();
+    }
+
+    #[cfg(unix)]
+    {
+        let listener = UnixListener::bind(target)?;
+        println!("Waiting for GDB on {}", target);
+        let (stream, _) = listener.accept()?;
+        let end = GdbStub::new(rv32_sys, stream).run();
+        std::fs::remove_file(target)?;
+        end
+    }
+
+    #[cfg(not(unix))]
+    Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "GDB target must be a TCP port",
+    ))
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    pub fn new(rv32_sys: &'a RV32System, conn: C) -> Self {
+        Self {
+            rv32_sys,
+            conn,
+            no_ack: false,
+
+            sw_breakpoints: HashSet::new(),
+            hw_breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    // serves packets until GDB detaches, kills the guest or
+    // disconnects
+    pub fn run(&mut self) -> io::Result<SessionEnd> {
+        while let Some(packet) = self.read_packet()? {
+            let packet = String::from_utf8_lossy(&packet);
+            match self.handle(&packet)? {
+                Response::Reply(reply) => {
+                    self.write_packet(&reply)?
+                }
+                Response::End(end, reply) => {
+                    if let Some(reply) = reply {
+                        self.write_packet(reply)?;
+                    }
+                    return Ok(end);
+                }
+            }
+        }
+
+        Ok(SessionEnd::Detached)
+    }
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    // None once the connection is closed
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0];
+        loop {
+            match self.conn.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(e)
+                    if e.kind()
+                        == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // "$data#checksum", anything in between packets (acks, a
+    // late ^C) is dropped
+    fn read_packet(
+        &mut self,
+    ) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = String::new();
+            for _ in 0..2 {
+                match self.read_byte()? {
+                    Some(b) => sum.push(b as char),
+                    None => return Ok(None),
+                }
+            }
+
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if u8::from_str_radix(&sum, 16).ok()
+                == Some(checksum(&data))
+            {
+                self.conn.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.conn.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(
+        &mut self,
+        data: &str,
+    ) -> io::Result<()> {
+        let mut escaped = Vec::new();
+        for b in data.bytes() {
+            if matches!(b, b'#' | b'$' | b'}' | b'*') {
+                escaped
+                    .extend_from_slice(&[b'}', b ^ 0x20]);
+            } else {
+                escaped.push(b);
+            }
+        }
+
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(
+            format!("#{:02x}", checksum(&escaped))
+                .as_bytes(),
+        );
+
+        loop {
+            self.conn.write_all(&packet)?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            // resent until GDB acknowledges it
+            loop {
+                match self.read_byte()? {
+                    Some(b'+') | None => return Ok(()),
+                    Some(b'-') => break,
+                    Some(_) => {}
+                }
+            }
+        }
+    }
+
+    // a byte from GDB while the guest runs can only be ^C
+    fn poll_interrupt(
+        &mut self,
+    ) -> io::Result<Option<StopReason>> {
+        let mut buf = [0];
+        self.conn.set_nonblocking(true)?;
+        let result = self.conn.read(&mut buf);
+        self.conn.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Ok(Some(StopReason::Disconnected)),
+            Ok(_) if buf[0] == INTERRUPT => {
+                Ok(Some(StopReason::Interrupted))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    fn handle(
+        &mut self,
+        packet: &str,
+    ) -> io::Result<Response> {
+        let reply = |reply: &str| {
+            Response::Reply(reply.to_string())
+        };
+        let error = reply("E01");
+        if packet.is_empty() {
+            return Ok(reply(""));
+        }
+        let (cmd, args) = packet.split_at(1);
+
+        let response = match cmd {
+            "?" => self.stop_reply(StopReason::Step),
+            "g" => Response::Reply(self.read_registers()),
+            "G" => match self.write_registers(args) {
+                Some(()) => reply("OK"),
+                None => error,
+            },
+            "p" => {
+                match parse_hex(args).and_then(|regnum| {
+                    self.read_register(regnum as usize)
+                }) {
+                    Some(val) => Response::Reply(val),
+                    None => error,
+                }
+            }
+            "P" => match args.split_once('=').and_then(
+                |(regnum, val)| {
+                    self.write_register(
+                        parse_hex(regnum)? as usize,
+                        val,
+                    )
+                },
+            ) {
+                Some(()) => reply("OK"),
+                None => error,
+            },
+            "m" => match parse_addr_len(args).and_then(
+                |(addr, len)| self.read_memory(addr, len),
+            ) {
+                Some(data) => Response::Reply(data),
+                None => reply("E14"),
+            },
+            "M" => match args.split_once(':').and_then(
+                |(addr_len, data)| {
+                    let (addr, _) =
+                        parse_addr_len(addr_len)?;
+                    self.write_memory(addr, data)
+                },
+            ) {
+                Some(()) => reply("OK"),
+                None => reply("E14"),
+            },
+            // an optional address to resume at
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.rv32_sys.set_pc(addr);
+                }
+                let stop = self.resume(cmd == "s")?;
+                self.stop_reply(stop)
+            }
+            "Z" | "z" => {
+                match self.breakpoint(cmd == "Z", args) {
+                    Some(true) => reply("OK"),
+                    Some(false) => reply(""),
+                    None => error,
+                }
+            }
+            "D" => Response::End(
+                SessionEnd::Detached,
+                Some("OK"),
+            ),
+            "k" => Response::End(SessionEnd::Killed, None),
+            // there is a single thread
+            "H" | "T" => reply("OK"),
+            _ => self.query(packet),
+        };
+
+        Ok(response)
+    }
+
+    fn query(&mut self, packet: &str) -> Response {
+        let reply = |reply: &str| {
+            Response::Reply(reply.to_string())
+        };
+
+        if packet.starts_with("qSupported") {
+            reply(
+                "PacketSize=1000;QStartNoAckMode+;swbreak+;\
+                 hwbreak+;qXfer:features:read+",
+            )
+        } else if packet == "QStartNoAckMode" {
+            // the OK itself is already sent without an ack
+            self.no_ack = true;
+            reply("OK")
+        } else if let Some(args) = packet
+            .strip_prefix("qXfer:features:read:target.xml:")
+        {
+            match parse_addr_len(args) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start =
+                        (offset as usize).min(xml.len());
+                    let end = (start + len as usize)
+                        .min(xml.len());
+                    let more = if end < xml.len() {
+                        "m"
+                    } else {
+                        "l"
+                    };
+                    Response::Reply(
+                        more.to_string() + &xml[start..end],
+                    )
+                }
+                None => reply("E01"),
+            }
+        } else if packet == "vKill" {
+            Response::End(SessionEnd::Killed, Some("OK"))
+        } else {
+            match packet {
+                "qAttached" => reply("1"),
+                "qC" => reply("QC1"),
+                "qfThreadInfo" => reply("m1"),
+                "qsThreadInfo" => reply("l"),
+                // unsupported, which includes vCont
+                _ => reply(""),
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: StopReason) -> Response {
+        let reply = match stop {
+            StopReason::Step => format!("S{:02x}", SIGTRAP),
+            StopReason::SwBreak => {
+                format!("T{:02x}swbreak:;", SIGTRAP)
+            }
+            StopReason::HwBreak => {
+                format!("T{:02x}hwbreak:;", SIGTRAP)
+            }
+            StopReason::Watch(kind, addr) => {
+                let kind = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!(
+                    "T{:02x}{}:{:x};",
+                    SIGTRAP, kind, addr
+                )
+            }
+            StopReason::Interrupted => {
+                format!("S{:02x}", SIGINT)
+            }
+            // the system has stopped on an error, and can
+            // still be inspected
+            StopReason::Error(EmulatorError::Decode {
+                ..
+            }) => format!("S{:02x}", SIGILL),
+            StopReason::Error(_) => {
+                format!("S{:02x}", SIGSEGV)
+            }
+            // the halt word exits with status 0
+            StopReason::Exited(code) => format!("W{:02x}", code),
+            StopReason::Disconnected => {
+                return Response::End(
+                    SessionEnd::Detached,
+                    None,
+                );
+            }
+        };
+
+        Response::Reply(reply)
+    }
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+    fn read_register(
+        &self,
+        regnum: usize,
+    ) -> Option<String> {
+        match regnum {
+            0..=31 => {
+                let reg = self.rv32_sys.get_reg()[regnum].0;
+                Some(to_hex_le(reg as u64, 4))
+            }
+            PC_REGNUM => Some(to_hex_le(
+                self.rv32_sys.get_pc() as u64,
+                4,
+            )),
+            33..=64 => {
+                let freg = self.rv32_sys.get_freg()
+                    [regnum - FPR_REGNUM]
+                    .0;
+                Some(to_hex_le(freg, 8))
+            }
+            _ => {
+                let csr =
+                    u32::try_from(regnum - CSR_REGNUM)
+                        .ok()?;
+                let val = self.rv32_sys.read_csr(csr)?;
+                Some(to_hex_le(val as u64, 4))
+            }
+        }
+    }
+
+    fn write_register(
+        &self,
+        regnum: usize,
+        hex: &str,
+    ) -> Option<()> {
+        let val = from_hex_le(hex)?;
+        match regnum {
+            0..=31 => {
+                self.rv32_sys.set_reg(regnum, val as u32)
+            }
+            PC_REGNUM => self.rv32_sys.set_pc(val as u32),
+            33..=64 => self
+                .rv32_sys
+                .set_freg(regnum - FPR_REGNUM, val),
+            _ => {
+                let csr =
+                    u32::try_from(regnum - CSR_REGNUM)
+                        .ok()?;
+                self.rv32_sys
+                    .write_csr(csr, val as u32)
+                    .then_some(())?;
+            }
+        }
+        Some(())
+    }
+
+    // every register in target.xml, in regnum order
+    fn g_packet_regnums() -> impl Iterator<Item = usize> {
+        (0..CSR_REGNUM).chain(
+            FP_CSRS.map(|csr| CSR_REGNUM + csr as usize),
+        )
+    }
+
+    fn register_size(regnum: usize) -> usize {
+        if (FPR_REGNUM..CSR_REGNUM).contains(&regnum) {
+            8
+        } else {
+            4
+        }
+    }
+
+    // unreadable registers, e.g. fcsr while mstatus.FS is
+    // off, are reported as unavailable
+    fn read_registers(&self) -> String {
+        Self::g_packet_regnums()
+            .map(|regnum| {
+                self.read_register(regnum).unwrap_or_else(
+                    || {
+                        "xx".repeat(Self::register_size(
+                            regnum,
+                        ))
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn write_registers(&self, hex: &str) -> Option<()> {
+        let mut offset = 0;
+        for regnum in Self::g_packet_regnums() {
+            let size = Self::register_size(regnum) * 2;
+            let Some(val) = hex.get(offset..offset + size)
+            else {
+                break;
+            };
+            offset += size;
+            if !val.starts_with('x') {
+                self.write_register(regnum, val)?;
+            }
+        }
+        Some(())
+    }
+
+    // stops at the first unreadable byte
+    fn read_memory(
+        &self,
+        addr: u32,
+        len: u32,
+    ) -> Option<String> {
+        let bytes = (0..len)
+            .map_while(|i| {
+                self.rv32_sys
+                    .debug_read(addr.wrapping_add(i))
+            })
+            .collect::<Vec<_>>();
+        (len == 0 || !bytes.is_empty())
+            .then(|| to_hex(&bytes))
+    }
+
+    fn write_memory(
+        &self,
+        addr: u32,
+        hex: &str,
+    ) -> Option<()> {
+        for (i, byte) in
+            from_hex(hex)?.into_iter().enumerate()
+        {
+            self.rv32_sys
+                .debug_write(
+                    addr.wrapping_add(i as u32),
+                    byte,
+                )
+                .then_some(())?;
+        }
+        Some(())
+    }
+
+    // "type,addr,kind", Some(false) for an unsupported type
+    fn breakpoint(
+        &mut self,
+        insert: bool,
+        args: &str,
+    ) -> Option<bool> {
+        let (kind, addr_len) = args.split_once(',')?;
+        let (addr, len) = parse_addr_len(addr_len)?;
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                let breakpoints = if kind == "0" {
+                    &mut self.sw_breakpoints
+                } else {
+                    &mut self.hw_breakpoints
+                };
+                if insert {
+                    breakpoints.insert(addr);
+                } else {
+                    breakpoints.remove(&addr);
+                }
+                return Some(true);
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(false),
+        };
+
+        let watchpoint = Watchpoint {
+            kind: watch_kind,
+            addr,
+            len,
+        };
+        if insert {
+            self.watchpoints.push(watchpoint);
+        } else {
+            self.watchpoints.retain(|&wp| wp != watchpoint);
+        }
+        Some(true)
+    }
+
+    fn hit_watchpoint(&self) -> Option<StopReason> {
+        let access = self.rv32_sys.get_data_access()?;
+        let start = access.vaddr as u64;
+        let end = start + access.size as u64;
+
+        self.watchpoints.iter().find_map(|wp| {
+            let overlaps = start
+                < wp.addr as u64 + wp.len as u64
+                && (wp.addr as u64) < end;
+            let matches = match wp.kind {
+                WatchKind::Write => access.is_write,
+                WatchKind::Read => access.is_read,
+                WatchKind::Access => true,
+            };
+            (overlaps && matches).then_some(
+                StopReason::Watch(wp.kind, wp.addr),
+            )
+        })
+    }
+
+    fn resume(
+        &mut self,
+        single_step: bool,
+    ) -> io::Result<StopReason> {
+        let mut steps = 0_u64;
+        loop {
+            if !self.rv32_sys.step() {
+                if let Some(error) = self.rv32_sys.get_error() {
+                    return Ok(StopReason::Error(error));
+                }
+                return Ok(match self.rv32_sys.get_halt_reason() {
+                    Some(HaltReason::Exit(code)) => {
+                        StopReason::Exited(code as u8)
+                    }
+                    _ => StopReason::Exited(0),
+                });
+            }
+            if let Some(stop) = self.hit_watchpoint() {
+                return Ok(stop);
+            }
+            if single_step {
+                return Ok(StopReason::Step);
+            }
+
+            let pc = self.rv32_sys.get_pc();
+            if self.sw_breakpoints.contains(&pc) {
+                return Ok(StopReason::SwBreak);
+            }
+            if self.hw_breakpoints.contains(&pc) {
+                return Ok(StopReason::HwBreak);
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_INTERVAL) {
+                if let Some(stop) = self.poll_interrupt()? {
+                    return Ok(stop);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    use std::{cell::Cell, io::Cursor};
+
+    struct MockConnection {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        nonblocking: Cell<bool>,
+    }
+
+    impl Read for MockConnection {
+        fn read(
+            &mut self,
+            buf: &mut [u8],
+        ) -> io::Result<usize> {
+            if self.nonblocking.get() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConnection {
+        fn write(
+            &mut self,
+            buf: &[u8],
+        ) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn set_nonblocking(
+            &self,
+            nonblocking: bool,
+        ) -> io::Result<()> {
+            self.nonblocking.set(nonblocking);
+            Ok(())
+        }
+    }
+
+    let packet = |data: &str| {
+        format!(
+            "${}#{:02x}",
+            data,
+            checksum(data.as_bytes())
+        )
+    };
+
+    let rom_file = [
+        0x00100513_u32, // li a0, 1
+        0x00200593,     // li a1, 2
+        0x80000437,     // lui s0, 0x80000
+        0x00b42023,     // sw a1, 0(s0)
+        0x00550513,     // addi a0, a0, 5
+        0,
+    ];
+    let session = [
+        ("QStartNoAckMode", "OK"),
+        ("?", "S05"),
+        ("Z0,40000008,4", "OK"),
+        ("c", "T05swbreak:;"),
+        ("p20", "08000040"), // pc
+        ("pa", "01000000"),  // a0
+        ("Z2,80000000,4", "OK"),
+        ("c", "T05watch:80000000;"),
+        ("m80000000,4", "02000000"),
+        ("Pa=07000000", "OK"),
+        ("s", "S05"),
+        ("M80000004,4:efbeadde", "OK"),
+        ("Z9,0,0", ""),
+        ("c", "W00"),
+    ];
+
+    let mut input = String::new();
+    let mut expected = String::from("+");
+    for (request, reply) in session {
+        input += &packet(request);
+        expected += &packet(reply);
+    }
+    input += &packet("k");
+
+    let rv32_sys = RV32System::new(&rom_file);
+    rv32_sys.set_halt_word(true);
+    let mut stub = GdbStub::new(
+        &rv32_sys,
+        MockConnection {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+            nonblocking: Cell::new(false),
+        },
+    );
+
+    assert_eq!(stub.run().unwrap(), SessionEnd::Killed);
+    assert_eq!(
+        String::from_utf8(stub.conn.output).unwrap(),
+        expected
+    );
+    assert_eq!(rv32_sys.get_reg()[10].0, 12);
+    assert_eq!(rv32_sys.get_mem(8)[1], 0xdead_beef);
+}
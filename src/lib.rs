@@ -5,18 +5,26 @@ use wasm_bindgen::prelude::*;
 extern crate web_sys;
 
 use crate::register::{Register32, NUM_REGISTER};
-use bus::RAM_START;
+use bus::{uart::UARTBackend, RAM_START};
+use elf::{ElfError, ElfFile};
 use rv_system::RV32System;
 
-mod bus;
-mod pipeline;
-mod register;
-mod rv_system;
+pub mod branch_predictor;
+pub mod bus;
+pub mod csr;
+pub mod dbt;
+pub mod elf;
+pub mod fpu;
+pub mod gdb;
+pub mod mmu;
+pub mod pipeline;
+pub mod register;
+pub mod rv_system;
+pub mod trap;
 
 fn get_rom_file(rom_file: &[u8]) -> Vec<u32> {
     rom_file
         .chunks(4)
-        .into_iter()
         .map(|chunk| {
             u32::from_le_bytes(
                 chunk.try_into().expect("cast error"),
@@ -25,62 +33,138 @@ fn get_rom_file(rom_file: &[u8]) -> Vec<u32> {
         .collect()
 }
 
-fn run(
-    rom_file: &[u32],
-    mem_dump_size: usize
-) -> ([Register32; NUM_REGISTER], Vec<u32>) {
-    let rv32_sys = RV32System::new(rom_file);
-    rv32_sys.run();
+// ELF files are loaded by segment, anything else is a raw
+// image placed in ROM. Raw images come from the old
+// bootloader, which ends with the all-zero word, so it halts
+fn load(file: &[u8]) -> Result<RV32System, ElfError> {
+    if ElfFile::is_elf(file) {
+        RV32System::from_elf(&ElfFile::parse(file)?)
+    } else {
+        let rv32_sys = RV32System::new(&get_rom_file(file));
+        rv32_sys.set_halt_word(true);
+        Ok(rv32_sys)
+    }
+}
+
+// the dump follows the error, if the system stopped on one
+fn run(rv32_sys: &RV32System, mem_dump_size: usize) -> String {
+    let result = rv32_sys.run();
+    let output = get_output(
+        &rv32_sys.get_reg(),
+        &rv32_sys.get_mem(mem_dump_size),
+    );
 
-    (rv32_sys.get_reg(), rv32_sys.get_mem(mem_dump_size))
+    match result {
+        Ok(_) => output,
+        Err(e) => format!("Error: {}\n\n{}", e, output),
+    }
 }
 
 fn get_output(
     reg: &[Register32; NUM_REGISTER],
-    mem: &Vec<u32>,
+    mem: &[u32],
 ) -> String {
     let reg_out = reg
-        .into_iter()
-        .zip(0..reg.len())
-        .map(|(reg, i)| {
+        .iter()
+        .enumerate()
+        .map(|(i, reg)| {
             let mut out = format!("x{}: {:#010x}\t", i, reg.0);
             if (i + 1) % 4 == 0 {
-                out = out + "\n";
+                out += "\n";
             }
             out
         })
         .collect::<String>();
 
     let mem_out = mem
-        .into_iter()
-        .zip(0..mem.len())
-        .map(|(val, i)| {
+        .iter()
+        .enumerate()
+        .map(|(i, val)| {
             let mut out = format!("{:#010x} ", val);
             if i % 4 == 0 {
-                out = String::from(format!(
-                    "\n{:#010x}: ",
-                    RAM_START + i * 4
-                )) + &out;
+                out = format!("\n{:#010x}: ", RAM_START + i * 4)
+                    + &out;
             }
             out
         })
         .collect::<String>();
 
-    let output = String::from("Register Dump:\n\n")
+    String::from("Register Dump:\n\n")
         + &reg_out
-        + &"\nMemory Dump:\n"
-        + &mem_out;
-
-    output
+        + "\nMemory Dump:\n"
+        + &mem_out
 }
 
 #[wasm_bindgen]
 pub fn emulate(rom_file: &[u8], mem_dump_size: usize) -> String {
-    let rom = get_rom_file(rom_file);
+    let rv32_sys = match load(rom_file) {
+        Ok(rv32_sys) => rv32_sys,
+        Err(e) => return e.to_string(),
+    };
+
+    run(&rv32_sys, mem_dump_size)
+}
+
+// the guest's UART output is handed to on_output one byte at
+// a time, on_input is polled for a byte and may return
+// undefined when there is none
+struct JsBackend {
+    on_output: js_sys::Function,
+    on_input: Option<js_sys::Function>,
+}
+
+impl UARTBackend for JsBackend {
+    fn put(&self, byte: u8) {
+        let _ = self
+            .on_output
+            .call1(&JsValue::NULL, &JsValue::from(byte));
+    }
 
-    let (reg, mem) = run(&rom, mem_dump_size);
+    fn get(&self) -> Option<u8> {
+        let on_input = self.on_input.as_ref()?;
+        let byte = on_input.call0(&JsValue::NULL).ok()?;
+        byte.as_f64().map(|byte| byte as u8)
+    }
+}
+
+#[wasm_bindgen]
+pub fn emulate_with_uart(
+    rom_file: &[u8],
+    mem_dump_size: usize,
+    uart_base: usize,
+    on_output: js_sys::Function,
+    on_input: Option<js_sys::Function>,
+) -> String {
+    let rv32_sys = match load(rom_file) {
+        Ok(rv32_sys) => rv32_sys,
+        Err(e) => return e.to_string(),
+    };
+
+    let backend = JsBackend {
+        on_output,
+        on_input,
+    };
+    let backend = Box::new(backend);
+    if let Err(e) = rv32_sys.attach_uart(uart_base, backend) {
+        return e.to_string();
+    }
+
+    run(&rv32_sys, mem_dump_size)
+}
 
-    let output = get_output(&reg, &mem);
+#[cfg(test)]
+#[test]
+fn test_emulate() {
+    let rom_file = [
+        0x00500513_u32, // li a0, 5
+        0,
+    ];
+    let rom_file = rom_file
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect::<Vec<_>>();
 
-    output
+    let output = emulate(&rom_file, 16);
+    assert!(output.starts_with("Register Dump:"));
+    assert!(output.contains("x10: 0x00000005"));
 }
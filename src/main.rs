@@ -5,25 +5,34 @@ use std::{
     process::exit,
 };
 
-use bus::RAM_START;
-use register::{Register32, NUM_REGISTER};
-use rv_system::RV32System;
-
-mod bus;
-mod pipeline;
-mod register;
-mod rv_system;
-
-fn get_file_as_u32_vec(filename: &String) -> Vec<u32> {
-    let mut f = File::open(&filename).expect("File not found");
-    let metadata = fs::metadata(&filename)
-        .expect("unable to read metadata");
+use rv_emu_rs::{
+    branch_predictor,
+    bus::{
+        uart::{StdioBackend, UART_START},
+        AccessFaultPolicy, RAM_START,
+    },
+    elf::{ElfError, ElfFile},
+    gdb::{self, SessionEnd},
+    pipeline::memory_access::MisalignedPolicy,
+    register::{Register32, NUM_REGISTER},
+    rv_system::{
+        error::EmulatorError, ExecutionMode, HaltReason,
+        RV32System, RunSummary,
+    },
+};
+
+fn get_file(filename: &str) -> Vec<u8> {
+    let mut f = File::open(filename).expect("File not found");
+    let metadata =
+        fs::metadata(filename).expect("unable to read metadata");
     let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
+    f.read_exact(&mut buffer).expect("buffer overflow");
 
     buffer
-        .chunks(4)
-        .into_iter()
+}
+
+fn get_rom_file(file: &[u8]) -> Vec<u32> {
+    file.chunks(4)
         .map(|chunk| {
             u32::from_le_bytes(
                 chunk.try_into().expect("cast error"),
@@ -32,78 +41,287 @@ fn get_file_as_u32_vec(filename: &String) -> Vec<u32> {
         .collect()
 }
 
+// ELF files are loaded by segment, anything else is a raw
+// image placed in ROM
+fn load(file: &[u8]) -> Result<RV32System, ElfError> {
+    if ElfFile::is_elf(file) {
+        RV32System::from_elf(&ElfFile::parse(file)?)
+    } else {
+        Ok(RV32System::new(&get_rom_file(file)))
+    }
+}
+
+type RunResult = Result<RunSummary, EmulatorError>;
+
 fn run(
-    rom_file: &[u32],
-) -> ([Register32; NUM_REGISTER], Vec<u32>) {
-    let rv32_sys = RV32System::new(rom_file);
-    rv32_sys.run();
+    rv32_sys: &RV32System,
+) -> (RunResult, [Register32; NUM_REGISTER], Vec<u32>) {
+    let result = rv32_sys.run();
 
-    (rv32_sys.get_reg(), rv32_sys.get_mem(0x1010))
+    (result, rv32_sys.get_reg(), rv32_sys.get_mem(0x1010))
 }
 
 fn get_output(
     reg: &[Register32; NUM_REGISTER],
-    mem: &Vec<u32>,
+    mem: &[u32],
 ) -> String {
     let reg_out = reg
-        .into_iter()
-        .zip(0..reg.len())
-        .map(|(reg, i)| {
+        .iter()
+        .enumerate()
+        .map(|(i, reg)| {
             let mut out = format!("x{}: {:#010x}\t", i, reg.0);
             if (i + 1) % 4 == 0 {
-                out = out + "\n";
+                out += "\n";
             }
             out
         })
         .collect::<String>();
 
     let mem_out = mem
-        .into_iter()
-        .zip(0..mem.len())
-        .map(|(val, i)| {
+        .iter()
+        .enumerate()
+        .map(|(i, val)| {
             let mut out = format!("{:#010x} ", val);
             if i % 4 == 0 {
-                out = String::from(format!(
-                    "\n{:#010x}: ",
-                    RAM_START + i * 4
-                )) + &out;
+                out = format!("\n{:#010x}: ", RAM_START + i * 4)
+                    + &out;
             }
             out
         })
         .collect::<String>();
 
-    let output = String::from("Register Dump:\n\n")
+    String::from("Register Dump:\n\n")
         + &reg_out
-        + &"\nMemory Dump:\n"
-        + &mem_out;
+        + "\nMemory Dump:\n"
+        + &mem_out
+}
 
-    output
+// a symbol name or a hexadecimal address
+fn resolve_address(
+    rv32_sys: &RV32System,
+    target: &str,
+) -> Option<u32> {
+    match rv32_sys.get_symbols().find(target) {
+        Some(symbol) => Some(symbol.addr),
+        None => u32::from_str_radix(
+            target.trim_start_matches("0x"),
+            16,
+        )
+        .ok(),
+    }
+}
+
+fn usage(program: &str) -> ! {
+    println!(
+        "\nUsage: {} [--gdb <port|socket>] \
+         [--access-fault <trap|halt|zero>] \
+         [--misaligned <trap|emulate>] \
+         [--predictor \
+         <not-taken|btfn|bimodal|gshare|tournament>] \
+         [--mode <pipelined|functional|translated>] \
+         [--fast-forward <symbol|address>] \
+         [--time-base <instructions per tick>] \
+         [--halt-word] \
+         [--stats] [filename]\n",
+        program
+    );
+    exit(0);
 }
 
 pub fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("\nUsage: {} [filename]\n", args[0]);
-        exit(0);
+    let mut gdb_target = None;
+    let mut fault_policy = AccessFaultPolicy::Trap;
+    let mut misaligned_policy = MisalignedPolicy::Trap;
+    let mut predictor = None;
+    let mut show_stats = false;
+    let mut mode = ExecutionMode::Pipelined;
+    let mut fast_forward = None;
+    let mut time_base = 1;
+    let mut halt_word = false;
+    let mut file_path = None;
+
+    let mut options = args[1..].iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            // a TCP port or the path of a Unix socket
+            "--gdb" => match options.next() {
+                Some(target) => gdb_target = Some(target),
+                None => usage(&args[0]),
+            },
+            "--access-fault" => {
+                let policy = options.next().map(|s| s.as_str());
+                fault_policy = match policy {
+                    Some("trap") => AccessFaultPolicy::Trap,
+                    Some("halt") => AccessFaultPolicy::Halt,
+                    Some("zero") => AccessFaultPolicy::ReadAsZero,
+                    _ => usage(&args[0]),
+                }
+            }
+            "--misaligned" => {
+                let policy = options.next().map(|s| s.as_str());
+                misaligned_policy = match policy {
+                    Some("trap") => MisalignedPolicy::Trap,
+                    Some("emulate") => MisalignedPolicy::Emulate,
+                    _ => usage(&args[0]),
+                }
+            }
+            "--predictor" => {
+                let name = options.next().map(|s| s.as_str());
+                predictor =
+                    name.and_then(branch_predictor::from_name);
+                if predictor.is_none() {
+                    usage(&args[0]);
+                }
+            }
+            "--stats" => show_stats = true,
+            "--mode" => {
+                use ExecutionMode::*;
+                mode = match options.next().map(|s| s.as_str()) {
+                    Some("pipelined") => Pipelined,
+                    Some("functional") => Functional,
+                    Some("translated") => Translated,
+                    _ => usage(&args[0]),
+                }
+            }
+            // run functionally up to there
+            "--fast-forward" => match options.next() {
+                Some(target) => fast_forward = Some(target),
+                None => usage(&args[0]),
+            },
+            // of the CLINT's mtime
+            "--time-base" => {
+                let n = options.next().map(|s| s.parse());
+                time_base = match n {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => usage(&args[0]),
+                }
+            }
+            // legacy, the all-zero word halts with status 0
+            // instead of being an illegal instruction
+            "--halt-word" => halt_word = true,
+            _ if file_path.is_none() => file_path = Some(arg),
+            _ => usage(&args[0]),
+        }
     }
+    let Some(file_path) = file_path else {
+        usage(&args[0]);
+    };
 
-    let file_path = &args[1];
-    let rom_file = get_file_as_u32_vec(file_path);
+    let rv32_sys = match load(&get_file(file_path)) {
+        Ok(rv32_sys) => rv32_sys,
+        Err(e) => {
+            println!("{}: {}", file_path, e);
+            exit(1);
+        }
+    };
+    rv32_sys.set_fault_policy(fault_policy);
+    rv32_sys.set_misaligned_policy(misaligned_policy);
+    rv32_sys.set_time_base(time_base);
+    rv32_sys.set_halt_word(halt_word);
+    if let Some(predictor) = predictor {
+        rv32_sys.set_branch_predictor(predictor);
+    }
+    let backend = Box::new(StdioBackend::new());
+    if let Err(e) = rv32_sys.attach_uart(UART_START, backend) {
+        println!("UART: {}", e);
+        exit(1);
+    }
+
+    // the guest keeps running once GDB detaches
+    if let Some(target) = gdb_target {
+        match gdb::serve(&rv32_sys, target) {
+            Ok(SessionEnd::Detached) => {}
+            Ok(SessionEnd::Killed) => exit(0),
+            Err(e) => {
+                println!("GDB: {}", e);
+                exit(1);
+            }
+        }
+    }
 
-    let (reg, mem) = run(&rom_file);
+    if let Some(target) = fast_forward {
+        let Some(pc) = resolve_address(&rv32_sys, target) else {
+            println!("{}: no such symbol", target);
+            exit(1);
+        };
+        rv32_sys.set_execution_mode(ExecutionMode::Functional);
+        rv32_sys.run_until(pc);
+    }
+    rv32_sys.set_execution_mode(mode);
+
+    let (result, reg, mem) = run(&rv32_sys);
+
+    if let Err(e) = result {
+        println!("Halted: {}", e);
+    }
 
     let output = get_output(&reg, &mem);
 
     println!("{}", output);
+
+    if show_stats {
+        print_stats(&rv32_sys);
+    }
+
+    // the guest's exit code becomes ours
+    exit(match result.map(|summary| summary.reason) {
+        Ok(HaltReason::HaltWord) => 0,
+        Ok(HaltReason::Exit(code)) => code as i32,
+        Err(_) => 1,
+    });
+}
+
+fn print_stats(rv32_sys: &RV32System) {
+    let pipeline = rv32_sys.get_pipeline_stats();
+    let branch = rv32_sys.get_branch_stats();
+    let decode_cache = rv32_sys.get_decode_cache_stats();
+    let dbt = rv32_sys.get_dbt_stats();
+
+    println!(
+        "Cycles: {}, retired: {}, load-use stalls: {}, \
+         flushes: {}",
+        pipeline.cycles,
+        pipeline.retired,
+        pipeline.load_use_stalls,
+        pipeline.flushes
+    );
+    println!(
+        "Branches: {}, mispredicted: {}; indirect jumps: {}, \
+         mispredicted: {}",
+        branch.branches,
+        branch.branch_mispredictions,
+        branch.indirect_jumps,
+        branch.indirect_mispredictions
+    );
+    println!(
+        "Decode cache hits: {}, misses: {}, invalidations: {}",
+        decode_cache.hits,
+        decode_cache.misses,
+        decode_cache.invalidations
+    );
+    println!(
+        "Translated blocks: {}, executed: {}, chained: {}, \
+         invalidated: {}",
+        dbt.blocks_translated,
+        dbt.blocks_executed,
+        dbt.chained,
+        dbt.invalidations
+    );
 }
 
 #[cfg(test)]
 #[test]
 pub fn test() {
-    let file_path = "test_payloads/build/quicksort.bin".to_string();
-    let rom_file = get_file_as_u32_vec(&file_path);
+    let file_path = "test_payloads/build/quicksort.elf".to_string();
+    let rv32_sys =
+        load(&get_file(&file_path)).expect("load error");
 
-    let (reg, mem) = run(&rom_file);
+    let (_, reg, mem) = run(&rv32_sys);
+
+    if let Some(e) = rv32_sys.get_error() {
+        println!("Halted: {}", e);
+    }
 
     let output = get_output(&reg, &mem);
 
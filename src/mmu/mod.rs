@@ -0,0 +1,388 @@
+use std::{cell::Cell, sync::Arc};
+
+use self::tlb::{Tlb, TlbEntry, TlbStats};
+use crate::{
+    bus::{
+        bus_error::{AccessFault, BusError},
+        Bus,
+    },
+    csr::{
+        CsrFile, PrivilegeMode, MSTATUS_MPP, MSTATUS_MPRV,
+        MSTATUS_MXR, MSTATUS_SUM,
+    },
+    pipeline::memory_access::MemoryAccessWidth,
+    trap::{Exception, Trap},
+};
+
+pub mod tlb;
+
+pub const PAGE_SIZE: u64 = 4096;
+const PTE_SIZE: u64 = 4;
+const LEVELS: usize = 2;
+
+const SATP_MODE_SV32: u32 = 1 << 31;
+const SATP_ASID_SHIFT: u32 = 22;
+const SATP_ASID_MASK: u32 = 0x1ff;
+const SATP_PPN_MASK: u32 = 0x3f_ffff;
+
+pub const PTE_V: u32 = 1 << 0;
+pub const PTE_R: u32 = 1 << 1;
+pub const PTE_W: u32 = 1 << 2;
+pub const PTE_X: u32 = 1 << 3;
+pub const PTE_U: u32 = 1 << 4;
+pub const PTE_G: u32 = 1 << 5;
+pub const PTE_A: u32 = 1 << 6;
+pub const PTE_D: u32 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(&self, vaddr: u32) -> Trap {
+        let cause = match self {
+            AccessType::Fetch => Exception::InstructionPageFault,
+            AccessType::Load => Exception::LoadPageFault,
+            AccessType::Store => Exception::StorePageFault,
+        };
+        Trap::new(cause, vaddr)
+    }
+
+    fn access_fault(&self, vaddr: u32) -> Trap {
+        let cause = match self {
+            AccessType::Fetch => Exception::InstructionAccessFault,
+            AccessType::Load => Exception::LoadAccessFault,
+            AccessType::Store => Exception::StoreAccessFault,
+        };
+        Trap::new(cause, vaddr)
+    }
+
+    // what the bus would have reported, for a physical
+    // address it cannot take
+    fn unmapped(&self, paddr: u64) -> BusError {
+        let addr = paddr as usize;
+        let fault = AccessFault::Unmapped;
+        match self {
+            AccessType::Fetch => {
+                BusError::FetchAccessFault(addr, fault)
+            }
+            AccessType::Load => {
+                BusError::LoadAccessFault(addr, fault)
+            }
+            AccessType::Store => {
+                BusError::StoreAccessFault(addr, 0, fault)
+            }
+        }
+    }
+}
+
+// what a fetch translates through. A virtual pc maps to the
+// same physical one for as long as it does not change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchContext {
+    Bare,
+    Sv32 {
+        satp: u32,
+        privilege: PrivilegeMode,
+        // sfence.vma makes page table updates visible
+        generation: u64,
+    },
+}
+
+// Sv32 address translation, shared by the fetch and the
+// memory access stages
+pub struct Mmu {
+    bus: Arc<Bus>,
+    csr_file: Arc<CsrFile>,
+
+    tlb: Tlb,
+    // counts the flushes
+    generation: Cell<u64>,
+}
+
+impl Mmu {
+    pub fn new(bus: Arc<Bus>, csr_file: Arc<CsrFile>) -> Self {
+        Self {
+            bus,
+            csr_file,
+
+            tlb: Tlb::new(),
+            generation: Cell::new(0),
+        }
+    }
+
+    // loads and stores in M-mode use MPP when MPRV is set
+    fn effective_privilege(
+        &self,
+        access: AccessType,
+    ) -> PrivilegeMode {
+        let privilege = self.csr_file.privilege();
+        let mstatus = self.csr_file.mstatus();
+
+        if access != AccessType::Fetch
+            && privilege == PrivilegeMode::Machine
+            && mstatus & MSTATUS_MPRV != 0
+        {
+            match (mstatus & MSTATUS_MPP) >> 11 {
+                0b00 => PrivilegeMode::User,
+                0b01 => PrivilegeMode::Supervisor,
+                _ => PrivilegeMode::Machine,
+            }
+        } else {
+            privilege
+        }
+    }
+
+    pub fn translate(
+        &self,
+        vaddr: u32,
+        access: AccessType,
+    ) -> Result<u32, Trap> {
+        let satp = self.csr_file.satp();
+        let privilege = self.effective_privilege(access);
+
+        if satp & SATP_MODE_SV32 == 0
+            || privilege == PrivilegeMode::Machine
+        {
+            return Ok(vaddr);
+        }
+
+        let vpn = vaddr >> 12;
+        let asid = (satp >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+
+        let entry = match self.tlb.lookup(vpn, asid) {
+            // the dirty bit has to be set in memory by a walk
+            Some(entry)
+                if access != AccessType::Store
+                    || entry.flags & PTE_D != 0 =>
+            {
+                if !self.has_permission(entry.flags, privilege, access)
+                {
+                    return Err(access.page_fault(vaddr));
+                }
+                entry
+            }
+            _ => {
+                let entry =
+                    self.walk(vaddr, satp, privilege, access)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+
+        let ppn = if entry.is_megapage {
+            (entry.ppn & !0x3ff) | (vpn & 0x3ff)
+        } else {
+            entry.ppn
+        };
+        let paddr = ((ppn as u64) << 12) | (vaddr as u64 & 0xfff);
+
+        // Sv32 produces 34-bit physical addresses, but nothing
+        // is mapped above 4 GiB
+        u32::try_from(paddr).map_err(|_| {
+            let err = access.unmapped(paddr);
+            access
+                .access_fault(vaddr)
+                .with_fault(self.bus.log_fault(err))
+        })
+    }
+
+    // without a lookup, so the TLB stats are not touched
+    pub fn fetch_context(&self) -> FetchContext {
+        let satp = self.csr_file.satp();
+        let privilege = self.csr_file.privilege();
+        if satp & SATP_MODE_SV32 == 0
+            || privilege == PrivilegeMode::Machine
+        {
+            return FetchContext::Bare;
+        }
+
+        FetchContext::Sv32 {
+            satp,
+            privilege,
+            generation: self.generation.get(),
+        }
+    }
+
+    // for the debugger: no permission checks, no A/D updates
+    // and no TLB fill, None if the page is not mapped
+    pub fn debug_translate(&self, vaddr: u32) -> Option<u32> {
+        let satp = self.csr_file.satp();
+        if satp & SATP_MODE_SV32 == 0
+            || self.csr_file.privilege() == PrivilegeMode::Machine
+        {
+            return Some(vaddr);
+        }
+
+        let vpn = [(vaddr >> 12) & 0x3ff, (vaddr >> 22) & 0x3ff];
+        let mut table = (satp & SATP_PPN_MASK) as u64 * PAGE_SIZE;
+
+        for level in (0..LEVELS).rev() {
+            let pte_addr = table + vpn[level] as u64 * PTE_SIZE;
+            let pte = self
+                .bus
+                .read(
+                    usize::try_from(pte_addr).ok()?,
+                    MemoryAccessWidth::Word,
+                )
+                .ok()?;
+            if pte & PTE_V == 0 {
+                return None;
+            }
+
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = ppn as u64 * PAGE_SIZE;
+                continue;
+            }
+
+            // a megapage maps 4 MiB
+            let offset_mask: u64 =
+                if level == 1 { 0x3f_ffff } else { 0xfff };
+            let paddr = (((ppn as u64) << 12) & !offset_mask)
+                | (vaddr as u64 & offset_mask);
+            return u32::try_from(paddr).ok();
+        }
+
+        None
+    }
+
+    fn has_permission(
+        &self,
+        flags: u32,
+        privilege: PrivilegeMode,
+        access: AccessType,
+    ) -> bool {
+        let mstatus = self.csr_file.mstatus();
+        let is_user_page = flags & PTE_U != 0;
+
+        let privilege_ok = match privilege {
+            PrivilegeMode::User => is_user_page,
+            // S-mode never executes user pages and only
+            // touches their data when SUM is set
+            _ => {
+                !is_user_page
+                    || (access != AccessType::Fetch
+                        && mstatus & MSTATUS_SUM != 0)
+            }
+        };
+
+        let access_ok = match access {
+            AccessType::Fetch => flags & PTE_X != 0,
+            AccessType::Load => {
+                flags & PTE_R != 0
+                    || (flags & PTE_X != 0
+                        && mstatus & MSTATUS_MXR != 0)
+            }
+            AccessType::Store => flags & PTE_W != 0,
+        };
+
+        privilege_ok && access_ok
+    }
+
+    fn read_pte(
+        &self,
+        pte_addr: u64,
+        vaddr: u32,
+        access: AccessType,
+    ) -> Result<u32, Trap> {
+        let pte_addr = usize::try_from(pte_addr)
+            .ok()
+            .filter(|&addr| addr <= u32::MAX as usize)
+            .ok_or_else(|| {
+                let err = AccessType::Load.unmapped(pte_addr);
+                access
+                    .access_fault(vaddr)
+                    .with_fault(self.bus.log_fault(err))
+            })?;
+
+        self.bus
+            .read(pte_addr, MemoryAccessWidth::Word)
+            .map_err(|e| {
+                access
+                    .access_fault(vaddr)
+                    .with_fault(self.bus.log_fault(e))
+            })
+    }
+
+    fn walk(
+        &self,
+        vaddr: u32,
+        satp: u32,
+        privilege: PrivilegeMode,
+        access: AccessType,
+    ) -> Result<TlbEntry, Trap> {
+        let vpn = [(vaddr >> 12) & 0x3ff, (vaddr >> 22) & 0x3ff];
+        let mut table = (satp & SATP_PPN_MASK) as u64 * PAGE_SIZE;
+
+        for level in (0..LEVELS).rev() {
+            let pte_addr = table + vpn[level] as u64 * PTE_SIZE;
+            let pte = self.read_pte(pte_addr, vaddr, access)?;
+
+            if pte & PTE_V == 0
+                || (pte & PTE_R == 0 && pte & PTE_W != 0)
+            {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level of the page table
+                table = ppn as u64 * PAGE_SIZE;
+                continue;
+            }
+
+            let is_megapage = level == 1;
+            if is_megapage && ppn & 0x3ff != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            if !self.has_permission(pte, privilege, access) {
+                return Err(access.page_fault(vaddr));
+            }
+
+            // A and D are updated by the hardware instead of
+            // raising a page fault for software to handle
+            let mut new_pte = pte | PTE_A;
+            if access == AccessType::Store {
+                new_pte |= PTE_D;
+            }
+            if new_pte != pte {
+                self.bus
+                    .write(
+                        pte_addr as usize,
+                        new_pte,
+                        MemoryAccessWidth::Word,
+                    )
+                    .map_err(|e| {
+                        access
+                            .access_fault(vaddr)
+                            .with_fault(self.bus.log_fault(e))
+                    })?;
+            }
+
+            return Ok(TlbEntry {
+                vpn: vaddr >> 12,
+                asid: (satp >> SATP_ASID_SHIFT) & SATP_ASID_MASK,
+                ppn,
+                flags: new_pte & 0xff,
+                is_megapage,
+            });
+        }
+
+        // a pointer PTE at the last level
+        Err(access.page_fault(vaddr))
+    }
+
+    pub fn flush(&self, vaddr: Option<u32>, asid: Option<u32>) {
+        self.tlb.flush(vaddr, asid);
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    pub fn get_tlb_stats(&self) -> TlbStats {
+        self.tlb.get_stats()
+    }
+}
@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+
+pub const TLB_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlbEntry {
+    pub vpn: u32,
+    pub asid: u32,
+    // for megapages the low 10 bits are taken from the VPN
+    pub ppn: u32,
+    // the low 8 bits of the leaf PTE
+    pub flags: u32,
+    pub is_megapage: bool,
+}
+
+impl TlbEntry {
+    fn matches(&self, vpn: u32, asid: u32) -> bool {
+        let is_global = self.flags & super::PTE_G != 0;
+        let vpn_match = if self.is_megapage {
+            self.vpn >> 10 == vpn >> 10
+        } else {
+            self.vpn == vpn
+        };
+
+        vpn_match && (is_global || self.asid == asid)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlbStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+}
+
+// fully associative, replaced in round-robin order
+pub struct Tlb {
+    entries: RefCell<[Option<TlbEntry>; TLB_SIZE]>,
+    next_victim: RefCell<usize>,
+
+    stats: RefCell<TlbStats>,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new([None; TLB_SIZE]),
+            next_victim: RefCell::new(0),
+
+            stats: RefCell::new(TlbStats::default()),
+        }
+    }
+
+    pub fn lookup(&self, vpn: u32, asid: u32) -> Option<TlbEntry> {
+        let entry = self
+            .entries
+            .borrow()
+            .iter()
+            .flatten()
+            .find(|entry| entry.matches(vpn, asid))
+            .copied();
+
+        let mut stats = self.stats.borrow_mut();
+        if entry.is_some() {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+
+        entry
+    }
+
+    pub fn insert(&self, entry: TlbEntry) {
+        let mut entries = self.entries.borrow_mut();
+
+        // refill an existing translation in place, e.g. after
+        // its dirty bit has been set
+        let slot = entries
+            .iter()
+            .position(|e| {
+                e.is_some_and(|e| e.matches(entry.vpn, entry.asid))
+            })
+            .unwrap_or_else(|| {
+                let victim = *self.next_victim.borrow();
+                self.next_victim.replace((victim + 1) % TLB_SIZE);
+                victim
+            });
+
+        entries[slot] = Some(entry);
+    }
+
+    // sfence.vma: None stands for every address / every ASID,
+    // global mappings are kept when flushing a single ASID
+    pub fn flush(&self, vaddr: Option<u32>, asid: Option<u32>) {
+        for entry in self.entries.borrow_mut().iter_mut() {
+            let should_flush = entry.is_some_and(|e| {
+                let addr_match = vaddr.is_none_or(|vaddr| {
+                    e.matches(vaddr >> 12, e.asid)
+                });
+                let asid_match = asid.is_none_or(|asid| {
+                    e.flags & super::PTE_G == 0 && e.asid == asid
+                });
+                addr_match && asid_match
+            });
+
+            if should_flush {
+                *entry = None;
+            }
+        }
+
+        self.stats.borrow_mut().flushes += 1;
+    }
+
+    pub fn get_stats(&self) -> TlbStats {
+        self.stats.borrow().to_owned()
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let tlb = Tlb::new();
+    let entry = |vpn, asid, flags, is_megapage| TlbEntry {
+        vpn,
+        asid,
+        ppn: 0x100,
+        flags,
+        is_megapage,
+    };
+
+    tlb.insert(entry(0x12345, 1, 0, false));
+    tlb.insert(entry(0x40000, 1, super::PTE_G, true));
+
+    assert!(tlb.lookup(0x12345, 1).is_some());
+    assert!(tlb.lookup(0x12345, 2).is_none());
+    assert!(tlb.lookup(0x403ff, 2).is_some()); // global megapage
+
+    tlb.flush(None, Some(1));
+    assert!(tlb.lookup(0x12345, 1).is_none());
+    assert!(tlb.lookup(0x40000, 1).is_some());
+
+    tlb.flush(Some(0x4000_1000), None);
+    assert!(tlb.lookup(0x40000, 1).is_none());
+
+    for vpn in 0..(TLB_SIZE as u32 + 1) {
+        tlb.insert(entry(vpn, 0, 0, false));
+    }
+    assert!(tlb.lookup(0, 0).is_none()); // evicted
+    assert!(tlb.lookup(TLB_SIZE as u32, 0).is_some());
+
+    let stats = tlb.get_stats();
+    assert_eq!(stats.hits, 4);
+    assert_eq!(stats.misses, 4);
+    assert_eq!(stats.flushes, 2);
+}
@@ -0,0 +1,441 @@
+// RVC: every 16-bit instruction is expanded into the 32-bit
+// instruction it stands for, so the later stages never see
+// the compressed form. Reserved encodings expand to None.
+
+const OP_LOAD: u32 = 0b000_0011;
+const OP_LOAD_FP: u32 = 0b000_0111;
+const OP_STORE: u32 = 0b010_0011;
+const OP_STORE_FP: u32 = 0b010_0111;
+const OP_IMM: u32 = 0b001_0011;
+const OP: u32 = 0b011_0011;
+const OP_LUI: u32 = 0b011_0111;
+const OP_BRANCH: u32 = 0b110_0011;
+const OP_JALR: u32 = 0b110_0111;
+const OP_JAL: u32 = 0b110_1111;
+
+const EBREAK: u32 = 0x0010_0073;
+
+const REG_RA: u32 = 1;
+const REG_SP: u32 = 2;
+
+pub fn is_compressed(instruction: u32) -> bool {
+    instruction & 0b11 != 0b11
+}
+
+// takes bits [hi:lo] of the instruction and puts them at
+// bit `at` of the result
+fn bits(inst: u32, hi: u32, lo: u32, at: u32) -> u32 {
+    ((inst >> lo) & ((1 << (hi - lo + 1)) - 1)) << at
+}
+
+fn sign_extend(val: u32, width: u32) -> u32 {
+    ((val << (32 - width)) as i32 >> (32 - width)) as u32
+}
+
+// x8 - x15, encoded in 3 bits
+fn reg_prime(inst: u32, lo: u32) -> u32 {
+    8 + bits(inst, lo + 2, lo, 0)
+}
+
+fn r_type(
+    funct7: u32,
+    rs2: u32,
+    rs1: u32,
+    funct3: u32,
+    rd: u32,
+) -> u32 {
+    (funct7 << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (rd << 7)
+        | OP
+}
+
+fn i_type(
+    imm: u32,
+    rs1: u32,
+    funct3: u32,
+    rd: u32,
+    opcode: u32,
+) -> u32 {
+    ((imm & 0xfff) << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | (rd << 7)
+        | opcode
+}
+
+fn s_type(
+    imm: u32,
+    rs2: u32,
+    rs1: u32,
+    funct3: u32,
+    opcode: u32,
+) -> u32 {
+    bits(imm, 11, 5, 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | bits(imm, 4, 0, 7)
+        | opcode
+}
+
+fn b_type(
+    imm: u32,
+    rs2: u32,
+    rs1: u32,
+    funct3: u32,
+) -> u32 {
+    bits(imm, 12, 12, 31)
+        | bits(imm, 10, 5, 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | bits(imm, 4, 1, 8)
+        | bits(imm, 11, 11, 7)
+        | OP_BRANCH
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    bits(imm, 20, 20, 31)
+        | bits(imm, 10, 1, 21)
+        | bits(imm, 11, 11, 20)
+        | bits(imm, 19, 12, 12)
+        | (rd << 7)
+        | OP_JAL
+}
+
+fn cj_offset(inst: u32) -> u32 {
+    let offset = bits(inst, 12, 12, 11)
+        | bits(inst, 11, 11, 4)
+        | bits(inst, 10, 9, 8)
+        | bits(inst, 8, 8, 10)
+        | bits(inst, 7, 7, 6)
+        | bits(inst, 6, 6, 7)
+        | bits(inst, 5, 3, 1)
+        | bits(inst, 2, 2, 5);
+    sign_extend(offset, 12)
+}
+
+fn cb_offset(inst: u32) -> u32 {
+    let offset = bits(inst, 12, 12, 8)
+        | bits(inst, 11, 10, 3)
+        | bits(inst, 6, 5, 6)
+        | bits(inst, 4, 3, 1)
+        | bits(inst, 2, 2, 5);
+    sign_extend(offset, 9)
+}
+
+// the offsets of the word and double-word loads and stores,
+// scaled by 4 and 8
+fn cl_word_offset(inst: u32) -> u32 {
+    bits(inst, 12, 10, 3)
+        | bits(inst, 6, 6, 2)
+        | bits(inst, 5, 5, 6)
+}
+
+fn cl_double_offset(inst: u32) -> u32 {
+    bits(inst, 12, 10, 3) | bits(inst, 6, 5, 6)
+}
+
+// the 6-bit immediate of c.addi, c.li, c.andi, ...
+fn ci_imm(inst: u32) -> u32 {
+    sign_extend(
+        bits(inst, 12, 12, 5) | bits(inst, 6, 2, 0),
+        6,
+    )
+}
+
+pub fn expand(inst: u32) -> Option<u32> {
+    let inst = inst & 0xffff;
+    let funct3 = bits(inst, 15, 13, 0);
+    let rd = bits(inst, 11, 7, 0);
+    let rs2 = bits(inst, 6, 2, 0);
+
+    match (inst & 0b11, funct3) {
+        // c.addi4spn
+        (0b00, 0b000) => {
+            let imm = bits(inst, 12, 11, 4)
+                | bits(inst, 10, 7, 6)
+                | bits(inst, 6, 6, 2)
+                | bits(inst, 5, 5, 3);
+            (imm != 0).then(|| {
+                i_type(
+                    imm,
+                    REG_SP,
+                    0b000,
+                    reg_prime(inst, 2),
+                    OP_IMM,
+                )
+            })
+        }
+        // c.fld
+        (0b00, 0b001) => Some(i_type(
+            cl_double_offset(inst),
+            reg_prime(inst, 7),
+            0b011,
+            reg_prime(inst, 2),
+            OP_LOAD_FP,
+        )),
+        // c.lw
+        (0b00, 0b010) => Some(i_type(
+            cl_word_offset(inst),
+            reg_prime(inst, 7),
+            0b010,
+            reg_prime(inst, 2),
+            OP_LOAD,
+        )),
+        // c.flw
+        (0b00, 0b011) => Some(i_type(
+            cl_word_offset(inst),
+            reg_prime(inst, 7),
+            0b010,
+            reg_prime(inst, 2),
+            OP_LOAD_FP,
+        )),
+        // c.fsd
+        (0b00, 0b101) => Some(s_type(
+            cl_double_offset(inst),
+            reg_prime(inst, 2),
+            reg_prime(inst, 7),
+            0b011,
+            OP_STORE_FP,
+        )),
+        // c.sw
+        (0b00, 0b110) => Some(s_type(
+            cl_word_offset(inst),
+            reg_prime(inst, 2),
+            reg_prime(inst, 7),
+            0b010,
+            OP_STORE,
+        )),
+        // c.fsw
+        (0b00, 0b111) => Some(s_type(
+            cl_word_offset(inst),
+            reg_prime(inst, 2),
+            reg_prime(inst, 7),
+            0b010,
+            OP_STORE_FP,
+        )),
+        // c.addi, c.nop
+        (0b01, 0b000) => Some(i_type(
+            ci_imm(inst),
+            rd,
+            0b000,
+            rd,
+            OP_IMM,
+        )),
+        // c.jal, RV32 only
+        (0b01, 0b001) => {
+            Some(j_type(cj_offset(inst), REG_RA))
+        }
+        // c.li
+        (0b01, 0b010) => {
+            Some(i_type(ci_imm(inst), 0, 0b000, rd, OP_IMM))
+        }
+        // c.addi16sp
+        (0b01, 0b011) if rd == REG_SP => {
+            let imm = bits(inst, 12, 12, 9)
+                | bits(inst, 6, 6, 4)
+                | bits(inst, 5, 5, 6)
+                | bits(inst, 4, 3, 7)
+                | bits(inst, 2, 2, 5);
+            (imm != 0).then(|| {
+                i_type(
+                    sign_extend(imm, 10),
+                    REG_SP,
+                    0b000,
+                    REG_SP,
+                    OP_IMM,
+                )
+            })
+        }
+        // c.lui
+        (0b01, 0b011) => {
+            let imm = ci_imm(inst);
+            (imm != 0)
+                .then_some((imm << 12) | (rd << 7) | OP_LUI)
+        }
+        (0b01, 0b100) => {
+            let rd = reg_prime(inst, 7);
+            let rs2 = reg_prime(inst, 2);
+            let shamt = bits(inst, 6, 2, 0);
+            let shamt_5 = bits(inst, 12, 12, 0);
+
+            match bits(inst, 11, 10, 0) {
+                // c.srli, shamt[5] must be clear on RV32
+                0b00 => (shamt_5 == 0).then(|| {
+                    i_type(shamt, rd, 0b101, rd, OP_IMM)
+                }),
+                // c.srai
+                0b01 => (shamt_5 == 0).then(|| {
+                    i_type(
+                        (0b010_0000 << 5) | shamt,
+                        rd,
+                        0b101,
+                        rd,
+                        OP_IMM,
+                    )
+                }),
+                // c.andi
+                0b10 => Some(i_type(
+                    ci_imm(inst),
+                    rd,
+                    0b111,
+                    rd,
+                    OP_IMM,
+                )),
+                // c.subw and c.addw are RV64 only
+                _ if shamt_5 != 0 => None,
+                _ => match bits(inst, 6, 5, 0) {
+                    0b00 => Some(r_type(
+                        0b010_0000, rs2, rd, 0b000, rd,
+                    )),
+                    0b01 => {
+                        Some(r_type(0, rs2, rd, 0b100, rd))
+                    }
+                    0b10 => {
+                        Some(r_type(0, rs2, rd, 0b110, rd))
+                    }
+                    _ => {
+                        Some(r_type(0, rs2, rd, 0b111, rd))
+                    }
+                },
+            }
+        }
+        // c.j
+        (0b01, 0b101) => Some(j_type(cj_offset(inst), 0)),
+        // c.beqz, c.bnez
+        (0b01, 0b110) | (0b01, 0b111) => Some(b_type(
+            cb_offset(inst),
+            0,
+            reg_prime(inst, 7),
+            funct3 & 0b001,
+        )),
+        // c.slli, shamt[5] must be clear on RV32
+        (0b10, 0b000) => (bits(inst, 12, 12, 0) == 0)
+            .then(|| i_type(rs2, rd, 0b001, rd, OP_IMM)),
+        // c.fldsp
+        (0b10, 0b001) => {
+            let imm = bits(inst, 12, 12, 5)
+                | bits(inst, 6, 5, 3)
+                | bits(inst, 4, 2, 6);
+            Some(i_type(imm, REG_SP, 0b011, rd, OP_LOAD_FP))
+        }
+        // c.lwsp
+        (0b10, 0b010) => {
+            let imm = bits(inst, 12, 12, 5)
+                | bits(inst, 6, 4, 2)
+                | bits(inst, 3, 2, 6);
+            (rd != 0).then(|| {
+                i_type(imm, REG_SP, 0b010, rd, OP_LOAD)
+            })
+        }
+        // c.flwsp, f0 is a valid destination
+        (0b10, 0b011) => {
+            let imm = bits(inst, 12, 12, 5)
+                | bits(inst, 6, 4, 2)
+                | bits(inst, 3, 2, 6);
+            Some(i_type(imm, REG_SP, 0b010, rd, OP_LOAD_FP))
+        }
+        (0b10, 0b100) => {
+            let is_add = bits(inst, 12, 12, 0) == 1;
+            match (is_add, rd, rs2) {
+                // c.jr
+                (false, 0, 0) => None,
+                (false, rs1, 0) => {
+                    Some(i_type(0, rs1, 0b000, 0, OP_JALR))
+                }
+                // c.mv
+                (false, rd, rs2) => {
+                    Some(r_type(0, rs2, 0, 0b000, rd))
+                }
+                (true, 0, 0) => Some(EBREAK),
+                // c.jalr
+                (true, rs1, 0) => Some(i_type(
+                    0, rs1, 0b000, REG_RA, OP_JALR,
+                )),
+                // c.add
+                (true, rd, rs2) => {
+                    Some(r_type(0, rs2, rd, 0b000, rd))
+                }
+            }
+        }
+        // c.fsdsp
+        (0b10, 0b101) => {
+            let imm =
+                bits(inst, 12, 10, 3) | bits(inst, 9, 7, 6);
+            Some(s_type(imm, rs2, REG_SP, 0b011, OP_STORE_FP))
+        }
+        // c.swsp
+        (0b10, 0b110) => {
+            let imm =
+                bits(inst, 12, 9, 2) | bits(inst, 8, 7, 6);
+            Some(s_type(imm, rs2, REG_SP, 0b010, OP_STORE))
+        }
+        // c.fswsp
+        (0b10, 0b111) => {
+            let imm =
+                bits(inst, 12, 9, 2) | bits(inst, 8, 7, 6);
+            Some(s_type(imm, rs2, REG_SP, 0b010, OP_STORE_FP))
+        }
+        // the reserved encodings
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let cases = [
+        (0x0808_u32, 0x01010513_u32), // addi a0, sp, 16
+        (0x424c, 0x00462583),         // lw a1, 4(a2)
+        (0xc24c, 0x00b62223),         // sw a1, 4(a2)
+        (0x0001, 0x00000013),         // nop
+        (0x157d, 0xfff50513),         // addi a0, a0, -1
+        (0x3ff5, 0xffdff0ef),         // jal -4
+        (0x5579, 0xffe00513),         // li a0, -2
+        (0x7139, 0xfc010113),         // addi sp, sp, -64
+        (0x6505, 0x00001537),         // lui a0, 1
+        (0x757d, 0xfffff537),         // lui a0, 0xfffff
+        (0x8105, 0x00155513),         // srli a0, a0, 1
+        (0x8505, 0x40155513),         // srai a0, a0, 1
+        (0x897d, 0x01f57513),         // andi a0, a0, 31
+        (0x8d0d, 0x40b50533),         // sub a0, a0, a1
+        (0x8d2d, 0x00b54533),         // xor a0, a0, a1
+        (0x8d4d, 0x00b56533),         // or a0, a0, a1
+        (0x8d6d, 0x00b57533),         // and a0, a0, a1
+        (0xbff5, 0xffdff06f),         // j -4
+        (0xc501, 0x00050463),         // beqz a0, 8
+        (0xfd6d, 0xfe051de3),         // bnez a0, -6
+        (0x050e, 0x00351513),         // slli a0, a0, 3
+        (0x4532, 0x00c12503),         // lw a0, 12(sp)
+        (0x8502, 0x00050067),         // jr a0
+        (0x852e, 0x00b00533),         // mv a0, a1
+        (0x9002, 0x00100073),         // ebreak
+        (0x9502, 0x000500e7),         // jalr a0
+        (0x952e, 0x00b50533),         // add a0, a0, a1
+        (0xc62a, 0x00a12623),         // sw a0, 12(sp)
+        (0x2608, 0x00863507),         // fld fa0, 8(a2)
+        (0xaa0c, 0x00b63827),         // fsd fa1, 16(a2)
+        (0x6248, 0x00462507),         // flw fa0, 4(a2)
+        (0xe60c, 0x00b62427),         // fsw fa1, 8(a2)
+        (0x2562, 0x01813507),         // fld fa0, 24(sp)
+        (0x6532, 0x00c12507),         // flw fa0, 12(sp)
+        (0xac2a, 0x00a13c27),         // fsd fa0, 24(sp)
+        (0xe62a, 0x00a12627),         // fsw fa0, 12(sp)
+    ];
+
+    for (compressed, expanded) in cases {
+        assert!(is_compressed(compressed));
+        assert_eq!(expand(compressed), Some(expanded));
+    }
+
+    assert!(!is_compressed(0x00000013));
+    assert_eq!(expand(0x0000), None); // illegal
+    assert_eq!(expand(0x6101), None); // c.addi16sp 0
+    assert_eq!(expand(0x4002), None); // c.lwsp x0
+    assert_eq!(expand(0x8002), None); // c.jr x0
+    assert_eq!(expand(0x1502), None); // c.slli shamt[5]
+}
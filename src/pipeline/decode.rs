@@ -1,26 +1,75 @@
 use super::{
-    instruction_fetch::InstructionFetchValues, PipelineStage,
-    Stage,
+    compressed, hazard,
+    instruction_fetch::InstructionFetchValues,
+    memory_access::AmoOperation, PipelineStage,
 };
-use crate::register::RegFile;
-use std::{cell::RefCell, sync::Arc};
+use crate::{
+    fpu,
+    register::{FRegFile, RegFile},
+    trap::{Exception, Trap},
+};
+use std::{
+    cell::{Cell, RefCell},
+    sync::Arc,
+};
+
+const ECALL: u32 = 0x0000_0073;
+const EBREAK: u32 = 0x0010_0073;
+const SRET: u32 = 0x1020_0073;
+const MRET: u32 = 0x3020_0073;
+const WFI: u32 = 0x1050_0073;
+
+// the offsets of jal and of the conditional branches
+fn j_imm(instruction: u32) -> i32 {
+    let j_imm_20 = (instruction & (0x1 << 31)) >> 31;
+    let j_imm_10_1 = (instruction & (0x3ff << 21)) >> 21;
+    let j_imm_11 = (instruction & (0x1 << 20)) >> 20;
+    let j_imm_19_12 = (instruction & (0xff << 12)) >> 12;
+    (((j_imm_20 << 20)
+        | (j_imm_19_12 << 12)
+        | (j_imm_11 << 11)
+        | (j_imm_10_1 << 1))
+        << 11) as i32
+        >> 11
+}
+
+fn b_imm(instruction: u32) -> i32 {
+    let b_imm_12 = (instruction & (0x1 << 31)) >> 31;
+    let b_imm_10_5 = (instruction & (0x3f << 25)) >> 25;
+    let b_imm_4_1 = (instruction & (0xf << 8)) >> 8;
+    let b_imm_11 = (instruction & (0x1 << 7)) >> 7;
+    (((b_imm_12 << 12)
+        | (b_imm_11 << 11)
+        | (b_imm_10_5 << 5)
+        | (b_imm_4_1 << 1))
+        << 19) as i32
+        >> 19
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct DecodedValues {
+    pub is_valid: bool,
     pub instruction: u32,
     pub opcode: u32,
     pub rd: u32,
     pub funct3: u32,
     pub rs1: u32,
     pub rs2: u32,
+    pub rs1_addr: u32,
+    pub rs2_addr: u32,
+    pub frs1: u64,
+    pub frs2: u64,
+    pub frs3: u64,
     pub imm11_0: u32,
     pub funct7: u32,
     pub shamt: u32,
 
     pub pc: u32,
     pub pc_plus_four: u32,
+    pub predicted_pc: u32,
 
     pub is_alu_operation: bool,
+    pub is_mul_div: bool,
     pub is_store: bool,
     pub is_load: bool,
     pub is_lui: bool,
@@ -28,27 +77,54 @@ pub struct DecodedValues {
     pub is_jal: bool,
     pub is_jalr: bool,
     pub is_branch: bool,
+    pub is_csr: bool,
+    pub is_ecall: bool,
+    pub is_mret: bool,
+    pub is_sret: bool,
+    pub is_sfence_vma: bool,
+    pub is_fence_i: bool,
+    pub is_ebreak: bool,
+    pub is_amo: bool,
+    pub is_aq: bool,
+    pub is_rl: bool,
+    pub is_fp: bool,
+    pub is_fp_load: bool,
+    pub is_fp_store: bool,
+    pub is_fp_to_int: bool,
+    pub writes_fp_reg: bool,
+    // the all-zero word, which stops the system
+    pub is_halt: bool,
 
     pub imm32: i32,
+    pub trap: Option<Trap>,
 }
 
 impl DecodedValues {
-    fn new() -> Self {
+    // a bubble
+    pub fn new() -> Self {
         Self {
+            is_valid: false,
             instruction: 0,
             opcode: 0,
             rd: 0,
             funct3: 0,
             rs1: 0,
             rs2: 0,
+            rs1_addr: 0,
+            rs2_addr: 0,
+            frs1: 0,
+            frs2: 0,
+            frs3: 0,
             imm11_0: 0,
             funct7: 0,
             shamt: 0,
 
             pc: 0,
             pc_plus_four: 0,
+            predicted_pc: 0,
 
             is_alu_operation: false,
+            is_mul_div: false,
             is_store: false,
             is_load: false,
             is_lui: false,
@@ -56,16 +132,200 @@ impl DecodedValues {
             is_jal: false,
             is_jalr: false,
             is_branch: false,
+            is_csr: false,
+            is_ecall: false,
+            is_mret: false,
+            is_sret: false,
+            is_sfence_vma: false,
+            is_fence_i: false,
+            is_ebreak: false,
+            is_amo: false,
+            is_aq: false,
+            is_rl: false,
+            is_fp: false,
+            is_fp_load: false,
+            is_fp_store: false,
+            is_fp_to_int: false,
+            writes_fp_reg: false,
+            is_halt: false,
 
             imm32: 0,
+            trap: None,
         }
     }
 }
 
-pub struct Decode {
-    stage: Arc<RefCell<Stage>>,
+impl Default for DecodedValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the fields and kind of a raw, possibly compressed,
+// instruction. They only depend on its bits, so fetch can
+// keep them in the decode cache
+pub fn decode_instruction(raw: u32) -> DecodedValues {
+    // compressed instructions are decoded as their 32-bit
+    // counterparts, an invalid one expands to 0
+    let instruction = if compressed::is_compressed(raw) {
+        compressed::expand(raw).unwrap_or(0)
+    } else {
+        raw
+    };
+
+    let mut val = DecodedValues::new();
+    val.is_valid = true;
+    val.instruction = instruction;
+    val.opcode = instruction & 0x7f;
+    val.rd = (instruction >> 7) & 0x1f;
+    val.funct3 = (instruction >> 12) & 0x7;
+    val.imm11_0 = (instruction >> 20) & 0xfff;
+    val.funct7 = (instruction >> 25) & 0x7f;
+    let rs1_addr = ((instruction >> 15) & 0x1f) as usize;
+    let rs2_addr = ((instruction >> 20) & 0x1f) as usize;
+    val.shamt = rs2_addr as u32;
+    val.rs1_addr = rs1_addr as u32;
+    val.rs2_addr = rs2_addr as u32;
+
+    // funct7, imm[11:5] for the immediate shifts, only
+    // selects sub and sra, or RV32M
+    let is_op = val.opcode == 0b011_0011;
+    let is_op_imm = val.opcode == 0b001_0011;
+    let is_shift_imm = is_op_imm && val.funct3 & 0b11 == 0b01;
+    val.is_alu_operation = (is_op_imm && !is_shift_imm)
+        || ((is_op || is_shift_imm)
+            && match (val.funct7, val.funct3) {
+                (0b000_0000, _) => true,
+                (0b010_0000, 0b000 | 0b101) => true,
+                (0b000_0001, _) => is_op,
+                _ => false,
+            });
+    val.is_mul_div = is_op && val.funct7 == 0b000_0001;
+    // RV32I has no lwu, nor any 64-bit access
+    val.is_store =
+        val.opcode == 0b010_0011 && val.funct3 < 0b011;
+    val.is_lui = val.opcode == 0b011_0111;
+    val.is_auipc = val.opcode == 0b001_0111;
+    val.is_load = val.opcode == 0b000_0011
+        && !matches!(val.funct3, 0b011 | 0b110 | 0b111);
+    val.is_jal = val.opcode == 0b110_1111;
+    val.is_jalr = val.opcode == 0b110_0111 && val.funct3 == 0;
+    val.is_branch = val.opcode == 0b110_0011
+        && !matches!(val.funct3, 0b010 | 0b011);
+    val.is_csr = val.opcode == 0b111_0011
+        && val.funct3 & 0b11 != 0;
+    val.is_ecall = instruction == ECALL;
+    val.is_mret = instruction == MRET;
+    val.is_sret = instruction == SRET;
+    val.is_sfence_vma = val.opcode == 0b111_0011
+        && val.funct3 == 0
+        && val.funct7 == 0b000_1001
+        && val.rd == 0;
+    // RV32A, only the word-sized operations exist and
+    // LR.W has no rs2
+    val.is_amo = val.opcode == 0b010_1111
+        && val.funct3 == 0b010
+        && match AmoOperation::try_from(val.funct7 >> 2) {
+            Ok(AmoOperation::LR) => rs2_addr == 0,
+            Ok(_) => true,
+            Err(_) => false,
+        };
+    val.is_aq = val.is_amo && val.funct7 & 0b10 != 0;
+    val.is_rl = val.is_amo && val.funct7 & 0b01 != 0;
+    // F and D, flw/fsw and fld/fsd are the only widths
+    val.is_fp_load = val.opcode == fpu::OPCODE_LOAD_FP
+        && matches!(val.funct3, 0b010 | 0b011);
+    val.is_fp_store = val.opcode == fpu::OPCODE_STORE_FP
+        && matches!(val.funct3, 0b010 | 0b011);
+    val.is_fp = fpu::is_valid(instruction);
+    val.is_fp_to_int =
+        val.is_fp && fpu::writes_int_reg(instruction);
+    val.writes_fp_reg = val.is_fp_load
+        || (val.is_fp && !val.is_fp_to_int);
+    val.is_ebreak = instruction == EBREAK;
+    // the unused fields of the fences are ignored, as the
+    // spec asks for forward compatibility
+    let is_fence =
+        val.opcode == 0b000_1111 && val.funct3 < 0b010;
+    val.is_fence_i = is_fence && val.funct3 == 0b001;
+    val.is_halt = raw == 0;
 
+    let u_imm = (instruction >> 12 << 12) as i32;
+    let s_imm = (((((instruction >> 25) & 0x7f) << 5)
+        | ((instruction >> 7) & 0x1f))
+        << 20) as i32
+        >> 20;
+    let i_imm = (val.imm11_0 << 20) as i32 >> 20; // signed extended
+
+    let j_imm = j_imm(instruction);
+    let b_imm = b_imm(instruction);
+
+    val.imm32 = if val.is_store || val.is_fp_store {
+        s_imm
+    } else if val.is_lui || val.is_auipc {
+        u_imm
+    } else if val.is_alu_operation
+        || val.is_load
+        || val.is_fp_load
+        || val.is_jalr
+    {
+        i_imm
+    } else if val.is_jal {
+        j_imm
+    } else if val.is_branch {
+        b_imm
+    } else if val.is_csr {
+        val.imm11_0 as i32 // CSR address, not sign extended
+    } else {
+        0_i32
+    };
+
+    let is_implemented = val.is_alu_operation
+        || val.is_store
+        || val.is_load
+        || val.is_lui
+        || val.is_auipc
+        || val.is_jal
+        || val.is_jalr
+        || val.is_branch
+        || val.is_csr
+        || val.is_ecall
+        || val.is_mret
+        || val.is_sret
+        || val.is_sfence_vma
+        || val.is_amo
+        || val.is_fp
+        || val.is_fp_load
+        || val.is_fp_store
+        || val.is_ebreak
+        || is_fence // executed as a no-op
+        // interrupts are checked before every instruction
+        // anyway, so waiting for one is a no-op as well
+        || instruction == WFI
+        || val.is_halt;
+
+    // any other encoding is illegal, with its bits as mtval.
+    // An ebreak traps with its pc, which Decode fills in
+    if !is_implemented {
+        val.trap = Some(Trap::new(
+            Exception::IllegalInstruction,
+            raw,
+        ));
+    }
+
+    val
+}
+
+pub struct Decode {
     reg_file: Arc<RegFile>,
+    freg_file: Arc<FRegFile>,
+
+    // set while a load-use hazard holds the instruction in
+    // this stage, fetching has to wait as well
+    stalled: Cell<bool>,
+    // whether the all-zero word halts the system, otherwise
+    // it is the illegal instruction the spec defines it as
+    halt_word: Cell<bool>,
 
     de_val: RefCell<DecodedValues>,
     de_val_ready: RefCell<DecodedValues>,
@@ -73,45 +333,49 @@ pub struct Decode {
 
 impl Decode {
     pub fn new(
-        stage: Arc<RefCell<Stage>>,
         reg_file: Arc<RegFile>,
+        freg_file: Arc<FRegFile>,
     ) -> Self {
         Self {
-            stage,
-
             reg_file,
+            freg_file,
+
+            stalled: Cell::new(false),
+            halt_word: Cell::new(false),
 
             de_val: RefCell::new(DecodedValues::new()),
             de_val_ready: RefCell::new(DecodedValues::new()),
         }
     }
-}
 
-impl PipelineStage<InstructionFetchValues, DecodedValues>
-    for Decode
-{
-    fn compute(&self, values: InstructionFetchValues) {
-        if self.should_stall() {
-            return;
-        }
+    pub fn set_halt_word(&self, enabled: bool) {
+        self.halt_word.set(enabled);
+    }
 
-        let if_val = values;
-        let instruction = if_val.instruction;
+    pub fn is_stalled(&self) -> bool {
+        self.stalled.get()
+    }
 
-        let mut val = self.de_val.borrow_mut();
-        val.instruction = instruction;
-        val.opcode = instruction & 0x7f;
-        val.rd = (instruction >> 7) & 0x1f;
-        val.funct3 = (instruction >> 12) & 0x7;
-        val.imm11_0 = (instruction >> 20) & 0xfff;
-        val.funct7 = (instruction >> 25) & 0x7f;
-        let rs1_addr = ((instruction >> 15) & 0x1f) as usize;
-        let rs2_addr = ((instruction >> 20) & 0x1f) as usize;
-        val.shamt = rs2_addr as u32;
+    // the operands of a fetched instruction, the register
+    // values may still have to be forwarded. Also used on its
+    // own by the functional model
+    pub fn decode(
+        &self,
+        if_val: InstructionFetchValues,
+    ) -> DecodedValues {
+        if !if_val.is_valid {
+            return DecodedValues::new();
+        }
 
+        // fetch already decoded the fields, they may come from
+        // the decode cache
+        let mut val = if_val.decoded;
         val.pc = if_val.pc;
         val.pc_plus_four = if_val.pc_plus_four;
+        val.predicted_pc = if_val.predicted_pc;
 
+        let rs1_addr = val.rs1_addr as usize;
+        let rs2_addr = val.rs2_addr as usize;
         val.rs1 = if rs1_addr == 0 {
             0
         } else {
@@ -123,72 +387,51 @@ impl PipelineStage<InstructionFetchValues, DecodedValues>
             self.reg_file.borrow()[rs2_addr].0
         };
 
-        val.is_alu_operation =
-            val.opcode & 0b101_1111 == 0b001_0011;
-        val.is_store = val.opcode == 0b010_0011;
-        val.is_lui = val.opcode == 0b011_0111;
-        val.is_auipc = val.opcode == 0b001_0111;
-        val.is_load = val.opcode == 0b000_0011;
-        val.is_jal = val.opcode == 0b110_1111;
-        val.is_jalr = val.opcode == 0b110_0111;
-        val.is_branch = val.opcode == 0b110_0011;
-
-        let u_imm = (instruction >> 12 << 12) as i32;
-        let s_imm = (((((instruction >> 25) & 0x7f) << 5)
-            | ((instruction >> 7) & 0x1f))
-            << 20) as i32
-            >> 20;
-        let i_imm = (val.imm11_0 << 20) as i32 >> 20; // signed extended
-
-        let j_imm_20 = (instruction & (0x1 << 31)) >> 31;
-        let j_imm_10_1 = (instruction & (0x3ff << 21)) >> 21;
-        let j_imm_11 = (instruction & (0x1 << 20)) >> 20;
-        let j_imm_19_12 = (instruction & (0xff << 12)) >> 12;
-        let j_imm = (((j_imm_20 << 20)
-            | (j_imm_19_12 << 12)
-            | (j_imm_11 << 11)
-            | (j_imm_10_1 << 1))
-            << 11) as i32
-            >> 11;
-
-        let b_imm_12 = (instruction & (0x1 << 31)) >> 31;
-        let b_imm_10_5 = (instruction & (0x3f << 25)) >> 25;
-        let b_imm_4_1 = (instruction & (0xf << 8)) >> 8;
-        let b_imm_11 = (instruction & (0x1 << 7)) >> 7;
-        let b_imm = (((b_imm_12 << 12)
-            | (b_imm_11 << 11)
-            | (b_imm_10_5 << 5)
-            | (b_imm_4_1 << 1))
-            << 19) as i32
-            >> 19;
-
-        val.imm32 = if val.is_store {
-            s_imm
-        } else if val.is_lui || val.is_auipc {
-            u_imm
-        } else if val.is_alu_operation
-            || val.is_load
-            || val.is_jalr
-        {
-            i_imm
-        } else if val.is_jal {
-            j_imm
-        } else if val.is_branch {
-            b_imm
-        } else {
-            if val.instruction != 0 {
-                println!(
-                    "Error: not implemented! opcode = {:#09b}, \
-                    instruction = {:#010x}\n",
-                    &val.opcode, &val.instruction
-                ); // should add error handling logic
-            }
-            0_i32
-        };
+        let freg_file = self.freg_file.borrow();
+        val.frs1 = freg_file[rs1_addr].0;
+        val.frs2 = freg_file[rs2_addr].0;
+        val.frs3 = freg_file[(val.instruction >> 27) as usize].0;
+
+        // a fault during fetch takes precedence
+        if if_val.trap.is_some() {
+            val.trap = if_val.trap;
+            val.is_halt = false;
+        } else if val.is_ebreak {
+            let breakpoint = Exception::Breakpoint;
+            val.trap = Some(Trap::new(breakpoint, val.pc));
+        } else if val.is_halt && !self.halt_word.get() {
+            let illegal = Exception::IllegalInstruction;
+            val.trap = Some(Trap::new(illegal, 0));
+            val.is_halt = false;
+        }
+
+        val
     }
 
-    fn should_stall(&self) -> bool {
-        !matches!(self.stage.borrow().to_owned(), Stage::DE)
+    // squashes the instruction being decoded
+    pub fn flush(&self) {
+        self.stalled.set(false);
+        self.de_val.replace(DecodedValues::new());
+    }
+}
+
+impl PipelineStage<InstructionFetchValues, DecodedValues>
+    for Decode
+{
+    fn compute(&self, values: InstructionFetchValues) {
+        self.stalled.set(false);
+        let mut val = self.decode(values);
+
+        // the instruction now in Execute has no result before
+        // the end of Memory Access, this one waits a cycle and
+        // Execute gets a bubble
+        let exe_val = self.de_val_ready.borrow();
+        if hazard::is_load_use(&exe_val, &val) {
+            self.stalled.set(true);
+            val = DecodedValues::new();
+        }
+
+        self.de_val.replace(val);
     }
 
     fn get_values_out(&self) -> DecodedValues {
@@ -200,3 +443,51 @@ impl PipelineStage<InstructionFetchValues, DecodedValues>
             .replace(self.de_val.borrow().to_owned());
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_illegal_instruction() {
+    let valid = [
+        0x003100b3_u32, // add x1, x2, x3
+        0x403100b3,     // sub x1, x2, x3
+        0x403150b3,     // sra x1, x2, x3
+        0x023100b3,     // mul x1, x2, x3
+        0x40315093,     // srai x1, x2, 3
+        0xfff14093,     // xori x1, x2, -1
+        0x00014083,     // lbu x1, 0(x2)
+        0x0ff0000f,     // fence
+        0x0000100f,     // fence.i
+        0x12000073,     // sfence.vma
+    ];
+    for raw in valid {
+        let val = decode_instruction(raw);
+        assert_eq!(val.trap, None, "{:#010x}", raw);
+    }
+
+    let illegal = [
+        0x203100b3_u32, // add, funct7 0010000
+        0x403140b3,     // xor, funct7 0100000
+        0x02011093,     // slli, shamt[5]
+        0x40011093,     // slli, funct7 0100000
+        0x00016083,     // lwu
+        0x00013083,     // ld
+        0x00313023,     // sd
+        0x000110e7,     // jalr, funct3 001
+        0x00002063,     // branch, funct3 010
+        0x0000200f,     // fence, funct3 010
+        0x00004073,     // system, funct3 100
+        0x000000f3,     // ecall, rd x1
+        0x120000f3,     // sfence.vma, rd x1
+        0x6101,         // c.addi16sp 0
+    ];
+    for raw in illegal {
+        let trap = decode_instruction(raw).trap;
+        let illegal = Exception::IllegalInstruction;
+        assert_eq!(
+            trap,
+            Some(Trap::new(illegal, raw)),
+            "{:#010x}",
+            raw
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use super::decode::DecodedValues;
+use std::{cell::RefCell, collections::HashSet};
+
+pub const DECODE_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // entries dropped by a store to their address
+    pub invalidations: u64,
+    pub flushes: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    paddr: u32,
+    instruction: u32,
+    decoded: DecodedValues,
+}
+
+// direct-mapped by physical pc, so switching address spaces
+// needs no flush and a store finds the entries it overwrites
+pub struct DecodeCache {
+    entries: RefCell<Vec<Option<Entry>>>,
+    // physical pages holding translated code, once a store
+    // or fence.i hits one it stays recorded until the
+    // translator has dropped its blocks
+    code_pages: RefCell<HashSet<u32>>,
+    written_pages: RefCell<Vec<u32>>,
+
+    stats: RefCell<DecodeCacheStats>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            entries: RefCell::new(vec![None; DECODE_CACHE_SIZE]),
+            code_pages: RefCell::new(HashSet::new()),
+            written_pages: RefCell::new(Vec::new()),
+
+            stats: RefCell::new(DecodeCacheStats::default()),
+        }
+    }
+
+    fn index(paddr: u32) -> usize {
+        (paddr as usize >> 1) % DECODE_CACHE_SIZE
+    }
+
+    // the raw instruction at paddr and its decoded fields
+    pub fn lookup(
+        &self,
+        paddr: u32,
+    ) -> Option<(u32, DecodedValues)> {
+        let entry = self.entries.borrow()[Self::index(paddr)]
+            .filter(|entry| entry.paddr == paddr)
+            .map(|entry| (entry.instruction, entry.decoded));
+
+        let mut stats = self.stats.borrow_mut();
+        if entry.is_some() {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+
+        entry
+    }
+
+    pub fn insert(
+        &self,
+        paddr: u32,
+        instruction: u32,
+        decoded: DecodedValues,
+    ) {
+        self.entries.borrow_mut()[Self::index(paddr)] =
+            Some(Entry {
+                paddr,
+                instruction,
+                decoded,
+            });
+    }
+
+    // a store of size bytes at paddr, which may also hit the
+    // upper half of a 32-bit instruction starting below it
+    pub fn invalidate(&self, paddr: u32, size: u32) {
+        let mut entries = self.entries.borrow_mut();
+        let start = paddr.saturating_sub(2) & !0b1;
+        let end = paddr.saturating_add(size);
+        self.mark_written(start >> 12);
+        self.mark_written((end - 1) >> 12);
+        for addr in (start..end).step_by(2) {
+            let entry = &mut entries[Self::index(addr)];
+            if entry.is_some_and(|e| e.paddr == addr) {
+                *entry = None;
+                self.stats.borrow_mut().invalidations += 1;
+            }
+        }
+    }
+
+    // fence.i
+    pub fn flush(&self) {
+        self.entries.borrow_mut().fill(None);
+        let pages = self.code_pages.take();
+        self.written_pages.borrow_mut().extend(pages);
+        self.stats.borrow_mut().flushes += 1;
+    }
+
+    pub fn watch_page(&self, page: u32) {
+        self.code_pages.borrow_mut().insert(page);
+    }
+
+    fn mark_written(&self, page: u32) {
+        let mut code_pages = self.code_pages.borrow_mut();
+        if !code_pages.is_empty() && code_pages.remove(&page) {
+            self.written_pages.borrow_mut().push(page);
+        }
+    }
+
+    pub fn has_written_pages(&self) -> bool {
+        !self.written_pages.borrow().is_empty()
+    }
+
+    pub fn take_written_pages(&self) -> Vec<u32> {
+        self.written_pages.take()
+    }
+
+    pub fn get_stats(&self) -> DecodeCacheStats {
+        self.stats.borrow().to_owned()
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test() {
+    let cache = DecodeCache::new();
+    let decoded = DecodedValues::new();
+
+    assert!(cache.lookup(0x8000_0000).is_none());
+    cache.insert(0x8000_0000, 0x0000_0513, decoded);
+    cache.insert(0x8000_0004, 0x0015_0513, decoded);
+    cache.insert(0x8000_0008, 0x4501, decoded);
+    assert_eq!(cache.lookup(0x8000_0000).unwrap().0, 0x513);
+
+    // same index, different address
+    let aliased = 0x8000_0000 + 2 * DECODE_CACHE_SIZE as u32;
+    assert!(cache.lookup(aliased).is_none());
+
+    // a byte store into the upper half of the second one
+    cache.invalidate(0x8000_0007, 1);
+    assert!(cache.lookup(0x8000_0000).is_some());
+    assert!(cache.lookup(0x8000_0004).is_none());
+    assert!(cache.lookup(0x8000_0008).is_some());
+
+    cache.flush();
+    assert!(cache.lookup(0x8000_0008).is_none());
+
+    let stats = cache.get_stats();
+    assert_eq!(stats.hits, 3);
+    assert_eq!(stats.misses, 4);
+    assert_eq!(stats.invalidations, 1);
+    assert_eq!(stats.flushes, 1);
+}
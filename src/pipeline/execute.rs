@@ -3,10 +3,18 @@ use std::convert::TryFrom;
 use std::{cell::RefCell, sync::Arc};
 
 use super::instruction_fetch::PCUpdateInfo;
-use super::{decode::DecodedValues, PipelineStage, Stage};
+use crate::{
+    csr::CsrFile,
+    fpu,
+    trap::{Exception, Trap},
+};
+use super::{
+    decode::DecodedValues, hazard::RegWrite, PipelineStage,
+};
 
 #[derive(Debug, Clone, Copy, TryFromPrimitive)]
 #[repr(u32)]
+#[allow(clippy::upper_case_acronyms)]
 enum ALUOperation {
     ADD = 0b000, // also SUB
     SLL = 0b001,
@@ -20,6 +28,20 @@ enum ALUOperation {
 
 #[derive(Debug, Clone, Copy, TryFromPrimitive)]
 #[repr(u32)]
+pub enum MulDivOperation {
+    MUL = 0b000,
+    MULH = 0b001,
+    MULHSU = 0b010,
+    MULHU = 0b011,
+    DIV = 0b100,
+    DIVU = 0b101,
+    REM = 0b110,
+    REMU = 0b111,
+}
+
+#[derive(Debug, Clone, Copy, TryFromPrimitive)]
+#[repr(u32)]
+#[allow(clippy::upper_case_acronyms)]
 enum BranchType {
     BEQ = 0b000,
     BNE = 0b001,
@@ -31,19 +53,30 @@ enum BranchType {
 
 #[derive(Debug, Clone, Copy)]
 pub struct ExecutionValues {
+    pub is_valid: bool,
+    pub instruction: u32,
     pub rd: u32,
     pub funct3: u32,
     pub rs1: u32,
     pub rs2: u32,
+    pub rs1_addr: u32,
+    pub rs2_addr: u32,
+    pub frs2: u64,
     pub imm11_0: u32,
     pub shamt: u32,
     pub imm32: i32,
 
     pub pc: u32,
     pub pc_plus_four: u32,
+    // the branch or jump target if taken
+    pub next_pc: u32,
+    pub predicted_pc: u32,
 
     pub alu_result: u32,
+    pub fp_result: u64,
+    pub fflags: u32,
     pub is_alu_operation: bool,
+    pub is_mul_div: bool,
     pub is_store: bool,
     pub is_load: bool,
     pub is_lui: bool,
@@ -51,24 +84,52 @@ pub struct ExecutionValues {
     pub is_jal: bool,
     pub is_jalr: bool,
     pub is_branch: bool,
+    pub is_csr: bool,
+    pub is_ecall: bool,
+    pub is_mret: bool,
+    pub is_sret: bool,
+    pub is_sfence_vma: bool,
+    pub is_fence_i: bool,
+    pub is_amo: bool,
+    pub is_aq: bool,
+    pub is_rl: bool,
+    pub is_fp: bool,
+    pub is_fp_load: bool,
+    pub is_fp_store: bool,
+    pub is_fp_to_int: bool,
+    pub writes_fp_reg: bool,
+    pub is_halt: bool,
+
+    pub trap: Option<Trap>,
 }
 
 impl ExecutionValues {
+    // a bubble
     pub fn new() -> Self {
         Self {
+            is_valid: false,
+            instruction: 0,
             rd: 0,
             funct3: 0,
             rs1: 0,
             rs2: 0,
+            rs1_addr: 0,
+            rs2_addr: 0,
+            frs2: 0,
             imm11_0: 0,
             shamt: 0,
             imm32: 0,
 
             pc: 0,
             pc_plus_four: 0,
+            next_pc: 0,
+            predicted_pc: 0,
 
             alu_result: 0,
+            fp_result: 0,
+            fflags: 0,
             is_alu_operation: false,
+            is_mul_div: false,
             is_store: false,
             is_load: false,
             is_lui: false,
@@ -76,12 +137,117 @@ impl ExecutionValues {
             is_jal: false,
             is_jalr: false,
             is_branch: false,
+            is_csr: false,
+            is_ecall: false,
+            is_mret: false,
+            is_sret: false,
+            is_sfence_vma: false,
+            is_fence_i: false,
+            is_amo: false,
+            is_aq: false,
+            is_rl: false,
+            is_fp: false,
+            is_fp_load: false,
+            is_fp_store: false,
+            is_fp_to_int: false,
+            writes_fp_reg: false,
+            is_halt: false,
+
+            trap: None,
         }
     }
+
+    // for forwarding, loads, AMOs and CSR reads have no
+    // value yet
+    pub fn reg_write(&self) -> Option<RegWrite> {
+        if !self.is_valid || self.trap.is_some() {
+            return None;
+        }
+
+        let writes_int_reg = self.is_alu_operation
+            || self.is_load
+            || self.is_lui
+            || self.is_jal
+            || self.is_jalr
+            || self.is_auipc
+            || self.is_csr
+            || self.is_amo
+            || self.is_fp_to_int;
+
+        if self.writes_fp_reg {
+            Some(RegWrite {
+                rd: self.rd,
+                is_fp: true,
+                val: (!self.is_fp_load)
+                    .then_some(self.fp_result),
+            })
+        } else if writes_int_reg && self.rd != 0 {
+            let val = if self.is_jal || self.is_jalr {
+                Some(self.pc_plus_four)
+            } else if self.is_lui {
+                Some(self.imm32 as u32)
+            } else if self.is_load
+                || self.is_csr
+                || self.is_amo
+            {
+                None
+            } else {
+                Some(self.alu_result)
+            };
+            Some(RegWrite {
+                rd: self.rd,
+                is_fp: false,
+                val: val.map(|val| val as u64),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ExecutionValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// RV32M: division by zero and signed overflow do not trap,
+// they produce the results defined in the spec instead. Also
+// used by the translator
+pub fn mul_div(op: MulDivOperation, rs1: u32, rs2: u32) -> u32 {
+    let (s1, s2) = (rs1 as i32 as i64, rs2 as i32 as i64);
+    let (u1, u2) = (rs1 as u64, rs2 as u64);
+
+    match op {
+        MulDivOperation::MUL => rs1.wrapping_mul(rs2),
+        MulDivOperation::MULH => ((s1 * s2) >> 32) as u32,
+        MulDivOperation::MULHSU => {
+            ((s1 * u2 as i64) >> 32) as u32
+        }
+        MulDivOperation::MULHU => ((u1 * u2) >> 32) as u32,
+        MulDivOperation::DIV => {
+            if rs2 == 0 {
+                u32::MAX
+            } else {
+                (rs1 as i32).wrapping_div(rs2 as i32) as u32
+            }
+        }
+        MulDivOperation::DIVU => {
+            rs1.checked_div(rs2).unwrap_or(u32::MAX)
+        }
+        MulDivOperation::REM => {
+            if rs2 == 0 {
+                rs1
+            } else {
+                (rs1 as i32).wrapping_rem(rs2 as i32) as u32
+            }
+        }
+        MulDivOperation::REMU => rs1.checked_rem(rs2).unwrap_or(rs1),
+    }
 }
 
 pub struct Execute {
-    stage: Arc<RefCell<Stage>>,
+    csr_file: Arc<CsrFile>,
 
     pc_update_info: RefCell<PCUpdateInfo>,
 
@@ -90,9 +256,9 @@ pub struct Execute {
 }
 
 impl Execute {
-    pub fn new(stage: Arc<RefCell<Stage>>) -> Self {
+    pub fn new(csr_file: Arc<CsrFile>) -> Self {
         Self {
-            stage,
+            csr_file,
 
             pc_update_info: RefCell::new(PCUpdateInfo {
                 should_update: false,
@@ -104,30 +270,36 @@ impl Execute {
         }
     }
 
+    // a mispredicted branch or jump, the instructions fetched
+    // after it are squashed
     pub fn get_pc_update_info(&self) -> PCUpdateInfo {
         self.pc_update_info.borrow().to_owned()
     }
-}
 
-impl PipelineStage<DecodedValues, ExecutionValues> for Execute {
-    fn compute(&self, values: DecodedValues) {
-        if self.should_stall() {
-            return;
+    // also used on its own by the functional model
+    pub fn execute(
+        &self,
+        de_val: DecodedValues,
+    ) -> ExecutionValues {
+        if !de_val.is_valid {
+            return ExecutionValues::new();
         }
 
-        let de_val = values;
-        let mut exe_val = self.exe_val.borrow_mut();
-        let mut pc_update_info =
-            self.pc_update_info.borrow_mut();
-
+        let mut exe_val = ExecutionValues::new();
+        exe_val.is_valid = true;
+        exe_val.instruction = de_val.instruction;
         exe_val.rd = de_val.rd;
         exe_val.funct3 = de_val.funct3;
         exe_val.rs1 = de_val.rs1;
         exe_val.rs2 = de_val.rs2;
+        exe_val.rs1_addr = de_val.rs1_addr;
+        exe_val.rs2_addr = de_val.rs2_addr;
+        exe_val.frs2 = de_val.frs2;
         exe_val.imm11_0 = de_val.imm11_0;
         exe_val.shamt = de_val.shamt;
         exe_val.imm32 = de_val.imm32;
         exe_val.is_alu_operation = de_val.is_alu_operation;
+        exe_val.is_mul_div = de_val.is_mul_div;
         exe_val.is_store = de_val.is_store;
         exe_val.is_load = de_val.is_load;
         exe_val.is_lui = de_val.is_lui;
@@ -135,17 +307,37 @@ impl PipelineStage<DecodedValues, ExecutionValues> for Execute {
         exe_val.is_jal = de_val.is_jal;
         exe_val.is_jalr = de_val.is_jalr;
         exe_val.is_branch = de_val.is_branch;
+        exe_val.is_csr = de_val.is_csr;
+        exe_val.is_ecall = de_val.is_ecall;
+        exe_val.is_mret = de_val.is_mret;
+        exe_val.is_sret = de_val.is_sret;
+        exe_val.is_sfence_vma = de_val.is_sfence_vma;
+        exe_val.is_fence_i = de_val.is_fence_i;
+        exe_val.is_amo = de_val.is_amo;
+        exe_val.is_aq = de_val.is_aq;
+        exe_val.is_rl = de_val.is_rl;
+        exe_val.is_fp = de_val.is_fp;
+        exe_val.is_fp_load = de_val.is_fp_load;
+        exe_val.is_fp_store = de_val.is_fp_store;
+        exe_val.is_fp_to_int = de_val.is_fp_to_int;
+        exe_val.writes_fp_reg = de_val.writes_fp_reg;
+        exe_val.is_halt = de_val.is_halt;
+        exe_val.fflags = 0;
+        exe_val.trap = de_val.trap;
 
         exe_val.pc = de_val.pc;
         exe_val.pc_plus_four = de_val.pc_plus_four;
+        exe_val.predicted_pc = de_val.predicted_pc;
 
         let is_register_op = (de_val.opcode >> 5) & 1 == 1;
         let is_alternate = (de_val.imm11_0 >> 10) & 1 == 1;
 
+        // I-type immediates are sign extended, also for the
+        // logical operations and sltiu
         let right_operant = if is_register_op {
             de_val.rs2
         } else {
-            de_val.imm11_0
+            de_val.imm32 as u32
         };
 
         // ALU
@@ -203,46 +395,131 @@ impl PipelineStage<DecodedValues, ExecutionValues> for Execute {
         let or_result = de_val.rs1 | right_operant;
         let xor_result = de_val.rs1 ^ right_operant;
 
-        exe_val.alu_result =
-            match ALUOperation::try_from(de_val.funct3) {
-                Ok(ALUOperation::ADD) => add_result,
-                Ok(ALUOperation::SLL) => sll_result,
-                Ok(ALUOperation::SRL) => srl_result,
-                Ok(ALUOperation::SLTU) => sltu_result,
-                Ok(ALUOperation::SLT) => slt_result,
-                Ok(ALUOperation::AND) => and_result,
-                Ok(ALUOperation::OR) => or_result,
-                Ok(ALUOperation::XOR) => xor_result,
-                _ => {
-                    // println!("Unimplemented! funct3 = {:#05b}, instruction = {:#010x}",
-                    //     de_val.funct3,
-                    //     de_val.instruction);
-                    0_u32
+        exe_val.alu_result = if exe_val.is_mul_div {
+            // funct3 is 3 bits wide, every value is a valid op
+            let op = MulDivOperation::try_from(de_val.funct3)
+                .expect("Invalid mul/div operation");
+            mul_div(op, de_val.rs1, de_val.rs2)
+        } else {
+            // decode has rejected the invalid funct7s, funct3
+            // selects one of the eight operations
+            let op = ALUOperation::try_from(de_val.funct3)
+                .expect("Invalid ALU operation");
+            match op {
+                ALUOperation::ADD => add_result,
+                ALUOperation::SLL => sll_result,
+                ALUOperation::SRL => srl_result,
+                ALUOperation::SLTU => sltu_result,
+                ALUOperation::SLT => slt_result,
+                ALUOperation::AND => and_result,
+                ALUOperation::OR => or_result,
+                ALUOperation::XOR => xor_result,
+            }
+        };
+
+        // F and D, every FP instruction is illegal while
+        // mstatus.FS is off
+        let illegal_instruction = Some(Trap::new(
+            Exception::IllegalInstruction,
+            de_val.instruction,
+        ));
+        let is_fp_instruction = exe_val.is_fp
+            || exe_val.is_fp_load
+            || exe_val.is_fp_store;
+        if is_fp_instruction && exe_val.trap.is_none() {
+            let rm = fpu::rounding_mode(
+                de_val.instruction,
+                self.csr_file.frm(),
+            );
+            if !self.csr_file.is_fp_enabled() {
+                exe_val.trap = illegal_instruction;
+            } else if exe_val.is_fp {
+                match rm {
+                    Some(rm) => {
+                        let (result, flags) = fpu::execute(
+                            de_val.instruction,
+                            de_val.rs1,
+                            de_val.frs1,
+                            de_val.frs2,
+                            de_val.frs3,
+                            rm,
+                        );
+                        exe_val.fp_result = result;
+                        exe_val.fflags = flags;
+                        if exe_val.is_fp_to_int {
+                            exe_val.alu_result = result as u32;
+                        }
+                    }
+                    None => exe_val.trap = illegal_instruction,
                 }
-            };
+            }
+        }
 
         let beq_result = de_val.rs1 == de_val.rs2;
         let slt_result = slt_result == 1;
         let sltu_result = sltu_result == 1;
+        // decode only lets the six branch types through
         let branch_condition_met = exe_val.is_branch
-            && match BranchType::try_from(de_val.funct3) {
-                Ok(BranchType::BEQ) => beq_result,
-                Ok(BranchType::BNE) => !beq_result,
-                Ok(BranchType::BLT) => slt_result,
-                Ok(BranchType::BGE) => !slt_result,
-                Ok(BranchType::BLTU) => sltu_result,
-                Ok(BranchType::BGEU) => !sltu_result,
-                _ => false,
+            && match BranchType::try_from(de_val.funct3)
+                .expect("Invalid branch type")
+            {
+                BranchType::BEQ => beq_result,
+                BranchType::BNE => !beq_result,
+                BranchType::BLT => slt_result,
+                BranchType::BGE => !slt_result,
+                BranchType::BLTU => sltu_result,
+                BranchType::BGEU => !sltu_result,
             };
 
-        pc_update_info.should_update = exe_val.is_jal
-            || exe_val.is_jalr
-            || branch_condition_met;
-        pc_update_info.pc_new = add_result;
+        let is_taken = exe_val.trap.is_none()
+            && (exe_val.is_jal
+                || exe_val.is_jalr
+                || branch_condition_met);
+        let target = if exe_val.is_jalr {
+            add_result & !1
+        } else {
+            add_result
+        };
+
+        // reported on the jump itself, not on the fetch. With
+        // the C extension targets only need 2-byte alignment
+        if is_taken && target & 0b1 != 0 {
+            exe_val.trap = Some(Trap::new(
+                Exception::InstructionAddrMisaligned,
+                target,
+            ));
+        }
+
+        exe_val.next_pc = if is_taken && exe_val.trap.is_none() {
+            target
+        } else {
+            exe_val.pc_plus_four
+        };
+
+        exe_val
     }
 
-    fn should_stall(&self) -> bool {
-        !matches!(self.stage.borrow().to_owned(), Stage::EXE)
+    // squashes the instruction being executed
+    pub fn flush(&self) {
+        self.pc_update_info.borrow_mut().should_update = false;
+        self.exe_val.replace(ExecutionValues::new());
+    }
+}
+
+impl PipelineStage<DecodedValues, ExecutionValues> for Execute {
+    fn compute(&self, values: DecodedValues) {
+        let exe_val = self.execute(values);
+
+        // a misprediction, fetching has gone down the wrong
+        // path
+        let mut pc_update_info =
+            self.pc_update_info.borrow_mut();
+        pc_update_info.should_update = exe_val.is_valid
+            && exe_val.trap.is_none()
+            && exe_val.next_pc != exe_val.predicted_pc;
+        pc_update_info.pc_new = exe_val.next_pc;
+
+        self.exe_val.replace(exe_val);
     }
 
     fn latch_next(&self) {
@@ -254,3 +531,34 @@ impl PipelineStage<DecodedValues, ExecutionValues> for Execute {
         self.exe_val_ready.borrow().to_owned()
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_mul_div() {
+    use MulDivOperation::*;
+
+    let min = i32::MIN as u32;
+    let neg = |v: i32| v as u32;
+
+    assert_eq!(mul_div(MUL, 7, neg(-3)), neg(-21));
+    assert_eq!(mul_div(MULH, neg(-1), neg(-1)), 0);
+    assert_eq!(mul_div(MULH, min, min), 0x4000_0000);
+    assert_eq!(mul_div(MULHU, u32::MAX, u32::MAX), 0xffff_fffe);
+    assert_eq!(mul_div(MULHSU, neg(-1), u32::MAX), u32::MAX);
+    assert_eq!(mul_div(MULHSU, 2, u32::MAX), 1);
+
+    assert_eq!(mul_div(DIV, neg(-7), 2), neg(-3));
+    assert_eq!(mul_div(REM, neg(-7), 2), neg(-1));
+    assert_eq!(mul_div(DIVU, 7, 2), 3);
+    assert_eq!(mul_div(REMU, 7, 2), 1);
+
+    // division by zero
+    assert_eq!(mul_div(DIV, 5, 0), u32::MAX);
+    assert_eq!(mul_div(DIVU, 5, 0), u32::MAX);
+    assert_eq!(mul_div(REM, neg(-5), 0), neg(-5));
+    assert_eq!(mul_div(REMU, 5, 0), 5);
+
+    // signed overflow
+    assert_eq!(mul_div(DIV, min, neg(-1)), min);
+    assert_eq!(mul_div(REM, min, neg(-1)), 0);
+}
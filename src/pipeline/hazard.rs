@@ -0,0 +1,99 @@
+use super::decode::DecodedValues;
+
+const OPCODE_OP: u32 = 0b011_0011;
+
+// a register an instruction in flight is going to write
+#[derive(Debug, Clone, Copy)]
+pub struct RegWrite {
+    pub rd: u32,
+    pub is_fp: bool,
+    // None while the value is still being computed
+    pub val: Option<u64>,
+}
+
+// conservative, a false positive costs a stall cycle
+fn reads_reg(de_val: &DecodedValues, reg: u32) -> bool {
+    let reads_rs1 =
+        !(de_val.is_lui || de_val.is_auipc || de_val.is_jal);
+    let reads_rs2 = de_val.is_store
+        || de_val.is_branch
+        || de_val.is_amo
+        || de_val.opcode == OPCODE_OP;
+
+    (reads_rs1 && de_val.rs1_addr == reg)
+        || (reads_rs2 && de_val.rs2_addr == reg)
+}
+
+// every FP operation is assumed to read all three
+fn reads_freg(de_val: &DecodedValues, reg: u32) -> bool {
+    let rs3_addr = de_val.instruction >> 27;
+
+    (de_val.is_fp
+        && (de_val.rs1_addr == reg
+            || de_val.rs2_addr == reg
+            || rs3_addr == reg))
+        || (de_val.is_fp_store && de_val.rs2_addr == reg)
+}
+
+// loads, AMOs and CSR reads only have their result after
+// Memory Access, an instruction right behind one that needs
+// it cannot get it forwarded in time
+pub fn is_load_use(
+    exe_val: &DecodedValues,
+    de_val: &DecodedValues,
+) -> bool {
+    if !exe_val.is_valid
+        || !de_val.is_valid
+        || exe_val.trap.is_some()
+    {
+        return false;
+    }
+
+    if exe_val.is_fp_load {
+        reads_freg(de_val, exe_val.rd)
+    } else if exe_val.is_load || exe_val.is_amo || exe_val.is_csr
+    {
+        exe_val.rd != 0 && reads_reg(de_val, exe_val.rd)
+    } else {
+        false
+    }
+}
+
+// replaces the operands read in Decode with the results of
+// older instructions that have not been written back yet,
+// `writes` goes from the oldest to the youngest
+pub fn forward(
+    de_val: DecodedValues,
+    writes: &[Option<RegWrite>],
+) -> DecodedValues {
+    let mut val = de_val;
+    let rs3_addr = de_val.instruction >> 27;
+
+    for write in writes.iter().flatten() {
+        let result = match write.val {
+            Some(result) => result,
+            None => continue,
+        };
+
+        if write.is_fp {
+            if val.rs1_addr == write.rd {
+                val.frs1 = result;
+            }
+            if val.rs2_addr == write.rd {
+                val.frs2 = result;
+            }
+            if rs3_addr == write.rd {
+                val.frs3 = result;
+            }
+        } else {
+            if val.rs1_addr == write.rd {
+                val.rs1 = result as u32;
+            }
+            if val.rs2_addr == write.rd {
+                val.rs2 = result as u32;
+            }
+        }
+    }
+
+    val
+}
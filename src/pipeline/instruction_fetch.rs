@@ -1,11 +1,28 @@
 use super::{
-    memory_access::MemoryAccessWidth, PipelineStage, Stage,
+    compressed::is_compressed,
+    decode::{self, DecodedValues},
+    decode_cache::DecodeCache,
+    execute::ExecutionValues,
+    PipelineStage,
+};
+use crate::{
+    branch_predictor::{
+        btb::Btb, ras::Ras, BranchPredictor, BranchStats,
+        NotTaken,
+    },
+    bus::Bus,
+    mmu::{AccessType, Mmu},
+    trap::{Exception, Trap},
+};
+use std::{
+    cell::{Cell, RefCell},
+    sync::Arc,
 };
-use crate::bus::Bus;
-use std::{cell::RefCell, sync::Arc};
 
-// addi x0, x1, 0x123
-const debug_inst: u32 = 0x12308013;
+// x1 and x5 hold return addresses
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct PCUpdateInfo {
@@ -15,27 +32,49 @@ pub struct PCUpdateInfo {
 
 #[derive(Debug, Clone, Copy)]
 pub struct InstructionFetchValues {
+    pub is_valid: bool,
     pub pc: u32,
     pub pc_plus_four: u32,
+    // where fetching went on after this instruction
+    pub predicted_pc: u32,
     pub instruction: u32,
+    pub decoded: DecodedValues,
+    pub trap: Option<Trap>,
 }
 
 impl InstructionFetchValues {
-    pub fn new(entry_point: u32) -> Self {
+    // a bubble
+    pub fn new() -> Self {
         Self {
-            pc: entry_point,
-            pc_plus_four: entry_point,
+            is_valid: false,
+            pc: 0,
+            pc_plus_four: 0,
+            predicted_pc: 0,
             instruction: 0_u32,
+            decoded: DecodedValues::new(),
+            trap: None,
         }
     }
 }
 
-pub struct InstructionFetch {
-    stage: Arc<RefCell<Stage>>,
+impl Default for InstructionFetchValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+pub struct InstructionFetch {
     bus: Arc<Bus>,
+    mmu: Arc<Mmu>,
+    decode_cache: Arc<DecodeCache>,
 
-    cycle: RefCell<u64>,
+    // where the next fetch happens
+    pc: Cell<u32>,
+
+    predictor: RefCell<Box<dyn BranchPredictor>>,
+    btb: Btb,
+    ras: Ras,
+    branch_stats: RefCell<BranchStats>,
 
     if_val: RefCell<InstructionFetchValues>,
     if_val_ready: RefCell<InstructionFetchValues>,
@@ -43,73 +82,230 @@ pub struct InstructionFetch {
 
 impl InstructionFetch {
     pub fn new(
-        stage: Arc<RefCell<Stage>>,
         bus: Arc<Bus>,
+        mmu: Arc<Mmu>,
+        decode_cache: Arc<DecodeCache>,
+        entry_point: u32,
     ) -> Self {
         Self {
-            stage,
+            bus,
+            mmu,
+            decode_cache,
 
-            bus: bus.clone(),
+            pc: Cell::new(entry_point),
 
-            cycle: RefCell::new(1_u64),
+            predictor: RefCell::new(Box::new(NotTaken)),
+            btb: Btb::new(),
+            ras: Ras::new(),
+            branch_stats: RefCell::new(BranchStats::default()),
 
-            if_val: RefCell::new(InstructionFetchValues::new(
-                bus.memory_layout.rom_start as u32,
-            )),
+            if_val: RefCell::new(InstructionFetchValues::new()),
             if_val_ready: RefCell::new(
-                InstructionFetchValues::new(
-                    bus.memory_layout.rom_start as u32,
-                ),
+                InstructionFetchValues::new(),
             ),
         }
     }
 
-    pub fn should_halt(&self) -> bool {
-        *self.cycle.borrow() == 0
+    // instructions are 2-byte aligned and may straddle a
+    // word (or a page), so they are fetched in halves
+    fn fetch_half_word(
+        &self,
+        pc: u32,
+        addr: u32,
+    ) -> Result<u32, Trap> {
+        if addr & 0b1 != 0 {
+            return Err(Trap::new(
+                Exception::InstructionAddrMisaligned,
+                pc,
+            ));
+        }
+
+        let word = self
+            .bus
+            .fetch(addr as usize & !0b11)
+            .map_err(|e| {
+                Trap::from_fetch_error(e, pc)
+                    .with_fault(self.bus.log_fault(e))
+            })?;
+        Ok((word >> ((addr & 0b10) * 8)) & 0xffff)
     }
-}
 
-impl PipelineStage<PCUpdateInfo, InstructionFetchValues>
-    for InstructionFetch
-{
-    fn compute(&self, values: PCUpdateInfo) {
-        if self.should_stall() {
-            return;
+    fn fetch(
+        &self,
+        pc: u32,
+    ) -> Result<(u32, DecodedValues), Trap> {
+        let addr = self.mmu.translate(pc, AccessType::Fetch)?;
+        if let Some(entry) = self.decode_cache.lookup(addr) {
+            return Ok(entry);
         }
 
-        let mut if_val = self.if_val.borrow_mut();
-
-        if_val.pc = if values.should_update {
-            values.pc_new
+        let low = self.fetch_half_word(pc, addr)?;
+        let instruction = if is_compressed(low) {
+            low
         } else {
-            if_val.pc_plus_four
+            let addr_high = self
+                .mmu
+                .translate(pc + 2, AccessType::Fetch)?;
+            let high = self.fetch_half_word(pc + 2, addr_high)?;
+            (high << 16) | low
         };
+        let decoded = decode::decode_instruction(instruction);
 
-        let addr = if_val.pc as usize;
-        if_val.instruction = self
-            .bus
-            .read(addr, MemoryAccessWidth::Word)
-            .expect("Instruction Fetch Error");
+        // only RAM and ROM change nothing but through stores,
+        // and the halves of an instruction on a page boundary
+        // may be mapped from anywhere
+        let length = if is_compressed(low) { 2 } else { 4 };
+        if (pc & 0xfff) + length <= 0x1000
+            && self.bus.is_memory(addr as usize, length as usize)
+        {
+            self.decode_cache.insert(addr, instruction, decoded);
+        }
 
-        // tracing
-        // println!("{}:\tpc = {:#010x}", self.cycle.borrow(), addr);
+        Ok((instruction, decoded))
+    }
+
+    // the pc the next fetch will use
+    pub fn get_pc(&self) -> u32 {
+        self.pc.get()
+    }
+
+    // the instruction at pc, assuming the next one follows
+    // it. Also used on its own by the functional model
+    pub fn fetch_at(&self, pc: u32) -> InstructionFetchValues {
+        let (instruction, decoded, trap) = match self.fetch(pc)
+        {
+            Ok((instruction, decoded)) => {
+                (instruction, decoded, None)
+            }
+            Err(trap) => {
+                (0, decode::decode_instruction(0), Some(trap))
+            }
+        };
+
+        // pc + 2 after a compressed instruction, this is also
+        // the link value of c.jal and c.jalr
+        let length =
+            if is_compressed(instruction) { 2 } else { 4 };
+        let pc_plus_four = pc.wrapping_add(length);
 
-        if if_val.instruction == debug_inst {
-            println!("hit breakpoint");
-            self.bus.mem_dump(0x4010);
-            loop {};
+        InstructionFetchValues {
+            is_valid: true,
+            pc,
+            pc_plus_four,
+            predicted_pc: pc_plus_four,
+            instruction,
+            decoded,
+            trap,
+        }
+    }
+
+    pub fn set_branch_predictor(
+        &self,
+        predictor: Box<dyn BranchPredictor>,
+    ) {
+        self.predictor.replace(predictor);
+    }
+
+    pub fn get_branch_stats(&self) -> BranchStats {
+        self.branch_stats.borrow().to_owned()
+    }
+
+    // fetch has already decoded the instruction: jal always
+    // goes to its target, conditional branches ask the
+    // predictor, returns pop the return address stack and
+    // other indirect jumps take the BTB target
+    fn predict(
+        &self,
+        pc: u32,
+        decoded: &DecodedValues,
+        pc_next: u32,
+    ) -> u32 {
+        let (rd, rs1) = (decoded.rd, decoded.rs1_addr);
+        if decoded.is_jal {
+            if is_link(rd) {
+                self.ras.push(pc_next);
+            }
+            pc.wrapping_add(decoded.imm32 as u32)
+        } else if decoded.is_branch {
+            let target = pc.wrapping_add(decoded.imm32 as u32);
+            if self.predictor.borrow().predict(pc, target) {
+                target
+            } else {
+                pc_next
+            }
+        } else if decoded.is_jalr {
+            // a call through a link register that is also the
+            // return address is a coroutine swap
+            let is_return =
+                is_link(rs1) && !(is_link(rd) && rd == rs1);
+            let target = if is_return {
+                self.ras.pop()
+            } else {
+                None
+            };
+            if is_link(rd) {
+                self.ras.push(pc_next);
+            }
+            target
+                .or_else(|| self.btb.lookup(pc))
+                .unwrap_or(pc_next)
+        } else {
+            pc_next
         }
+    }
 
-        if_val.pc_plus_four = if_val.pc + 4;
-        self.cycle.replace_with(|&mut c| c + 1);
+    // trains the predictor with a branch or jump Execute has
+    // resolved
+    pub fn resolve(&self, exe_val: &ExecutionValues) {
+        if !exe_val.is_valid || exe_val.trap.is_some() {
+            return;
+        }
 
-        if if_val.instruction == 0 {
-            self.cycle.replace(0);
+        let is_mispredicted =
+            exe_val.next_pc != exe_val.predicted_pc;
+        let mut stats = self.branch_stats.borrow_mut();
+        if exe_val.is_branch {
+            let taken = exe_val.next_pc != exe_val.pc_plus_four;
+            self.predictor.borrow().update(exe_val.pc, taken);
+            stats.branches += 1;
+            stats.branch_mispredictions +=
+                is_mispredicted as u64;
+        } else if exe_val.is_jalr {
+            self.btb.insert(exe_val.pc, exe_val.next_pc);
+            stats.indirect_jumps += 1;
+            stats.indirect_mispredictions +=
+                is_mispredicted as u64;
         }
     }
+}
+
+impl PipelineStage<PCUpdateInfo, InstructionFetchValues>
+    for InstructionFetch
+{
+    // a redirect takes effect from the next cycle on, the
+    // slot it arrives in becomes a bubble
+    fn compute(&self, values: PCUpdateInfo) {
+        let mut if_val = self.if_val.borrow_mut();
+        if values.should_update {
+            self.pc.set(values.pc_new);
+            *if_val = InstructionFetchValues::new();
+            return;
+        }
 
-    fn should_stall(&self) -> bool {
-        !matches!(self.stage.borrow().to_owned(), Stage::IF)
+        let pc = self.pc.get();
+        *if_val = self.fetch_at(pc);
+
+        // tracing
+        // println!("pc = {:#010x}", if_val.pc);
+
+        // fetching goes on at the predicted pc until Execute or
+        // WB redirects it
+        if if_val.trap.is_none() {
+            let pc_next = if_val.pc_plus_four;
+            if_val.predicted_pc =
+                self.predict(pc, &if_val.decoded, pc_next);
+        }
+        self.pc.set(if_val.predicted_pc);
     }
 
     fn latch_next(&self) {
@@ -118,14 +314,6 @@ impl PipelineStage<PCUpdateInfo, InstructionFetchValues>
     }
 
     fn get_values_out(&self) -> InstructionFetchValues {
-        // self.if_val_ready.borrow().to_owned()
-
-        // temporarily done in this way, before pipeline becomes multi-period
-        let if_val_ready = self.if_val_ready.borrow().to_owned();
-        InstructionFetchValues {
-            pc: if_val_ready.pc,
-            pc_plus_four: if_val_ready.pc_plus_four,
-            instruction: if_val_ready.instruction,
-        }
+        self.if_val_ready.borrow().to_owned()
     }
 }
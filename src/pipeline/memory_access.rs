@@ -1,13 +1,35 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    sync::Arc,
+};
 
 use num_enum::TryFromPrimitive;
 
-use crate::bus::Bus;
+use crate::{
+    bus::Bus,
+    csr::{CsrError, CsrFile, PrivilegeMode},
+    fpu,
+    mmu::{AccessType, Mmu},
+    trap::{Exception, Trap},
+};
 
-use super::{execute::ExecutionValues, PipelineStage, Stage};
+use super::{
+    decode_cache::DecodeCache, execute::ExecutionValues,
+    hazard::RegWrite, PipelineStage,
+};
+
+// the data access an instruction made, for watchpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAccess {
+    pub vaddr: u32,
+    pub size: u32,
+    pub is_read: bool,
+    pub is_write: bool,
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct MemoryAccessValues {
+    pub is_valid: bool,
     pub rd: u32,
     pub rs1: u32,
     pub rs2: u32,
@@ -20,14 +42,33 @@ pub struct MemoryAccessValues {
     pub is_auipc: bool,
     pub is_jal: bool,
     pub is_jalr: bool,
+    pub is_csr: bool,
+    pub is_mret: bool,
+    pub is_sret: bool,
+    pub is_amo: bool,
+    pub is_fp_to_int: bool,
+    pub writes_fp_reg: bool,
+    // CSR accesses, sfence.vma and fence.i may change how
+    // the instructions behind them are fetched or executed
+    pub is_serializing: bool,
+    pub is_halt: bool,
 
+    pub pc: u32,
+    pub instruction: u32,
+    pub next_pc: u32,
     pub imm32: i32,
     pub write_back_value: u32,
+    pub fp_write_back_value: u64,
+    pub fflags: u32,
+    pub data_access: Option<DataAccess>,
+    pub trap: Option<Trap>,
 }
 
 impl MemoryAccessValues {
+    // a bubble
     pub fn new() -> Self {
         Self {
+            is_valid: false,
             rd: 0,
             rs1: 0,
             rs2: 0,
@@ -40,14 +81,69 @@ impl MemoryAccessValues {
             is_auipc: false,
             is_jal: false,
             is_jalr: false,
+            is_csr: false,
+            is_mret: false,
+            is_sret: false,
+            is_amo: false,
+            is_fp_to_int: false,
+            writes_fp_reg: false,
+            is_serializing: false,
+            is_halt: false,
 
+            pc: 0,
+            instruction: 0,
+            next_pc: 0,
             imm32: 0_i32,
             write_back_value: 0,
+            fp_write_back_value: 0,
+            fflags: 0,
+            data_access: None,
+            trap: None,
+        }
+    }
+
+    pub fn writes_int_reg(&self) -> bool {
+        (self.is_alu_operation
+            || self.is_load
+            || self.is_lui
+            || self.is_jal
+            || self.is_jalr
+            || self.is_auipc
+            || self.is_csr
+            || self.is_amo
+            || self.is_fp_to_int)
+            && self.rd != 0
+    }
+
+    // for forwarding, every result is known by now
+    pub fn reg_write(&self) -> Option<RegWrite> {
+        if !self.is_valid || self.trap.is_some() {
+            None
+        } else if self.writes_fp_reg {
+            Some(RegWrite {
+                rd: self.rd,
+                is_fp: true,
+                val: Some(self.fp_write_back_value),
+            })
+        } else if self.writes_int_reg() {
+            Some(RegWrite {
+                rd: self.rd,
+                is_fp: false,
+                val: Some(self.write_back_value as u64),
+            })
+        } else {
+            None
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, TryFromPrimitive)]
+impl Default for MemoryAccessValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u32)]
 pub enum MemoryAccessWidth {
     Byte = 0b00,
@@ -55,10 +151,66 @@ pub enum MemoryAccessWidth {
     Word = 0b10,
 }
 
-pub struct MemoryAccess {
-    stage: Arc<RefCell<Stage>>,
+impl MemoryAccessWidth {
+    // in bytes
+    pub fn size(self) -> usize {
+        1 << self as u32
+    }
+}
 
+// what a load or store to an address that is not a multiple
+// of its width does. AMOs always trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MisalignedPolicy {
+    // raise a misaligned exception
+    Trap,
+    // split the access into bytes
+    Emulate,
+}
+
+// where the bytes of a load or store live
+enum PhysicalAccess {
+    Aligned(usize),
+    // one address per byte, the access may cross a page
+    Split(Vec<usize>),
+}
+
+#[derive(Debug, Clone, Copy, TryFromPrimitive)]
+#[repr(u32)]
+#[allow(clippy::upper_case_acronyms)]
+enum CsrOperation {
+    CSRRW = 0b001,
+    CSRRS = 0b010,
+    CSRRC = 0b011,
+    CSRRWI = 0b101,
+    CSRRSI = 0b110,
+    CSRRCI = 0b111,
+}
+
+// funct5 of the AMO opcode
+#[derive(Debug, Clone, Copy, TryFromPrimitive)]
+#[repr(u32)]
+pub enum AmoOperation {
+    AMOADD = 0b00000,
+    AMOSWAP = 0b00001,
+    LR = 0b00010,
+    SC = 0b00011,
+    AMOXOR = 0b00100,
+    AMOOR = 0b01000,
+    AMOAND = 0b01100,
+    AMOMIN = 0b10000,
+    AMOMAX = 0b10100,
+    AMOMINU = 0b11000,
+    AMOMAXU = 0b11100,
+}
+
+pub struct MemoryAccess {
     bus: Arc<Bus>,
+    csr_file: Arc<CsrFile>,
+    mmu: Arc<Mmu>,
+    decode_cache: Arc<DecodeCache>,
+
+    misaligned_policy: Cell<MisalignedPolicy>,
 
     mem_val: RefCell<MemoryAccessValues>,
     mem_val_ready: RefCell<MemoryAccessValues>,
@@ -66,13 +218,18 @@ pub struct MemoryAccess {
 
 impl MemoryAccess {
     pub fn new(
-        stage: Arc<RefCell<Stage>>,
         bus: Arc<Bus>,
+        csr_file: Arc<CsrFile>,
+        mmu: Arc<Mmu>,
+        decode_cache: Arc<DecodeCache>,
     ) -> Self {
         Self {
-            stage,
-
             bus,
+            csr_file,
+            mmu,
+            decode_cache,
+
+            misaligned_policy: Cell::new(MisalignedPolicy::Trap),
 
             mem_val: RefCell::new(MemoryAccessValues::new()),
             mem_val_ready: RefCell::new(
@@ -80,19 +237,19 @@ impl MemoryAccess {
             ),
         }
     }
-}
 
-impl PipelineStage<ExecutionValues, MemoryAccessValues>
-    for MemoryAccess
-{
-    fn compute(&self, values: ExecutionValues) {
-        if self.should_stall() {
-            return;
+    // the memory or CSR access of an instruction and its
+    // result. Also used on its own by the functional model
+    pub fn access(
+        &self,
+        exe_val: ExecutionValues,
+    ) -> MemoryAccessValues {
+        let mut mem_val = MemoryAccessValues::new();
+        if !exe_val.is_valid {
+            return mem_val;
         }
 
-        let exe_val = values;
-        let mut mem_val = self.mem_val.borrow_mut();
-
+        mem_val.is_valid = true;
         mem_val.rd = exe_val.rd;
         mem_val.rs1 = exe_val.rs1;
         mem_val.rs2 = exe_val.rs2;
@@ -104,6 +261,18 @@ impl PipelineStage<ExecutionValues, MemoryAccessValues>
         mem_val.is_auipc = exe_val.is_auipc;
         mem_val.is_jal = exe_val.is_jal;
         mem_val.is_jalr = exe_val.is_jalr;
+        mem_val.is_csr = exe_val.is_csr;
+        mem_val.is_mret = exe_val.is_mret;
+        mem_val.is_sret = exe_val.is_sret;
+        mem_val.is_amo = exe_val.is_amo;
+        mem_val.is_fp_to_int = exe_val.is_fp_to_int;
+        mem_val.writes_fp_reg = exe_val.writes_fp_reg;
+        mem_val.is_serializing = exe_val.is_csr
+            || exe_val.is_sfence_vma
+            || exe_val.is_fence_i;
+        mem_val.is_halt = exe_val.is_halt;
+        mem_val.fp_write_back_value = exe_val.fp_result;
+        mem_val.fflags = exe_val.fflags;
         mem_val.imm32 = exe_val.imm32;
         mem_val.write_back_value =
             if mem_val.is_jal || mem_val.is_jalr {
@@ -112,28 +281,65 @@ impl PipelineStage<ExecutionValues, MemoryAccessValues>
                 exe_val.alu_result
             };
 
+        mem_val.pc = exe_val.pc;
+        mem_val.instruction = exe_val.instruction;
+        mem_val.next_pc = exe_val.next_pc;
+        mem_val.data_access = None;
+        mem_val.trap = exe_val.trap;
+        if mem_val.trap.is_some() {
+            // a trapping instruction must not have side effects
+            return mem_val;
+        }
+
         // this line should be done in the ALU
-        let addr =
-            (mem_val.rs1 as i32 + mem_val.imm32) as u32 as usize;
+        let vaddr = (mem_val.rs1 as i32 + mem_val.imm32) as u32;
+
+        let illegal_instruction = Some(Trap::new(
+            Exception::IllegalInstruction,
+            exe_val.instruction,
+        ));
+        // recorded once the access has completed
+        let data_access = Self::data_access(&exe_val, vaddr);
 
         if mem_val.is_store {
-            let width = MemoryAccessWidth::try_from(
+            let width = match MemoryAccessWidth::try_from(
                 mem_val.funct3 & 0b11,
-            )
-            .expect("Invalid store width");
-            self.bus
-                .write(addr, mem_val.rs2, width)
-                .expect("Memory store error");
+            ) {
+                Ok(width) => width,
+                Err(_) => {
+                    mem_val.trap = illegal_instruction;
+                    return mem_val;
+                }
+            };
+            let result = self
+                .translate_access(vaddr, width, AccessType::Store)
+                .and_then(|paddr| {
+                    self.write(&paddr, mem_val.rs2, width, vaddr)
+                });
+            if let Err(trap) = result {
+                mem_val.trap = Some(trap);
+            }
         } else if mem_val.is_load {
             let signed_extend = mem_val.funct3 & 0b100 == 0;
-            let width = MemoryAccessWidth::try_from(
+            let width = match MemoryAccessWidth::try_from(
                 mem_val.funct3 & 0b11,
-            )
-            .expect("Invalid load width");
-            let val = self
-                .bus
-                .read(addr, width)
-                .expect("Memory load error");
+            ) {
+                Ok(width) => width,
+                Err(_) => {
+                    mem_val.trap = illegal_instruction;
+                    return mem_val;
+                }
+            };
+            let result = self
+                .translate_access(vaddr, width, AccessType::Load)
+                .and_then(|paddr| self.read(&paddr, width, vaddr));
+            let val = match result {
+                Ok(val) => val,
+                Err(trap) => {
+                    mem_val.trap = Some(trap);
+                    return mem_val;
+                }
+            };
             mem_val.write_back_value = if signed_extend {
                 match width {
                     MemoryAccessWidth::Byte => {
@@ -147,13 +353,397 @@ impl PipelineStage<ExecutionValues, MemoryAccessValues>
             } else {
                 val
             };
+        } else if exe_val.is_fp_load || exe_val.is_fp_store {
+            match self.fp_access(&exe_val, vaddr) {
+                Ok(val) => mem_val.fp_write_back_value = val,
+                Err(trap) => mem_val.trap = Some(trap),
+            }
+        } else if mem_val.is_amo {
+            match self.amo_access(&exe_val) {
+                Ok(val) => mem_val.write_back_value = val,
+                Err(trap) => mem_val.trap = Some(trap),
+            }
         } else if mem_val.is_lui {
             mem_val.write_back_value = mem_val.imm32 as u32;
+        } else if mem_val.is_csr {
+            // CSRs are accessed here, together with the other
+            // side effects, instead of in the Execute stage
+            match self.csr_access(&exe_val) {
+                Ok(old) => mem_val.write_back_value = old,
+                Err(_) => mem_val.trap = illegal_instruction,
+            }
+        } else if exe_val.is_ecall {
+            let cause = match self.csr_file.privilege() {
+                PrivilegeMode::User => Exception::EcallFromUMode,
+                PrivilegeMode::Supervisor => {
+                    Exception::EcallFromSMode
+                }
+                PrivilegeMode::Machine => Exception::EcallFromMMode,
+            };
+            mem_val.trap = Some(Trap::new(cause, 0));
+        } else if exe_val.is_sfence_vma {
+            if self.csr_file.privilege() == PrivilegeMode::User
+                || self.csr_file.is_vm_trapped()
+            {
+                mem_val.trap = illegal_instruction;
+            } else {
+                // rs1 = x0 and rs2 = x0 stand for all addresses
+                // and all address spaces respectively
+                let vaddr = (exe_val.rs1_addr != 0)
+                    .then_some(exe_val.rs1);
+                let asid = (exe_val.rs2_addr != 0)
+                    .then_some(exe_val.rs2);
+                self.mmu.flush(vaddr, asid);
+            }
+        } else if exe_val.is_fence_i {
+            self.decode_cache.flush();
+        } else if (mem_val.is_mret
+            && self.csr_file.privilege() != PrivilegeMode::Machine)
+            || (mem_val.is_sret
+                && self.csr_file.privilege() == PrivilegeMode::User)
+        {
+            mem_val.trap = illegal_instruction;
+        }
+
+        if mem_val.trap.is_none() {
+            mem_val.data_access = data_access;
+        }
+
+        mem_val
+    }
+
+    // squashes the instruction in this stage, before it has
+    // made any access
+    pub fn flush(&self) {
+        self.mem_val.replace(MemoryAccessValues::new());
+    }
+}
+
+impl MemoryAccess {
+    // returns the old value of the CSR
+    fn csr_access(
+        &self,
+        exe_val: &ExecutionValues,
+    ) -> Result<u32, CsrError> {
+        let csr_addr = exe_val.imm11_0;
+        let op = CsrOperation::try_from(exe_val.funct3)
+            .expect("Invalid CSR operation");
+        let src = match op {
+            CsrOperation::CSRRWI
+            | CsrOperation::CSRRSI
+            | CsrOperation::CSRRCI => exe_val.rs1_addr, // uimm
+            _ => exe_val.rs1,
+        };
+
+        let is_write = matches!(
+            op,
+            CsrOperation::CSRRW | CsrOperation::CSRRWI
+        );
+        // CSRRW/CSRRWI with x0 as rd must not read, nor cause
+        // the side effects of a read
+        let old = if is_write && exe_val.rd == 0 {
+            0
+        } else {
+            self.csr_file.read(csr_addr)?
+        };
+        // CSRRS/CSRRC with x0 or uimm = 0 must not write
+        let new = match op {
+            CsrOperation::CSRRW | CsrOperation::CSRRWI => Some(src),
+            _ if exe_val.rs1_addr == 0 => None,
+            CsrOperation::CSRRS | CsrOperation::CSRRSI => {
+                Some(old | src)
+            }
+            CsrOperation::CSRRC | CsrOperation::CSRRCI => {
+                Some(old & !src)
+            }
+        };
+        if let Some(new) = new {
+            self.csr_file.write(csr_addr, new)?;
         }
+
+        Ok(old)
     }
+}
+
+impl MemoryAccess {
+    // returns the value written back to rd. A single hart
+    // performs every access in program order, so the aq and
+    // rl bits need no extra fences here
+    fn amo_access(
+        &self,
+        exe_val: &ExecutionValues,
+    ) -> Result<u32, Trap> {
+        let vaddr = exe_val.rs1;
+        let src = exe_val.rs2;
+        let op = AmoOperation::try_from(exe_val.instruction >> 27)
+            .expect("Invalid AMO operation");
 
-    fn should_stall(&self) -> bool {
-        !matches!(self.stage.borrow().to_owned(), Stage::MEM)
+        let access = match op {
+            AmoOperation::LR => AccessType::Load,
+            _ => AccessType::Store,
+        };
+        let addr = self.mmu.translate(vaddr, access)? as usize;
+        let bus_error = |e| {
+            Trap::from_bus_error(e, vaddr)
+                .with_fault(self.bus.log_fault(e))
+        };
+        if access == AccessType::Store {
+            self.decode_cache.invalidate(addr as u32, 4);
+        }
+
+        match op {
+            AmoOperation::LR => {
+                self.bus.load_reserved(addr).map_err(bus_error)
+            }
+            // 0 on success, 1 on failure
+            AmoOperation::SC => self
+                .bus
+                .store_conditional(addr, src)
+                .map(|stored| !stored as u32)
+                .map_err(bus_error),
+            _ => self
+                .bus
+                .amo(addr, |old| match op {
+                    AmoOperation::AMOADD => old.wrapping_add(src),
+                    AmoOperation::AMOXOR => old ^ src,
+                    AmoOperation::AMOOR => old | src,
+                    AmoOperation::AMOAND => old & src,
+                    AmoOperation::AMOMIN => {
+                        (old as i32).min(src as i32) as u32
+                    }
+                    AmoOperation::AMOMAX => {
+                        (old as i32).max(src as i32) as u32
+                    }
+                    AmoOperation::AMOMINU => old.min(src),
+                    AmoOperation::AMOMAXU => old.max(src),
+                    _ => src, // AMOSWAP
+                })
+                .map_err(bus_error),
+        }
+    }
+}
+
+impl MemoryAccess {
+    pub fn set_misaligned_policy(
+        &self,
+        policy: MisalignedPolicy,
+    ) {
+        self.misaligned_policy.set(policy);
+    }
+
+    // every byte is translated before the access goes to the
+    // bus, so a page fault leaves memory untouched. A misaligned
+    // access that is not emulated is left for the bus to
+    // reject.
+    fn translate_access(
+        &self,
+        vaddr: u32,
+        width: MemoryAccessWidth,
+        access: AccessType,
+    ) -> Result<PhysicalAccess, Trap> {
+        let size = width.size();
+        let policy = self.misaligned_policy.get();
+        if (vaddr as usize).is_multiple_of(size)
+            || policy == MisalignedPolicy::Trap
+        {
+            let addr = self.mmu.translate(vaddr, access)?;
+            return Ok(PhysicalAccess::Aligned(addr as usize));
+        }
+
+        (0..size as u32)
+            .map(|i| {
+                let vaddr = vaddr.wrapping_add(i);
+                let addr = self.mmu.translate(vaddr, access)?;
+                Ok(addr as usize)
+            })
+            .collect::<Result<_, _>>()
+            .map(PhysicalAccess::Split)
+    }
+
+    fn read(
+        &self,
+        paddr: &PhysicalAccess,
+        width: MemoryAccessWidth,
+        vaddr: u32,
+    ) -> Result<u32, Trap> {
+        let bus_error = |e| {
+            Trap::from_bus_error(e, vaddr)
+                .with_fault(self.bus.log_fault(e))
+        };
+        match paddr {
+            PhysicalAccess::Aligned(addr) => {
+                self.bus.read(*addr, width).map_err(bus_error)
+            }
+            PhysicalAccess::Split(addrs) => {
+                let mut val = 0;
+                for (i, &addr) in addrs.iter().enumerate() {
+                    let byte = self
+                        .bus
+                        .read(addr, MemoryAccessWidth::Byte)
+                        .map_err(bus_error)?;
+                    val |= byte << (8 * i);
+                }
+                Ok(val)
+            }
+        }
+    }
+
+    fn write(
+        &self,
+        paddr: &PhysicalAccess,
+        val: u32,
+        width: MemoryAccessWidth,
+        vaddr: u32,
+    ) -> Result<(), Trap> {
+        let bus_error = |e| {
+            Trap::from_bus_error(e, vaddr)
+                .with_fault(self.bus.log_fault(e))
+        };
+        match paddr {
+            PhysicalAccess::Aligned(addr) => {
+                let size = width.size() as u32;
+                self.decode_cache.invalidate(*addr as u32, size);
+                self.bus
+                    .write(*addr, val, width)
+                    .map_err(bus_error)
+            }
+            PhysicalAccess::Split(addrs) => {
+                // every byte is checked first, so a fault on
+                // any of them writes none
+                self.check_write(paddr, val, width, vaddr)?;
+                for (i, &addr) in addrs.iter().enumerate() {
+                    let byte = val >> (8 * i);
+                    let width = MemoryAccessWidth::Byte;
+                    self.decode_cache.invalidate(addr as u32, 1);
+                    self.bus
+                        .write(addr, byte, width)
+                        .map_err(bus_error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // faults as write would, but leaves memory untouched
+    fn check_write(
+        &self,
+        paddr: &PhysicalAccess,
+        val: u32,
+        width: MemoryAccessWidth,
+        vaddr: u32,
+    ) -> Result<(), Trap> {
+        let bus_error = |e| {
+            Trap::from_bus_error(e, vaddr)
+                .with_fault(self.bus.log_fault(e))
+        };
+        match paddr {
+            PhysicalAccess::Aligned(addr) => self
+                .bus
+                .check_write(*addr, val, width)
+                .map_err(bus_error),
+            PhysicalAccess::Split(addrs) => {
+                for (i, &addr) in addrs.iter().enumerate() {
+                    let byte = val >> (8 * i);
+                    let width = MemoryAccessWidth::Byte;
+                    self.bus
+                        .check_write(addr, byte, width)
+                        .map_err(bus_error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MemoryAccess {
+    // flw/fld return the loaded value, NaN-boxed for flw.
+    // fld/fsd are done as two word accesses, both translated
+    // before either one goes to the bus. A fault on the high
+    // half reports its own address, and fsd checks both
+    // halves before storing either one
+    fn fp_access(
+        &self,
+        exe_val: &ExecutionValues,
+        vaddr: u32,
+    ) -> Result<u64, Trap> {
+        let is_double = exe_val.funct3 == 0b011;
+        let access = if exe_val.is_fp_store {
+            AccessType::Store
+        } else {
+            AccessType::Load
+        };
+        let vaddr_hi = vaddr.wrapping_add(4);
+
+        let word = MemoryAccessWidth::Word;
+        let addr_lo = self.translate_access(vaddr, word, access)?;
+        let addr_hi = if is_double {
+            Some(self.translate_access(vaddr_hi, word, access)?)
+        } else {
+            None
+        };
+
+        if exe_val.is_fp_store {
+            let val = exe_val.frs2;
+            let hi = (val >> 32) as u32;
+            self.check_write(&addr_lo, val as u32, word, vaddr)?;
+            if let Some(addr_hi) = &addr_hi {
+                self.check_write(addr_hi, hi, word, vaddr_hi)?;
+            }
+            self.write(&addr_lo, val as u32, word, vaddr)?;
+            if let Some(addr_hi) = &addr_hi {
+                self.write(addr_hi, hi, word, vaddr_hi)?;
+            }
+            Ok(0)
+        } else {
+            let lo = self.read(&addr_lo, word, vaddr)?;
+            match &addr_hi {
+                Some(addr_hi) => {
+                    let hi =
+                        self.read(addr_hi, word, vaddr_hi)?;
+                    Ok((hi as u64) << 32 | lo as u64)
+                }
+                None => Ok(fpu::nan_box(lo)),
+            }
+        }
+    }
+}
+
+impl MemoryAccess {
+    fn data_access(
+        exe_val: &ExecutionValues,
+        vaddr: u32,
+    ) -> Option<DataAccess> {
+        let access = |vaddr, is_read, is_write| DataAccess {
+            vaddr,
+            size: 1 << (exe_val.funct3 & 0b11),
+            is_read,
+            is_write,
+        };
+
+        if exe_val.is_load || exe_val.is_fp_load {
+            Some(access(vaddr, true, false))
+        } else if exe_val.is_store || exe_val.is_fp_store {
+            Some(access(vaddr, false, true))
+        } else if exe_val.is_amo {
+            let op =
+                AmoOperation::try_from(exe_val.instruction >> 27);
+            let (is_read, is_write) = match op {
+                Ok(AmoOperation::LR) => (true, false),
+                Ok(AmoOperation::SC) => (false, true),
+                _ => (true, true),
+            };
+            Some(access(exe_val.rs1, is_read, is_write))
+        } else {
+            None
+        }
+    }
+}
+
+impl PipelineStage<ExecutionValues, MemoryAccessValues>
+    for MemoryAccess
+{
+    fn compute(&self, values: ExecutionValues) {
+        self.mem_val.replace(self.access(values));
     }
 
     fn latch_next(&self) {
@@ -1,25 +1,31 @@
+pub mod compressed;
 pub mod decode;
+pub mod decode_cache;
 pub mod execute;
+pub mod hazard;
 pub mod instruction_fetch;
 pub mod memory_access;
 pub mod write_back;
 
-#[derive(Debug, Clone, Copy)]
-pub enum Stage {
-    IF,
-    DE,
-    EXE,
-    MEM,
-    WB,
-}
-
+// every stage works on a different instruction each cycle,
+// an input that is not valid is a bubble and produces a
+// bubble
 pub trait PipelineStage<TI, TO>
 where
     TI: Clone + Copy,
     TO: Clone + Copy,
 {
     fn compute(&self, values: TI);
-    fn should_stall(&self) -> bool;
     fn get_values_out(&self) -> TO;
     fn latch_next(&self);
 }
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipelineStats {
+    pub cycles: u64,
+    pub retired: u64,
+    pub load_use_stalls: u64,
+    // a mispredicted branch or jump in Execute, or a trap,
+    // trap return or serializing instruction in WB
+    pub flushes: u64,
+}
@@ -1,28 +1,157 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    sync::Arc,
+};
 
-use crate::register::RegFile;
+use crate::{
+    csr::CsrFile,
+    register::{FRegFile, RegFile},
+};
 
 use super::{
-    memory_access::MemoryAccessValues, PipelineStage, Stage,
+    instruction_fetch::PCUpdateInfo,
+    memory_access::{DataAccess, MemoryAccessValues},
+    PipelineStage,
 };
 
+// the instruction that left the pipeline this cycle
 #[derive(Debug, Clone, Copy)]
 pub struct WriteBackValues {
-    _dummy: u8,
+    // retired or trapped
+    pub is_valid: bool,
+    pub is_retired: bool,
+    pub pc: u32,
+    // the trap handler, trap return address, branch target
+    // or the following instruction
+    pub next_pc: u32,
+    pub data_access: Option<DataAccess>,
 }
 
-pub struct WriteBack {
-    stage: Arc<RefCell<Stage>>,
+impl WriteBackValues {
+    pub fn new() -> Self {
+        Self {
+            is_valid: false,
+            is_retired: false,
+            pc: 0,
+            next_pc: 0,
+            data_access: None,
+        }
+    }
+}
 
+impl Default for WriteBackValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct WriteBack {
     reg_file: Arc<RegFile>,
+    freg_file: Arc<FRegFile>,
+    csr_file: Arc<CsrFile>,
+
+    // flush after every instruction, so that the next one
+    // starts from the state this one left
+    precise: Cell<bool>,
+    halted: Cell<bool>,
+
+    pc_update_info: RefCell<PCUpdateInfo>,
+
+    wb_val: RefCell<WriteBackValues>,
+    wb_val_ready: RefCell<WriteBackValues>,
 }
 
 impl WriteBack {
     pub fn new(
-        stage: Arc<RefCell<Stage>>,
         reg_file: Arc<RegFile>,
+        freg_file: Arc<FRegFile>,
+        csr_file: Arc<CsrFile>,
     ) -> Self {
-        Self { stage, reg_file }
+        Self {
+            reg_file,
+            freg_file,
+            csr_file,
+
+            precise: Cell::new(false),
+            halted: Cell::new(false),
+
+            pc_update_info: RefCell::new(PCUpdateInfo {
+                should_update: false,
+                pc_new: 0,
+            }),
+
+            wb_val: RefCell::new(WriteBackValues::new()),
+            wb_val_ready: RefCell::new(WriteBackValues::new()),
+        }
+    }
+
+    pub fn set_precise(&self, precise: bool) {
+        self.precise.set(precise);
+    }
+
+    // set once the halt word has reached this stage
+    pub fn is_halted(&self) -> bool {
+        self.halted.get()
+    }
+
+    // registers, traps and trap returns take effect. Also
+    // used on its own by the functional model
+    pub fn commit(
+        &self,
+        mem_val: MemoryAccessValues,
+    ) -> WriteBackValues {
+        let mut wb_val = WriteBackValues::new();
+        if !mem_val.is_valid {
+            return wb_val;
+        }
+        if mem_val.is_halt {
+            self.halted.set(true);
+            return wb_val;
+        }
+
+        wb_val.is_valid = true;
+        wb_val.pc = mem_val.pc;
+
+        if let Some(trap) = mem_val.trap {
+            wb_val.next_pc =
+                self.csr_file.trap_enter(mem_val.pc, trap);
+            return wb_val;
+        }
+
+        wb_val.next_pc = if mem_val.is_mret {
+            self.csr_file.trap_return()
+        } else if mem_val.is_sret {
+            self.csr_file.supervisor_trap_return()
+        } else {
+            mem_val.next_pc
+        };
+
+        if mem_val.writes_int_reg() {
+            self.reg_file.borrow_mut()[mem_val.rd as usize].0 =
+                mem_val.write_back_value;
+        }
+
+        // f0 is an ordinary register, unlike x0
+        if mem_val.writes_fp_reg {
+            self.freg_file.borrow_mut()[mem_val.rd as usize].0 =
+                mem_val.fp_write_back_value;
+            self.csr_file.set_fp_dirty();
+        }
+        if mem_val.fflags != 0 {
+            self.csr_file.accrue_fflags(mem_val.fflags);
+        }
+
+        self.csr_file.retire();
+        wb_val.is_retired = true;
+        wb_val.data_access = mem_val.data_access;
+        wb_val
+    }
+
+    // traps, trap returns and serializing instructions are
+    // resolved here, once every older instruction has
+    // completed. Everything younger is squashed
+    pub fn get_pc_update_info(&self) -> PCUpdateInfo {
+        self.pc_update_info.borrow().to_owned()
     }
 }
 
@@ -30,43 +159,28 @@ impl PipelineStage<MemoryAccessValues, WriteBackValues>
     for WriteBack
 {
     fn compute(&self, values: MemoryAccessValues) {
-        if self.should_stall() {
-            return;
-        }
-
         let mem_val = values;
+        let wb_val = self.commit(mem_val);
 
-        let write_back_value = mem_val.write_back_value;
-        let rd = mem_val.rd;
-        let is_alu_operation = mem_val.is_alu_operation;
-        let is_load = mem_val.is_load;
-        let is_lui = mem_val.is_lui;
-        let is_jal = mem_val.is_jal;
-        let is_jalr = mem_val.is_jalr;
-        let is_auipc = mem_val.is_auipc;
-
-        let should_write_back = is_alu_operation
-            | is_load
-            | is_lui
-            | is_jal
-            | is_jalr
-            | is_auipc;
-
-        if should_write_back {
-            if rd != 0 {
-                self.reg_file.borrow_mut()[rd as usize].0 =
-                    write_back_value;
-            }
-        }
-    }
+        let mut pc_update_info =
+            self.pc_update_info.borrow_mut();
+        pc_update_info.should_update = wb_val.is_valid
+            && (!wb_val.is_retired
+                || mem_val.is_mret
+                || mem_val.is_sret
+                || mem_val.is_serializing
+                || self.precise.get());
+        pc_update_info.pc_new = wb_val.next_pc;
 
-    fn should_stall(&self) -> bool {
-        !matches!(self.stage.borrow().to_owned(), Stage::WB)
+        self.wb_val.replace(wb_val);
     }
 
-    fn latch_next(&self) {}
+    fn latch_next(&self) {
+        self.wb_val_ready
+            .replace(self.wb_val.borrow().to_owned());
+    }
 
     fn get_values_out(&self) -> WriteBackValues {
-        WriteBackValues { _dummy: 0 }
+        self.wb_val_ready.borrow().to_owned()
     }
 }
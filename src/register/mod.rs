@@ -6,3 +6,9 @@ pub const NUM_REGISTER: usize = 32;
 pub struct Register32(pub u32);
 
 pub type RegFile = RefCell<[Register32; NUM_REGISTER]>;
+
+// f0 - f31, wide enough for double precision
+#[derive(Debug, Clone, Copy)]
+pub struct FRegister64(pub u64);
+
+pub type FRegFile = RefCell<[FRegister64; NUM_REGISTER]>;
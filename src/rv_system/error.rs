@@ -0,0 +1,88 @@
+use crate::{bus::bus_error::BusError, trap::Trap};
+use std::fmt;
+
+// why the system has stopped before the guest asked it to,
+// with the pc and the bits of the instruction at fault (0
+// when it could not be fetched)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatorError {
+    // an access fault, under AccessFaultPolicy::Halt
+    Bus {
+        pc: u32,
+        instruction: u32,
+        error: BusError,
+    },
+    // an illegal instruction with no usable trap handler
+    Decode { pc: u32, instruction: u32 },
+    // any other trap whose handler cannot be entered: the
+    // vector is unset or unmapped, or the first instruction
+    // there faults or halts
+    Unhandled {
+        pc: u32,
+        instruction: u32,
+        trap: Trap,
+    },
+    // any other trap taken again and again at the start of
+    // its own handler, the hart can never get past it
+    TrapLoop {
+        pc: u32,
+        instruction: u32,
+        trap: Trap,
+    },
+}
+
+impl EmulatorError {
+    pub fn get_pc(&self) -> u32 {
+        match *self {
+            EmulatorError::Bus { pc, .. }
+            | EmulatorError::Decode { pc, .. }
+            | EmulatorError::Unhandled { pc, .. }
+            | EmulatorError::TrapLoop { pc, .. } => pc,
+        }
+    }
+
+    pub fn get_instruction(&self) -> u32 {
+        match *self {
+            EmulatorError::Bus { instruction, .. }
+            | EmulatorError::Decode { instruction, .. }
+            | EmulatorError::Unhandled {
+                instruction, ..
+            }
+            | EmulatorError::TrapLoop {
+                instruction, ..
+            } => instruction,
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::Bus { error, .. } => {
+                write!(f, "{}", error)?
+            }
+            EmulatorError::Decode { .. } => write!(
+                f,
+                "Illegal instruction with no usable trap handler"
+            )?,
+            EmulatorError::Unhandled { trap, .. } => write!(
+                f,
+                "{} with no usable trap handler",
+                trap
+            )?,
+            EmulatorError::TrapLoop { trap, .. } => write!(
+                f,
+                "{} in its own trap handler",
+                trap
+            )?,
+        }
+        write!(
+            f,
+            ", pc = {:#010x}, instruction = {:#010x}",
+            self.get_pc(),
+            self.get_instruction()
+        )
+    }
+}
@@ -1,21 +1,127 @@
-use std::{cell::RefCell, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+};
+
+use self::error::EmulatorError;
 
 use crate::{
-    bus::Bus,
+    branch_predictor::{BranchPredictor, BranchStats},
+    bus::{
+        bus_error::MapError,
+        clint::{Clint, CLINT_SIZE, CLINT_START},
+        mmio_device::{IrqLine, MMIODevice},
+        plic::{
+            Plic, CONTEXT_MACHINE, CONTEXT_SUPERVISOR,
+            PLIC_SIZE, PLIC_START,
+        },
+        test_finisher::{
+            TestFinisher, TEST_FINISHER_SIZE,
+            TEST_FINISHER_START,
+        },
+        uart::{UARTBackend, UARTDevice, UART_SIZE},
+        AccessFaultPolicy, Bus, ROM_START,
+    },
+    csr::CsrFile,
+    dbt::{Dbt, DbtStats},
+    elf::{ElfError, ElfFile, SymbolTable},
+    mmu::{tlb::TlbStats, Mmu},
     pipeline::{
-        decode::Decode, execute::Execute,
-        instruction_fetch::InstructionFetch,
-        memory_access::MemoryAccess, write_back::WriteBack,
-        PipelineStage, Stage,
+        decode::Decode,
+        decode_cache::{DecodeCache, DecodeCacheStats},
+        execute::Execute,
+        hazard,
+        instruction_fetch::{InstructionFetch, PCUpdateInfo},
+        memory_access::{
+            DataAccess, MemoryAccess, MemoryAccessValues,
+            MemoryAccessWidth, MisalignedPolicy,
+        },
+        write_back::WriteBack,
+        PipelineStage, PipelineStats,
     },
-    register::{RegFile, Register32, NUM_REGISTER},
+    register::{
+        FRegFile, FRegister64, RegFile, Register32,
+        NUM_REGISTER,
+    },
+    trap::{Exception, Interrupt, Trap},
 };
 
-pub struct RV32System {
-    stage: Arc<RefCell<Stage>>,
+pub mod error;
+
+// how instructions are run, both go through the same stage
+// logic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+    // cycle by cycle, overlapped in the 5-stage pipeline
+    Pipelined,
+    // one instruction at a time, with no timing model
+    Functional,
+    // functional, with hot basic blocks translated into
+    // closures
+    Translated,
+}
+
+// why the guest has stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    // the all-zero halt word was fetched
+    HaltWord,
+    // with the exit code the guest wrote to the test finisher
+    // or to tohost
+    Exit(u32),
+}
+
+// what run() returns once the guest has stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunSummary {
+    pub reason: HaltReason,
+    // of the next instruction, had the system gone on
+    pub pc: u32,
+    pub retired: u64,
+    pub cycles: u64,
+}
+
+// a trap the hart has taken, and the handler it has entered
+#[derive(Clone, Copy)]
+struct TakenTrap {
+    pc: u32,
+    instruction: u32,
+    trap: Trap,
+    handler: u32,
+}
+
+impl TakenTrap {
+    // the fault ids of two traps always differ
+    fn is_same(&self, other: &TakenTrap) -> bool {
+        (self.pc, self.trap.cause, self.trap.tval)
+            == (other.pc, other.trap.cause, other.trap.tval)
+    }
+}
 
+pub struct RV32System {
     bus: Arc<Bus>,
     reg_file: Arc<RegFile>,
+    freg_file: Arc<FRegFile>,
+    csr_file: Arc<CsrFile>,
+    mmu: Arc<Mmu>,
+    decode_cache: Arc<DecodeCache>,
+    clint: Rc<Clint>,
+    plic: Rc<Plic>,
+    finisher: Rc<TestFinisher>,
+
+    // empty unless loaded from an ELF file
+    symbols: SymbolTable,
+    // set once the system cannot go on
+    error: Cell<Option<EmulatorError>>,
+    // the last instruction to trap, as long as nothing has
+    // retired since
+    last_trap: Cell<Option<TakenTrap>>,
+    // the exit requested by a store that has completed
+    exit_code: Cell<Option<u32>>,
+    stats: RefCell<PipelineStats>,
+    mode: Cell<ExecutionMode>,
+    dbt: Dbt,
 
     stage_if: InstructionFetch,
     stage_de: Decode,
@@ -26,26 +132,125 @@ pub struct RV32System {
 
 impl RV32System {
     pub fn new(rom_file: &[u32]) -> Self {
-        let stage = Arc::new(RefCell::new(Stage::IF));
-        let bus = Arc::new(Bus::new(rom_file));
+        Self::with_bus(Bus::new(rom_file), ROM_START as u32)
+    }
+
+    pub fn with_mode(self, mode: ExecutionMode) -> Self {
+        self.set_execution_mode(mode);
+        self
+    }
+
+    // PT_LOAD segments go to RAM, execution starts at e_entry
+    pub fn from_elf(elf: &ElfFile) -> Result<Self, ElfError> {
+        let bus = Bus::new(&[]);
+        for segment in &elf.segments {
+            let addr = segment.paddr as usize;
+            let size = segment.mem_size as usize;
+            if !bus.is_ram(addr, size) {
+                return Err(ElfError::SegmentOutOfMemory(
+                    segment.paddr,
+                    segment.mem_size,
+                ));
+            }
+            bus.load(addr, &segment.data, size)
+                .expect("byte accesses are always aligned");
+        }
+
+        // riscv-tests style programs exit through it
+        if let Some(tohost) = elf.symbols.find("tohost") {
+            bus.set_tohost(tohost.addr as usize);
+        }
+
+        let mut rv32_sys = Self::with_bus(bus, elf.entry);
+        rv32_sys.symbols = elf.symbols.clone();
+        Ok(rv32_sys)
+    }
+
+    // the stages share the state through Arc, although all
+    // of them run on one thread
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn with_bus(bus: Bus, entry_point: u32) -> Self {
+        let bus = Arc::new(bus);
         let reg_file = Arc::new(RefCell::new(
             [Register32(0); NUM_REGISTER],
         ));
+        let freg_file = Arc::new(RefCell::new(
+            [FRegister64(0); NUM_REGISTER],
+        ));
+        let csr_file = Arc::new(CsrFile::new());
+        let mmu = Arc::new(Mmu::new(bus.clone(), csr_file.clone()));
+        let decode_cache = Arc::new(DecodeCache::new());
 
-        let stage_if =
-            InstructionFetch::new(stage.clone(), bus.clone());
+        let clint = Rc::new(Clint::new());
+        bus.register(
+            "clint",
+            CLINT_START,
+            CLINT_SIZE,
+            Box::new(clint.clone()),
+        )
+        .expect("CLINT overlaps memory");
+        let plic = Rc::new(Plic::new());
+        bus.register(
+            "plic",
+            PLIC_START,
+            PLIC_SIZE,
+            Box::new(plic.clone()),
+        )
+        .expect("PLIC overlaps memory");
+        let finisher = Rc::new(TestFinisher::new());
+        bus.register(
+            "test_finisher",
+            TEST_FINISHER_START,
+            TEST_FINISHER_SIZE,
+            Box::new(finisher.clone()),
+        )
+        .expect("test finisher overlaps memory");
+
+        let stage_if = InstructionFetch::new(
+            bus.clone(),
+            mmu.clone(),
+            decode_cache.clone(),
+            entry_point,
+        );
         let stage_de =
-            Decode::new(stage.clone(), reg_file.clone());
-        let stage_exe = Execute::new(stage.clone());
-        let stage_mem =
-            MemoryAccess::new(stage.clone(), bus.clone());
-        let stage_wb =
-            WriteBack::new(stage.clone(), reg_file.clone());
+            Decode::new(reg_file.clone(), freg_file.clone());
+        let stage_exe = Execute::new(csr_file.clone());
+        let stage_mem = MemoryAccess::new(
+            bus.clone(),
+            csr_file.clone(),
+            mmu.clone(),
+            decode_cache.clone(),
+        );
+        let stage_wb = WriteBack::new(
+            reg_file.clone(),
+            freg_file.clone(),
+            csr_file.clone(),
+        );
+        let dbt = Dbt::new(
+            bus.clone(),
+            mmu.clone(),
+            reg_file.clone(),
+            decode_cache.clone(),
+        );
 
         Self {
-            stage,
             bus,
             reg_file,
+            freg_file,
+            csr_file,
+            mmu,
+            decode_cache,
+            clint,
+            plic,
+            finisher,
+
+            symbols: SymbolTable::default(),
+            error: Cell::new(None),
+            last_trap: Cell::new(None),
+            exit_code: Cell::new(None),
+            stats: RefCell::new(PipelineStats::default()),
+            mode: Cell::new(ExecutionMode::Pipelined),
+            dbt,
 
             stage_if,
             stage_de,
@@ -55,27 +260,124 @@ impl RV32System {
         }
     }
 
-    pub fn run(&self) {
+    // in the pipelined mode instructions overlap, one may
+    // start before the one ahead of it has completed
+    pub fn run(&self) -> Result<RunSummary, EmulatorError> {
+        match self.mode.get() {
+            ExecutionMode::Pipelined => {
+                self.stage_wb.set_precise(false);
+                while self.cycle() {}
+            }
+            ExecutionMode::Functional => while self.execute() {},
+            ExecutionMode::Translated => {
+                while self.translated() {}
+            }
+        }
+
+        if let Some(error) = self.get_error() {
+            return Err(error);
+        }
+        let stats = self.get_pipeline_stats();
+        Ok(RunSummary {
+            reason: self
+                .get_halt_reason()
+                .expect("only a halted system stops running"),
+            pc: self.get_pc(),
+            retired: stats.retired,
+            cycles: stats.cycles,
+        })
+    }
+
+    // runs until one instruction has retired or trapped and
+    // squashes everything behind it, false once the system
+    // has halted
+    pub fn step(&self) -> bool {
+        // translated blocks are stepped through by the
+        // interpreter
+        if self.mode.get() != ExecutionMode::Pipelined {
+            return self.execute();
+        }
+
+        self.stage_wb.set_precise(true);
         loop {
-            if self.stage_if.should_halt() {
-                break;
+            if !self.cycle() {
+                return false;
+            }
+            if self.stage_wb.get_values_out().is_valid {
+                return true;
+            }
+        }
+    }
+
+    // steps until the next instruction is the one at pc,
+    // false if the system halts first
+    pub fn run_until(&self, pc: u32) -> bool {
+        while self.get_pc() != pc {
+            if !self.step() {
+                return false;
             }
+        }
+        true
+    }
+
+    // may be switched between instructions, e.g. to get
+    // through boot code quickly and only model the timing
+    // of the part of interest
+    pub fn set_execution_mode(&self, mode: ExecutionMode) {
+        if mode != self.mode.get() {
+            // nothing in flight has had any effect yet
+            self.set_pc(self.get_pc());
+            self.mode.set(mode);
+        }
+    }
+
+    pub fn get_execution_mode(&self) -> ExecutionMode {
+        self.mode.get()
+    }
+
+    // mtime advances once every n retired instructions
+    pub fn set_time_base(&self, n: u64) {
+        self.clint.set_time_base(n);
+    }
 
-            self.compute();
-            self.latch_next();
+    pub fn is_halted(&self) -> bool {
+        self.get_error().is_some()
+            || self.get_halt_reason().is_some()
+    }
 
-            let current_stage = self.stage.borrow().to_owned();
-            let next_stage = match current_stage {
-                Stage::IF => Stage::DE,
-                Stage::DE => Stage::EXE,
-                Stage::EXE => Stage::MEM,
-                Stage::MEM => Stage::WB,
-                Stage::WB => Stage::IF,
-            };
-            self.stage.replace(next_stage);
+    pub fn get_halt_reason(&self) -> Option<HaltReason> {
+        if let Some(code) = self.exit_code.get() {
+            Some(HaltReason::Exit(code))
+        } else {
+            self.stage_wb
+                .is_halted()
+                .then_some(HaltReason::HaltWord)
         }
     }
 
+    // off by default, the all-zero word is the illegal
+    // instruction the spec defines. Older payloads written
+    // for the emulator end with it
+    pub fn set_halt_word(&self, enabled: bool) {
+        self.stage_de.set_halt_word(enabled);
+    }
+
+    pub fn set_fault_policy(&self, policy: AccessFaultPolicy) {
+        self.bus.set_fault_policy(policy);
+    }
+
+    pub fn set_misaligned_policy(
+        &self,
+        policy: MisalignedPolicy,
+    ) {
+        self.stage_mem.set_misaligned_policy(policy);
+    }
+
+    // set once the system has stopped on an error
+    pub fn get_error(&self) -> Option<EmulatorError> {
+        self.error.get()
+    }
+
     pub fn reg_dump(&self) {
         println!("Register Dump");
         for (i, reg) in
@@ -94,18 +396,498 @@ impl RV32System {
         self.bus.get_mem(size)
     }
 
+    pub fn register_device(
+        &self,
+        name: &str,
+        base: usize,
+        size: usize,
+        device: Box<dyn MMIODevice>,
+    ) -> Result<(), MapError> {
+        self.bus.register(name, base, size, device)
+    }
+
+    // the PLIC source a device raises and lowers, 1 to 31
+    pub fn irq_line(&self, source: u32) -> IrqLine {
+        IrqLine::new(self.plic.clone(), source)
+    }
+
+    pub fn attach_uart(
+        &self,
+        base: usize,
+        backend: Box<dyn UARTBackend>,
+    ) -> Result<(), MapError> {
+        let uart = Box::new(UARTDevice::new(backend));
+        self.register_device("uart0", base, UART_SIZE, uart)
+    }
+
     pub fn get_reg(&self) -> [Register32; NUM_REGISTER] {
         self.reg_file.borrow().to_owned()
     }
 
-    fn compute(&self) {
-        let pc_update_info = self.stage_exe.get_pc_update_info();
+    pub fn get_freg(&self) -> [FRegister64; NUM_REGISTER] {
+        self.freg_file.borrow().to_owned()
+    }
+
+    pub fn set_reg(&self, idx: usize, val: u32) {
+        if idx != 0 {
+            self.reg_file.borrow_mut()[idx].0 = val;
+        }
+    }
+
+    pub fn set_freg(&self, idx: usize, val: u64) {
+        self.freg_file.borrow_mut()[idx].0 = val;
+    }
+
+    // the pc of the oldest instruction in flight, or of the
+    // next one to be fetched
+    pub fn get_pc(&self) -> u32 {
+        let mem_val = self.stage_mem.get_values_out();
+        let exe_val = self.stage_exe.get_values_out();
+        let de_val = self.stage_de.get_values_out();
+        let if_val = self.stage_if.get_values_out();
+
+        [
+            (mem_val.is_valid, mem_val.pc),
+            (exe_val.is_valid, exe_val.pc),
+            (de_val.is_valid, de_val.pc),
+            (if_val.is_valid, if_val.pc),
+        ]
+        .into_iter()
+        .find_map(|(is_valid, pc)| is_valid.then_some(pc))
+        .unwrap_or_else(|| self.stage_if.get_pc())
+    }
+
+    // everything in flight is squashed
+    pub fn set_pc(&self, pc: u32) {
+        self.flush(PCUpdateInfo {
+            should_update: true,
+            pc_new: pc,
+        });
+        self.latch_next();
+    }
+
+    pub fn read_csr(&self, addr: u32) -> Option<u32> {
+        self.csr_file.read(addr).ok()
+    }
+
+    pub fn write_csr(&self, addr: u32, val: u32) -> bool {
+        self.csr_file.write(addr, val).is_ok()
+    }
+
+    // memory as the debugger sees it, translated without side
+    // effects
+    pub fn debug_read(&self, vaddr: u32) -> Option<u8> {
+        let addr = self.mmu.debug_translate(vaddr)? as usize;
+        self.bus
+            .read(addr, MemoryAccessWidth::Byte)
+            .ok()
+            .map(|val| val as u8)
+    }
+
+    pub fn debug_write(&self, vaddr: u32, val: u8) -> bool {
+        self.mmu.debug_translate(vaddr).is_some_and(|addr| {
+            // e.g. a software breakpoint
+            self.decode_cache.invalidate(addr, 1);
+            self.bus
+                .write(
+                    addr as usize,
+                    val as u32,
+                    MemoryAccessWidth::Byte,
+                )
+                .is_ok()
+        })
+    }
+
+    // the load, store or AMO of the last instruction
+    pub fn get_data_access(&self) -> Option<DataAccess> {
+        self.stage_wb.get_values_out().data_access
+    }
+
+    pub fn get_symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn get_tlb_stats(&self) -> TlbStats {
+        self.mmu.get_tlb_stats()
+    }
+
+    pub fn get_decode_cache_stats(&self) -> DecodeCacheStats {
+        self.decode_cache.get_stats()
+    }
+
+    pub fn get_dbt_stats(&self) -> DbtStats {
+        self.dbt.get_stats()
+    }
+
+    pub fn get_pipeline_stats(&self) -> PipelineStats {
+        self.stats.borrow().to_owned()
+    }
+
+    // not-taken unless set
+    pub fn set_branch_predictor(
+        &self,
+        predictor: Box<dyn BranchPredictor>,
+    ) {
+        self.stage_if.set_branch_predictor(predictor);
+    }
+
+    pub fn get_branch_stats(&self) -> BranchStats {
+        self.stage_if.get_branch_stats()
+    }
+
+    // one clock cycle, false once the system has halted
+    fn cycle(&self) -> bool {
+        if self.is_halted() {
+            return false;
+        }
+
+        let mem_val = self.stage_mem.get_values_out();
+        if self.halt_on_access_fault(&mem_val) {
+            return false;
+        }
+
+        // from the oldest instruction to the youngest, a flush
+        // squashes the younger ones before they do anything
+        self.stage_wb.compute(mem_val);
+        let wb_update = self.stage_wb.get_pc_update_info();
+        self.check_trap(&mem_val, wb_update.pc_new);
+        if self.stage_wb.is_halted() {
+            return false;
+        }
+
+        let mut stats = self.stats.borrow_mut();
+        let exe_val = self.stage_exe.get_values_out();
+        if self.commit_exit() {
+            // the store asking for it has just completed,
+            // everything younger is squashed
+            self.flush(PCUpdateInfo {
+                should_update: true,
+                pc_new: wb_update.pc_new,
+            });
+        } else if wb_update.should_update {
+            self.flush(wb_update);
+            stats.flushes += 1;
+        } else if let Some(handler) = exe_val
+            .is_valid
+            .then(|| self.take_interrupt(exe_val.pc))
+            .flatten()
+        {
+            // everything older has completed, the
+            // instruction about to enter MEM has no effect
+            // yet
+            self.flush(PCUpdateInfo {
+                should_update: true,
+                pc_new: handler,
+            });
+            stats.flushes += 1;
+        } else {
+            self.stage_mem.compute(exe_val);
+            // resolved by Execute last cycle
+            self.stage_if.resolve(&exe_val);
+
+            // EX/MEM and MEM/WB forwarding
+            let de_val = hazard::forward(
+                self.stage_de.get_values_out(),
+                &[mem_val.reg_write(), exe_val.reg_write()],
+            );
+            self.stage_exe.compute(de_val);
+
+            let exe_update = self.stage_exe.get_pc_update_info();
+            if exe_update.should_update {
+                self.stage_de.flush();
+                self.stage_if.compute(exe_update);
+                stats.flushes += 1;
+            } else {
+                let if_val = self.stage_if.get_values_out();
+                self.stage_de.compute(if_val);
+                if self.stage_de.is_stalled() {
+                    stats.load_use_stalls += 1;
+                } else {
+                    self.stage_if.compute(exe_update);
+                }
+            }
+        }
+
+        self.latch_next();
+        self.csr_file.tick();
+
+        stats.cycles += 1;
+        if self.stage_wb.get_values_out().is_retired {
+            stats.retired += 1;
+            self.tick_clint(1);
+        }
+        true
+    }
+
+    // one instruction from fetch to write back, false once
+    // the system has halted
+    fn execute(&self) -> bool {
+        if self.is_halted() {
+            return false;
+        }
+
+        let pc = self.stage_if.get_pc();
+        if self.interrupt(pc) {
+            return true;
+        }
+
+        let if_val = self.stage_if.fetch_at(pc);
+        let de_val = self.stage_de.decode(if_val);
+        let exe_val = self.stage_exe.execute(de_val);
+        let mem_val = self.stage_mem.access(exe_val);
+        if self.halt_on_access_fault(&mem_val) {
+            return false;
+        }
+
+        self.stage_wb.compute(mem_val);
+        self.stage_wb.latch_next();
+        let wb_val = self.stage_wb.get_values_out();
+        self.check_trap(&mem_val, wb_val.next_pc);
+        if self.stage_wb.is_halted() {
+            return false;
+        }
+
+        self.stage_if.compute(PCUpdateInfo {
+            should_update: true,
+            pc_new: wb_val.next_pc,
+        });
+        self.csr_file.tick();
+        self.tick_clint(wb_val.is_retired as u64);
+        self.commit_exit();
+
+        let mut stats = self.stats.borrow_mut();
+        stats.cycles += 1;
+        stats.retired += wb_val.is_retired as u64;
+        true
+    }
+
+    // the translated blocks at pc, or else one instruction
+    // interpreted, false once the system has halted
+    fn translated(&self) -> bool {
+        if self.is_halted() {
+            return false;
+        }
+
+        let pc = self.stage_if.get_pc();
+        if self.interrupt(pc) {
+            return true;
+        }
+
+        // stops where the timer interrupt becomes pending
+        let budget = self.clint.instructions_until_timer();
+        let (retired, next_pc) = match self.dbt.run(pc, budget)
+        {
+            Some((retired, next_pc)) if retired > 0 => {
+                (retired, next_pc)
+            }
+            // also when a block exits at its first instruction
+            _ => return self.execute(),
+        };
+
+        self.stage_if.compute(PCUpdateInfo {
+            should_update: true,
+            pc_new: next_pc,
+        });
+        for _ in 0..retired {
+            self.csr_file.tick();
+            self.csr_file.retire();
+        }
+        self.last_trap.set(None);
+        self.tick_clint(retired);
+        self.commit_exit();
+
+        let mut stats = self.stats.borrow_mut();
+        stats.cycles += retired;
+        stats.retired += retired;
+        true
+    }
+
+    // true once a store to the test finisher or to tohost
+    // has completed
+    fn commit_exit(&self) -> bool {
+        let exit_code = self
+            .finisher
+            .get_exit_code()
+            .or_else(|| self.bus.get_tohost_exit());
+        self.exit_code.set(exit_code);
+        exit_code.is_some()
+    }
+
+    fn tick_clint(&self, retired: u64) {
+        self.clint.advance(retired);
+        self.csr_file.set_time(self.clint.get_mtime());
+    }
+
+    // enters the handler of a pending and enabled interrupt
+    // instead of running the instruction at pc
+    fn take_interrupt(&self, pc: u32) -> Option<u32> {
+        let clint = &self.clint;
+        self.csr_file.set_irq_line(
+            Interrupt::MachineTimer,
+            clint.is_timer_pending(),
+        );
+        self.csr_file.set_irq_line(
+            Interrupt::MachineSoftware,
+            clint.is_software_pending(),
+        );
+        let plic = &self.plic;
+        self.csr_file.set_irq_line(
+            Interrupt::MachineExternal,
+            plic.is_interrupting(CONTEXT_MACHINE),
+        );
+        self.csr_file.set_irq_line(
+            Interrupt::SupervisorExternal,
+            plic.is_interrupting(CONTEXT_SUPERVISOR),
+        );
+
+        let interrupt = self.csr_file.pending_interrupt()?;
+        self.last_trap.set(None);
+        Some(self.csr_file.interrupt_enter(pc, interrupt))
+    }
+
+    // between two instructions of the functional models
+    fn interrupt(&self, pc: u32) -> bool {
+        match self.take_interrupt(pc) {
+            Some(handler) => {
+                self.stage_if.compute(PCUpdateInfo {
+                    should_update: true,
+                    pc_new: handler,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    // stop before WB takes the trap
+    fn halt_on_access_fault(
+        &self,
+        mem_val: &MemoryAccessValues,
+    ) -> bool {
+        if !mem_val.is_valid
+            || self.bus.get_fault_policy()
+                != AccessFaultPolicy::Halt
+        {
+            return false;
+        }
 
+        match mem_val
+            .trap
+            .filter(|trap| trap.cause.is_access_fault())
+            .and_then(|trap| trap.fault)
+            .and_then(|fault| self.bus.get_fault(fault))
+        {
+            Some(error) => {
+                self.error.set(Some(EmulatorError::Bus {
+                    pc: mem_val.pc,
+                    instruction: mem_val.instruction,
+                    error,
+                }));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the handler of the last trap cannot be entered when
+    // its first instruction cannot be fetched or decoded and
+    // traps back to it, or halts. The same trap taken twice
+    // in a row at the start of its own handler is taken there
+    // forever. Either way the system halts
+    fn check_trap(
+        &self,
+        mem_val: &MemoryAccessValues,
+        next_pc: u32,
+    ) {
+        if !mem_val.is_valid {
+            return;
+        }
+        let pc = mem_val.pc;
+        let last_trap = self
+            .last_trap
+            .take()
+            .filter(|last_trap| last_trap.handler == pc);
+        let Some(trap) = mem_val.trap else {
+            if let Some(last_trap) =
+                last_trap.filter(|_| mem_val.is_halt)
+            {
+                let error = self.trap_error(&last_trap, false);
+                self.error.set(Some(error));
+            }
+            return;
+        };
+
+        let this_trap = TakenTrap {
+            pc,
+            instruction: mem_val.instruction,
+            trap,
+            handler: next_pc,
+        };
+        self.last_trap.set(Some(this_trap));
+        let Some(last_trap) = last_trap else {
+            return;
+        };
+        if next_pc != pc {
+            return;
+        }
+
+        if trap.cause == Exception::IllegalInstruction
+            || trap.cause.is_fetch_fault()
+        {
+            let error = self.trap_error(&last_trap, false);
+            self.error.set(Some(error));
+        } else if last_trap.is_same(&this_trap) {
+            let error = self.trap_error(&this_trap, true);
+            self.error.set(Some(error));
+        }
+    }
+
+    // the error reporting a trap the hart cannot get past
+    fn trap_error(
+        &self,
+        taken: &TakenTrap,
+        is_loop: bool,
+    ) -> EmulatorError {
+        let TakenTrap {
+            pc,
+            instruction,
+            trap,
+            ..
+        } = *taken;
+        let fault = trap
+            .fault
+            .and_then(|fault| self.bus.get_fault(fault));
+        match (trap.cause, fault) {
+            (Exception::IllegalInstruction, _) => {
+                EmulatorError::Decode { pc, instruction }
+            }
+            (cause, Some(error)) if cause.is_access_fault() => {
+                EmulatorError::Bus {
+                    pc,
+                    instruction,
+                    error,
+                }
+            }
+            _ if is_loop => EmulatorError::TrapLoop {
+                pc,
+                instruction,
+                trap,
+            },
+            _ => EmulatorError::Unhandled {
+                pc,
+                instruction,
+                trap,
+            },
+        }
+    }
+
+    // squashes every instruction younger than the one in WB,
+    // fetching resumes at the new pc
+    fn flush(&self, pc_update_info: PCUpdateInfo) {
+        self.stage_mem.flush();
+        self.stage_exe.flush();
+        self.stage_de.flush();
         self.stage_if.compute(pc_update_info);
-        self.stage_de.compute(self.stage_if.get_values_out());
-        self.stage_exe.compute(self.stage_de.get_values_out());
-        self.stage_mem.compute(self.stage_exe.get_values_out());
-        self.stage_wb.compute(self.stage_mem.get_values_out());
     }
 
     fn latch_next(&self) {
@@ -116,3 +898,1339 @@ impl RV32System {
         self.stage_wb.latch_next();
     }
 }
+
+// the test programs end with the all-zero word, which only
+// halts the system when enabled
+#[cfg(test)]
+fn new_halting(rom_file: &[u32]) -> RV32System {
+    let rv32_sys = RV32System::new(rom_file);
+    rv32_sys.set_halt_word(true);
+    rv32_sys
+}
+
+#[cfg(test)]
+#[test]
+fn test_mul_div() {
+    let rom_file = [
+        0x00700593_u32, // li a1, 7
+        0xffd00613,     // li a2, -3
+        0x02c58533,     // mul a0, a1, a2
+        0x02c5c6b3,     // div a3, a1, a2
+        0x02c5e733,     // rem a4, a1, a2
+        0x02c637b3,     // mulhu a5, a2, a2
+        0x0205d833,     // divu a6, a1, zero
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, -21_i32 as u32);
+    assert_eq!(reg[13].0, -2_i32 as u32);
+    assert_eq!(reg[14].0, 1);
+    assert_eq!(reg[15].0, 0xffff_fffa);
+    assert_eq!(reg[16].0, u32::MAX);
+}
+
+#[cfg(test)]
+#[test]
+fn test_csr() {
+    let rom_file = [
+        0x05500593_u32, // li a1, 0x55
+        0x34059573,     // csrrw a0, mscratch, a1
+        0x34056673,     // csrrsi a2, mscratch, 0xa
+        0x3405b6f3,     // csrrc a3, mscratch, a1
+        0x34002773,     // csrr a4, mscratch
+        0xc02027f3,     // rdinstret a5
+        0xc0002873,     // rdcycle a6
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 0);
+    assert_eq!(reg[12].0, 0x55);
+    assert_eq!(reg[13].0, 0x5f);
+    assert_eq!(reg[14].0, 0x0a);
+    assert_eq!(reg[15].0, 5);
+    assert!(reg[16].0 > reg[15].0);
+
+    // csrw is csrrw with x0 as rd, which does not read
+    let rom_file = [
+        0x05500593_u32, // li a1, 0x55
+        0x34059073,     // csrw mscratch, a1
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    assert_eq!(rv32_sys.csr_file.get_reads(), 0);
+    assert_eq!(rv32_sys.read_csr(0x340), Some(0x55));
+}
+
+#[cfg(test)]
+#[test]
+fn test_trap() {
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x04028293,     // addi t0, t0, 0x40
+        0x30529073,     // csrw mtvec, t0
+        0x00000073,     // ecall
+        0x00058493,     // mv s1, a1
+        0x80000337,     // lui t1, 0x80000
+        0x00132383,     // lw t2, 1(t1)
+        0x00058913,     // mv s2, a1
+        0x00060993,     // mv s3, a2
+        0xffffffff,     // illegal instruction
+        0x00058a13,     // mv s4, a1
+        0x00060a93,     // mv s5, a2
+        0x00100073,     // ebreak
+        0x00058b13,     // mv s6, a1
+        0x00060b93,     // mv s7, a2
+        0,
+        // handler: count traps and skip the faulting instruction
+        0x00150513, // addi a0, a0, 1
+        0x342025f3, // csrr a1, mcause
+        0x34302673, // csrr a2, mtval
+        0x34102e73, // csrr t3, mepc
+        0x004e0e13, // addi t3, t3, 4
+        0x341e1073, // csrw mepc, t3
+        0x30200073, // mret
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 4);
+    assert_eq!(reg[9].0, 11); // ecall from M-mode
+    assert_eq!(reg[18].0, 4); // misaligned load
+    assert_eq!(reg[19].0, 0x8000_0001);
+    assert_eq!(reg[7].0, 0); // the load did not write back
+    assert_eq!(reg[20].0, 2); // illegal instruction
+    assert_eq!(reg[21].0, 0xffff_ffff);
+    assert_eq!(reg[22].0, 3); // breakpoint
+    assert_eq!(reg[23].0, 0x4000_0030);
+}
+
+#[cfg(test)]
+#[test]
+fn test_privilege() {
+    let rom_file = [
+        // M-mode: delegate ecall from U-mode, enter S-mode
+        0x00000297_u32, // auipc t0, 0
+        0x07428293,     // addi t0, t0, 0x74
+        0x30529073,     // csrw mtvec, t0
+        0x10000293,     // li t0, 1 << 8
+        0x30229073,     // csrw medeleg, t0
+        0x00000297,     // auipc t0, 0
+        0x01c28293,     // addi t0, t0, 0x1c
+        0x34129073,     // csrw mepc, t0
+        0x00100293,     // li t0, 1
+        0x00b29293,     // slli t0, t0, 11
+        0x30029073,     // csrw mstatus, t0
+        0x30200073,     // mret
+        // S-mode: install handler, enter U-mode
+        0x00000297, // auipc t0, 0
+        0x03028293, // addi t0, t0, 0x30
+        0x10529073, // csrw stvec, t0
+        0x00000297, // auipc t0, 0
+        0x01428293, // addi t0, t0, 0x14
+        0x14129073, // csrw sepc, t0
+        0x10001073, // csrw sstatus, zero
+        0x10200073, // sret
+        // U-mode
+        0x02a00513, // li a0, 42
+        0x00000073, // ecall
+        0x300025f3, // csrr a1, mstatus
+        0,
+        // S-mode handler
+        0x142024f3, // csrr s1, scause
+        0x14102373, // csrr t1, sepc
+        0x00430313, // addi t1, t1, 4
+        0x14131073, // csrw sepc, t1
+        0x10200073, // sret
+        // M-mode handler
+        0x34202973, // csrr s2, mcause
+        0x343029f3, // csrr s3, mtval
+        0x30002a73, // csrr s4, mstatus
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 42);
+    assert_eq!(reg[9].0, 8); // ecall from U-mode, in S-mode
+    assert_eq!(reg[18].0, 2); // illegal mstatus access from U-mode
+    assert_eq!(reg[19].0, 0x300025f3);
+    assert_eq!(reg[20].0 & (0b11 << 11), 0); // MPP = U
+    assert_eq!(reg[11].0, 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_sv32() {
+    let rom_file = [
+        // M-mode: root page table at RAM_START with two
+        // megapages, 0x4000_0000 -> ROM (R|X) and
+        // 0xc000_0000 -> RAM (R|W)
+        0x800002b7_u32, // lui t0, 0x80000
+        0x10000337,     // lui t1, 0x10000
+        0x00b30313,     // addi t1, t1, 0xb
+        0x4062a023,     // sw t1, 0x400(t0)
+        0x20000337,     // lui t1, 0x20000
+        0x00730313,     // addi t1, t1, 7
+        0x800013b7,     // lui t2, 0x80001
+        0xc063a023,     // sw t1, -0x400(t2)
+        0x00000297,     // auipc t0, 0
+        0x05c28293,     // addi t0, t0, 0x5c
+        0x30529073,     // csrw mtvec, t0
+        0x800802b7,     // lui t0, 0x80080
+        0x18029073,     // csrw satp, t0
+        0x12000073,     // sfence.vma
+        0x00000297,     // auipc t0, 0
+        0x01c28293,     // addi t0, t0, 0x1c
+        0x34129073,     // csrw mepc, t0
+        0x00100293,     // li t0, 1
+        0x00b29293,     // slli t0, t0, 11
+        0x30029073,     // csrw mstatus, t0
+        0x30200073,     // mret
+        // S-mode, translated
+        0xc0001437, // lui s0, 0xc0001
+        0x12300293, // li t0, 0x123
+        0x00542223, // sw t0, 4(s0)
+        0x00442503, // lw a0, 4(s0)
+        0xc0001337, // lui t1, 0xc0001
+        0xc0032583, // lw a1, -0x400(t1)
+        0xc00003b7, // lui t2, 0xc0000
+        0x4003a603, // lw a2, 0x400(t2)
+        0x00002683, // lw a3, 0(zero)
+        0,
+        // M-mode handler
+        0x34202973, // csrr s2, mcause
+        0x343029f3, // csrr s3, mtval
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+    let mem = rv32_sys.get_mem(0x1100);
+
+    assert_eq!(reg[10].0, 0x123);
+    assert_eq!(mem[0x1004 / 4], 0x123);
+    assert_eq!(reg[11].0, 0x200000c7); // A and D set
+    assert_eq!(reg[12].0, 0x1000004b); // A set by fetch
+    assert_eq!(reg[18].0, 13); // load page fault
+    assert_eq!(reg[19].0, 0);
+
+    let stats = rv32_sys.get_tlb_stats();
+    assert!(stats.hits > 0);
+    assert!(stats.misses > 0);
+    assert_eq!(stats.flushes, 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_compressed() {
+    let rom_file = [
+        0x05934515_u32, // c.li a0, 5; addi a1, zero, 7 (low)
+        0x11370070,     // addi (high); lui sp, 0x80001 (low)
+        0x20398000,     // lui (high); c.jal 0xe
+        0x952e8486,     // c.mv s1, ra; c.add a0, a1
+        0x4612c22a,     // c.swsp a0, 4(sp); c.lwsp a2, 4(sp)
+        0,
+        0x80820585, // c.addi a1, 1; c.jr ra
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 13);
+    assert_eq!(reg[11].0, 8);
+    assert_eq!(reg[9].0, 0x4000_000c); // c.jal links pc + 2
+    assert_eq!(reg[12].0, 13);
+    assert_eq!(reg[2].0, 0x8000_1000);
+}
+
+#[cfg(test)]
+#[test]
+fn test_atomic() {
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x05028293,     // addi t0, t0, 0x50
+        0x30529073,     // csrw mtvec, t0
+        0x80000437,     // lui s0, 0x80000
+        0x00500293,     // li t0, 5
+        0x00542023,     // sw t0, 0(s0)
+        0x0054252f,     // amoadd.w a0, t0, (s0)
+        0x085425af,     // amoswap.w a1, t0, (s0)
+        0xffd00313,     // li t1, -3
+        0x8064262f,     // amomin.w a2, t1, (s0)
+        0xe05426af,     // amomaxu.w a3, t0, (s0)
+        0x1004272f,     // lr.w a4, (s0)
+        0x185427af,     // sc.w a5, t0, (s0)
+        0x1864282f,     // sc.w a6, t1, (s0)
+        0x140428af,     // lr.w.aq a7, (s0)
+        0x00042023,     // sw zero, 0(s0)
+        0x1a5424af,     // sc.w.rl s1, t0, (s0)
+        0x00240993,     // addi s3, s0, 2
+        0x0059a92f,     // amoadd.w s2, t0, (s3)
+        0,
+        // handler
+        0x34202a73, // csrr s4, mcause
+        0x34302af3, // csrr s5, mtval
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+    let mem = rv32_sys.get_mem(4);
+
+    assert_eq!(reg[10].0, 5);
+    assert_eq!(reg[11].0, 10);
+    assert_eq!(reg[12].0, 5);
+    assert_eq!(reg[13].0, -3_i32 as u32);
+    assert_eq!(reg[14].0, -3_i32 as u32);
+    assert_eq!(reg[15].0, 0); // sc succeeded
+    assert_eq!(reg[16].0, 1); // no reservation
+    assert_eq!(reg[9].0, 1); // broken by the store
+    assert_eq!(mem[0], 0);
+    assert_eq!(reg[18].0, 0); // the trapping amo wrote nothing
+    assert_eq!(reg[20].0, 6); // store/AMO address misaligned
+    assert_eq!(reg[21].0, 0x8000_0002);
+}
+
+#[cfg(test)]
+#[test]
+fn test_float() {
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x0a028293,     // addi t0, t0, 0xa0
+        0x30529073,     // csrw mtvec, t0
+        0x80000437,     // lui s0, 0x80000
+        0x00300293,     // li t0, 3
+        0xd002f553,     // fcvt.s.w fa0, t0
+        0x00100293,     // li t0, 1
+        0xd002f5d3,     // fcvt.s.w fa1, t0
+        0x18a5f653,     // fdiv.s fa2, fa1, fa0
+        0x00b576d3,     // fadd.s fa3, fa0, fa1
+        0xe0068553,     // fmv.x.w a0, fa3
+        0x42050753,     // fcvt.d.s fa4, fa0
+        0x12e777d3,     // fmul.d fa5, fa4, fa4
+        0x00f43027,     // fsd fa5, 0(s0)
+        0x00043807,     // fld fa6, 0(s0)
+        0x7ae878c3,     // fmadd.d fa7, fa6, fa4, fa5
+        0xc208f5d3,     // fcvt.w.d a1, fa7
+        0xa2f71653,     // flt.d a2, fa4, fa5
+        0xa0b526d3,     // feq.s a3, fa0, fa1
+        0xe0051753,     // fclass.s a4, fa0
+        0x5a07f053,     // fsqrt.d ft0, fa5
+        0xc20077d3,     // fcvt.w.d a5, ft0
+        0x00c42427,     // fsw fa2, 8(s0)
+        0x00102873,     // frflags a6
+        0xd0007153,     // fcvt.s.w ft2, zero
+        0x182570d3,     // fdiv.s ft1, fa0, ft2
+        0x001028f3,     // frflags a7
+        0xff900293,     // li t0, -7
+        0xd002f453,     // fcvt.s.w fs0, t0
+        0x00200293,     // li t0, 2
+        0xd002f4d3,     // fcvt.s.w fs1, t0
+        0x18947953,     // fdiv.s fs2, fs0, fs1
+        0xc00974d3,     // fcvt.w.s s1, fs2
+        0x0020d073,     // fsrmi 1
+        0xc0097953,     // fcvt.w.s s2, fs2
+        0x300029f3,     // csrr s3, mstatus
+        0x000062b7,     // lui t0, 6
+        0x3002b073,     // csrc mstatus, t0
+        0x00a571d3,     // fadd.s ft3, fa0, fa0
+        0,
+        // handler
+        0x34202a73,     // csrr s4, mcause
+        0x34302af3,     // csrr s5, mtval
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+    let freg = rv32_sys.get_freg();
+    let mem = rv32_sys.get_mem(12);
+
+    assert_eq!(reg[10].0, 0x4080_0000); // 4.0
+    assert_eq!(reg[11].0, 36);
+    assert_eq!(reg[12].0, 1);
+    assert_eq!(reg[13].0, 0);
+    assert_eq!(reg[14].0, 1 << 6); // positive normal
+    assert_eq!(reg[15].0, 3);
+    assert_eq!(mem[0], 0);
+    assert_eq!(mem[1], 0x4022_0000); // 9.0
+    assert_eq!(mem[2], 0x3eaa_aaab); // 1/3
+    assert_eq!(freg[12].0, 0xffff_ffff_3eaa_aaab); // boxed
+    assert_eq!(reg[16].0, 0b00001); // NX
+    assert_eq!(reg[17].0, 0b01001); // NX, DZ
+    assert_eq!(reg[9].0, -4_i32 as u32); // -3.5, RNE
+    assert_eq!(reg[18].0, -3_i32 as u32); // -3.5, RTZ
+    assert_eq!(reg[19].0 >> 13 & 0b11, 0b11); // FS dirty
+    assert_eq!(reg[19].0 >> 31, 1); // SD
+    assert_eq!(freg[3].0, 0); // FS off, fadd.s trapped
+    assert_eq!(reg[20].0, 2); // illegal instruction
+    assert_eq!(reg[21].0, 0x00a5_71d3);
+
+    // the high half of fsd runs past the end of RAM
+    let rom_file = [
+        0x80001437_u32, // lui s0, 0x80001
+        0x0fc40413,     // addi s0, s0, 0xfc
+        0xfff00293,     // li t0, -1
+        0xf0028553,     // fmv.w.x fa0, t0
+        0x00a43027,     // fsd fa0, 0(s0)
+        0,
+        // handler
+        0x34202a73, // csrr s4, mcause
+        0x34302af3, // csrr s5, mtval
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.write_csr(0x305, ROM_START as u32 + 24); // mtvec
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+    assert_eq!(reg[20].0, 7); // store access fault
+    assert_eq!(reg[21].0, 0x8000_1100);
+    // nor has the low half been stored
+    assert_eq!(rv32_sys.get_mem(0x1100)[0x43f], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_elf() {
+    use crate::{
+        bus::RAM_SIZE,
+        elf::{Segment, Symbol},
+    };
+
+    let code = [
+        0x00100513_u32, // li a0, 1
+        0,
+        // _start
+        0x80000437, // lui s0, 0x80000
+        0x01842583, // lw a1, 0x18(s0)
+        0x01c42603, // lw a2, 0x1c(s0), .bss
+        0,
+        0x1234_5678,
+    ];
+    let mut elf = ElfFile {
+        entry: 0x8000_0008,
+        segments: vec![Segment {
+            paddr: 0x8000_0000,
+            data: code
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+            mem_size: 0x20,
+        }],
+        symbols: SymbolTable::new(vec![Symbol {
+            name: String::from("_start"),
+            addr: 0x8000_0008,
+            size: 0,
+        }]),
+    };
+
+    let rv32_sys = RV32System::from_elf(&elf).unwrap();
+    rv32_sys.set_halt_word(true);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 0); // skipped, before e_entry
+    assert_eq!(reg[11].0, 0x1234_5678);
+    assert_eq!(reg[12].0, 0);
+    assert!(rv32_sys.get_symbols().find("_start").is_some());
+
+    // past the end of RAM
+    elf.segments[0].mem_size = RAM_SIZE as u32 + 4;
+    assert!(matches!(
+        RV32System::from_elf(&elf),
+        Err(ElfError::SegmentOutOfMemory(0x8000_0000, _))
+    ));
+}
+
+#[cfg(test)]
+#[test]
+fn test_uart() {
+    use crate::bus::uart::UART_START;
+    use std::rc::Rc;
+
+    struct BufferBackend(Rc<RefCell<Vec<u8>>>);
+
+    impl UARTBackend for BufferBackend {
+        fn put(&self, byte: u8) {
+            self.0.borrow_mut().push(byte);
+        }
+
+        fn get(&self) -> Option<u8> {
+            Some(b'!').filter(|_| self.0.borrow().len() == 2)
+        }
+    }
+
+    let rom_file = [
+        0x100002b7_u32, // lui t0, 0x10000
+        0x06800313,     // li t1, 'h'
+        0x00628023,     // sb t1, 0(t0), THR
+        0x06900313,     // li t1, 'i'
+        0x00628023,     // sb t1, 0(t0)
+        0x0052c503,     // lbu a0, 5(t0), LSR
+        0x0002c583,     // lbu a1, 0(t0), RBR
+        0x0052c603,     // lbu a2, 5(t0)
+        0x0062a023,     // sw t1, 0(t0), byte registers only
+        0,
+        // handler: exit through the test finisher
+        0x00100f37, // lui t5, 0x100
+        0x00005fb7, // lui t6, 0x5
+        0x555f8f93, // addi t6, t6, 0x555
+        0x01ff2023, // sw t6, 0(t5)
+    ];
+
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.write_csr(0x305, ROM_START as u32 + 40); // mtvec
+    let backend = BufferBackend(output.clone());
+    rv32_sys
+        .attach_uart(UART_START, Box::new(backend))
+        .unwrap();
+    let summary = rv32_sys.run().unwrap();
+    assert_eq!(summary.reason, HaltReason::Exit(0));
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(*output.borrow(), b"hi");
+    assert_eq!(reg[10].0, 0x61); // data ready, THR empty
+    assert_eq!(reg[11].0, b'!' as u32);
+    assert_eq!(reg[12].0, 0x61); // backend keeps sending
+    assert_eq!(rv32_sys.read_csr(0x342), Some(7)); // mcause
+    assert_eq!(rv32_sys.read_csr(0x343), Some(UART_START as u32));
+}
+
+#[cfg(test)]
+#[test]
+fn test_access_fault() {
+    use crate::bus::bus_error::{AccessFault, BusError};
+
+    let rom_file = [
+        0x400002b7_u32, // lui t0, 0x40000
+        0x01828293,     // addi t0, t0, 0x18
+        0x30529073,     // csrw mtvec, t0
+        0x20000337,     // lui t1, 0x20000
+        0x00032383,     // lw t2, 0(t1), unmapped
+        0,
+        // trap handler
+        0x34202573, // csrr a0, mcause
+        0x343025f3, // csrr a1, mtval
+        0,
+    ];
+
+    // legacy, the load reads 0
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.set_fault_policy(AccessFaultPolicy::ReadAsZero);
+    rv32_sys.run().unwrap();
+    assert_eq!(rv32_sys.get_reg()[10].0, 0);
+    assert!(rv32_sys.get_error().is_none());
+
+    // the default
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+    assert_eq!(reg[10].0, 5); // load access fault
+    assert_eq!(reg[11].0, 0x2000_0000);
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.set_fault_policy(AccessFaultPolicy::Halt);
+    let error = rv32_sys.run().unwrap_err();
+    assert_eq!(
+        error,
+        EmulatorError::Bus {
+            pc: 0x4000_0010,
+            instruction: 0x00032383,
+            error: BusError::LoadAccessFault(
+                0x2000_0000,
+                AccessFault::Unmapped
+            ),
+        }
+    );
+    assert_eq!(rv32_sys.get_error(), Some(error));
+    assert_eq!(rv32_sys.read_csr(0x342), Some(0)); // no trap
+    assert!(!rv32_sys.step());
+}
+
+#[cfg(test)]
+#[test]
+fn test_misaligned() {
+    let rom_file = [
+        0x80000437_u32, // lui s0, 0x80000
+        0x443322b7,     // lui t0, 0x44332
+        0x21128293,     // addi t0, t0, 0x211
+        0x005420a3,     // sw t0, 1(s0)
+        0x00042503,     // lw a0, 0(s0)
+        0x00442583,     // lw a1, 4(s0)
+        0x00341603,     // lh a2, 3(s0)
+        0x00145683,     // lhu a3, 1(s0)
+        0x005413a3,     // sh t0, 7(s0)
+        0x00842703,     // lw a4, 8(s0)
+        0x00542783,     // lw a5, 5(s0)
+        0,
+        // handler: exit through the test finisher
+        0x00100f37, // lui t5, 0x100
+        0x00005fb7, // lui t6, 0x5
+        0x555f8f93, // addi t6, t6, 0x555
+        0x01ff2023, // sw t6, 0(t5)
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.set_misaligned_policy(MisalignedPolicy::Emulate);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[10].0, 0x3322_1100);
+    assert_eq!(reg[11].0, 0x0000_0044);
+    assert_eq!(reg[12].0, 0x4433);
+    assert_eq!(reg[13].0, 0x2211);
+    assert_eq!(reg[14].0, 0x0000_0022);
+    assert_eq!(reg[15].0, 0x2211_0000);
+
+    // the first store traps
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.write_csr(0x305, ROM_START as u32 + 48); // mtvec
+    let summary = rv32_sys.run().unwrap();
+    assert_eq!(summary.reason, HaltReason::Exit(0));
+    assert_eq!(rv32_sys.read_csr(0x342), Some(6));
+    assert_eq!(rv32_sys.read_csr(0x343), Some(0x8000_0001));
+    assert_eq!(rv32_sys.get_mem(8), [0, 0]);
+
+    // the last 2 bytes are past the end of RAM, the first 2
+    // are not stored either
+    let rom_file = [
+        0x80001437_u32, // lui s0, 0x80001
+        0x443322b7,     // lui t0, 0x44332
+        0x21128293,     // addi t0, t0, 0x211
+        0x0e542f23,     // sw t0, 0xfe(s0)
+        0,
+        // handler: exit through the test finisher
+        0x00100f37, // lui t5, 0x100
+        0x00005fb7, // lui t6, 0x5
+        0x555f8f93, // addi t6, t6, 0x555
+        0x01ff2023, // sw t6, 0(t5)
+    ];
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.set_misaligned_policy(MisalignedPolicy::Emulate);
+    rv32_sys.write_csr(0x305, ROM_START as u32 + 20); // mtvec
+    let summary = rv32_sys.run().unwrap();
+    assert_eq!(summary.reason, HaltReason::Exit(0));
+    assert_eq!(rv32_sys.read_csr(0x342), Some(7));
+    assert_eq!(rv32_sys.read_csr(0x343), Some(0x8000_10fe));
+    assert_eq!(rv32_sys.get_mem(0x1100)[0x43f], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_pipeline() {
+    let rom_file = [
+        0x00500513_u32, // li a0, 5
+        0x00150593,     // addi a1, a0, 1, from EX/MEM
+        0x00250613,     // addi a2, a0, 2, from MEM/WB
+        0x80000437,     // lui s0, 0x80000
+        0x00b42023,     // sw a1, 0(s0)
+        0x00042683,     // lw a3, 0(s0)
+        0x00168713,     // addi a4, a3, 1, load-use stall
+        0x00a50463,     // beq a0, a0, 8
+        0x00100793,     // li a5, 1, squashed
+        0x00200813,     // li a6, 2
+        0,
+    ];
+
+    let rv32_sys = new_halting(&rom_file);
+    rv32_sys.run().unwrap();
+    let reg = rv32_sys.get_reg();
+
+    assert_eq!(reg[11].0, 6);
+    assert_eq!(reg[12].0, 7);
+    assert_eq!(reg[13].0, 6);
+    assert_eq!(reg[14].0, 7);
+    assert_eq!(reg[15].0, 0);
+    assert_eq!(reg[16].0, 2);
+
+    // 4 cycles to fill the pipeline, then one instruction
+    // per cycle, plus the stall and 2 cycles for the branch
+    let stats = rv32_sys.get_pipeline_stats();
+    assert_eq!(stats.retired, 9);
+    assert_eq!(stats.load_use_stalls, 1);
+    assert_eq!(stats.flushes, 1);
+    assert_eq!(stats.cycles, 4 + 9 + 1 + 2);
+
+    // stepping drains the pipeline after every instruction
+    let rv32_sys = new_halting(&rom_file);
+    let mut steps = 0;
+    while rv32_sys.step() {
+        steps += 1;
+    }
+    assert_eq!(steps, 9);
+    let stepped = rv32_sys.get_reg();
+    assert!((0..NUM_REGISTER).all(|i| stepped[i].0 == reg[i].0));
+    // and the halt word takes 4 more to reach WB
+    assert_eq!(rv32_sys.get_pipeline_stats().cycles, 5 * 9 + 4);
+}
+
+#[cfg(test)]
+#[test]
+fn test_branch_predictor() {
+    use crate::branch_predictor;
+
+    let rom_file = [
+        0x00000513_u32, // li a0, 0
+        0x00a00593,     // li a1, 10
+        0x010000ef,     // loop: jal f
+        0xfff58593,     // addi a1, a1, -1
+        0xfe059ce3,     // bnez a1, loop
+        0,
+        0x00150513, // f: addi a0, a0, 1
+        0x00008067, // ret
+    ];
+
+    let run = |name| {
+        let rv32_sys = new_halting(&rom_file);
+        let predictor = branch_predictor::from_name(name);
+        rv32_sys.set_branch_predictor(predictor.unwrap());
+        rv32_sys.run().unwrap();
+        assert_eq!(rv32_sys.get_reg()[10].0, 10);
+        (
+            rv32_sys.get_branch_stats(),
+            rv32_sys.get_pipeline_stats().cycles,
+        )
+    };
+
+    let (not_taken, not_taken_cycles) = run("not-taken");
+    assert_eq!(not_taken.branches, 10);
+    assert_eq!(not_taken.branch_mispredictions, 9);
+    // every return comes from the return address stack
+    assert_eq!(not_taken.indirect_jumps, 10);
+    assert_eq!(not_taken.indirect_mispredictions, 0);
+
+    let (btfn, btfn_cycles) = run("btfn");
+    assert_eq!(btfn.branch_mispredictions, 1);
+    // 2 cycles per misprediction
+    assert_eq!(not_taken_cycles - btfn_cycles, 2 * 8);
+
+    let (bimodal, _) = run("bimodal");
+    assert_eq!(bimodal.branch_mispredictions, 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_functional() {
+    let rom_file = [
+        0x00000513_u32, // li a0, 0
+        0x00a00593,     // li a1, 10
+        0x80000437,     // lui s0, 0x80000
+        0x010000ef,     // loop: jal f
+        0xfff58593,     // addi a1, a1, -1
+        0xfe059ce3,     // bnez a1, loop
+        0,
+        0x00150513, // f: addi a0, a0, 1
+        0x00a42023, // sw a0, 0(s0)
+        0x00042603, // lw a2, 0(s0)
+        0x00008067, // ret
+    ];
+    let state = |rv32_sys: &RV32System| {
+        let reg = rv32_sys.get_reg().map(|reg| reg.0);
+        (reg, rv32_sys.get_mem(4), rv32_sys.read_csr(0xb02))
+    };
+
+    let pipelined = new_halting(&rom_file);
+    pipelined.run().unwrap();
+    let expected = state(&pipelined);
+    assert_eq!(expected.0[12], 10);
+    assert_eq!(expected.2, Some(3 + 7 * 10)); // minstret
+
+    let functional = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    functional.run().unwrap();
+    assert_eq!(state(&functional), expected);
+    // one cycle per instruction
+    let stats = functional.get_pipeline_stats();
+    assert_eq!(stats.cycles, stats.retired);
+
+    // fast-forward to the 4th call, then model the timing
+    let rv32_sys = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    for _ in 0..4 {
+        rv32_sys.step();
+        assert!(rv32_sys.run_until(0x4000_001c));
+    }
+    rv32_sys.set_execution_mode(ExecutionMode::Pipelined);
+    rv32_sys.run().unwrap();
+    assert_eq!(state(&rv32_sys), expected);
+    assert!(
+        rv32_sys.get_pipeline_stats().cycles
+            < pipelined.get_pipeline_stats().cycles
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_cache() {
+    let rom_file = [
+        0x80000437_u32, // lui s0, 0x80000
+        0x001502b7,     // lui t0, 0x150
+        0x51328293,     // addi t0, t0, 0x513
+        0x00542023,     // sw t0, 0(s0)    # addi a0, a0, 1
+        0x00008337,     // lui t1, 0x8
+        0x06730313,     // addi t1, t1, 0x67
+        0x00642223,     // sw t1, 4(s0)    # ret
+        0x0000100f,     // fence.i
+        0x000400e7,     // jalr s0
+        0x000400e7,     // jalr s0
+        0x010502b7,     // lui t0, 0x1050
+        0x51328293,     // addi t0, t0, 0x513
+        0x00542023,     // sw t0, 0(s0)    # addi a0, a0, 16
+        0x0000100f,     // fence.i
+        0x000400e7,     // jalr s0
+        0,
+    ];
+
+    let pipelined = new_halting(&rom_file);
+    pipelined.run().unwrap();
+    assert_eq!(pipelined.get_reg()[10].0, 1 + 1 + 16);
+
+    let rv32_sys = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    rv32_sys.run().unwrap();
+    assert_eq!(rv32_sys.get_reg()[10].0, 1 + 1 + 16);
+
+    // the second call hits, the patch drops the first
+    // instruction and fence.i the second
+    let stats = rv32_sys.get_decode_cache_stats();
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, rom_file.len() as u64 + 2 + 2);
+    assert_eq!(stats.invalidations, 1);
+    assert_eq!(stats.flushes, 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_translated() {
+    let rom_file = [
+        0x80000437_u32, // lui s0, 0x80000
+        0x00000513,     // li a0, 0
+        0x06400593,     // li a1, 100
+        0x00b50533,     // loop: add a0, a0, a1
+        0x02b582b3,     // mul t0, a1, a1
+        0x00542823,     // sw t0, 16(s0)
+        0x01042303,     // lw t1, 16(s0)
+        0x00650533,     // add a0, a0, t1
+        0xfff5b393,     // sltiu t2, a1, -1
+        0x00750533,     // add a0, a0, t2
+        0xfff58593,     // addi a1, a1, -1
+        0xfe0590e3,     // bnez a1, loop
+        0x001602b7,     // lui t0, 0x160
+        0x61328293,     // addi t0, t0, 0x613
+        0x10542023,     // sw t0, 256(s0)  # addi a2, a2, 1
+        0x00008337,     // lui t1, 0x8
+        0x06730313,     // addi t1, t1, 0x67
+        0x10642223,     // sw t1, 260(s0)  # ret
+        0x0000100f,     // fence.i
+        0x10040493,     // addi s1, s0, 256
+        0x02800693,     // li a3, 40
+        0x000480e7,     // calls: jalr s1
+        0xfff68693,     // addi a3, a3, -1
+        0x01400393,     // li t2, 20
+        0x00769a63,     // bne a3, t2, skip
+        0x003602b7,     // lui t0, 0x360
+        0x61328293,     // addi t0, t0, 0x613
+        0x10542023,     // sw t0, 256(s0)  # addi a2, a2, 3
+        0x0000100f,     // fence.i
+        0xfe0690e3,     // skip: bnez a3, calls
+        0,
+    ];
+    let state = |rv32_sys: &RV32System| {
+        let reg = rv32_sys.get_reg().map(|reg| reg.0);
+        let counters = [0xb00, 0xb02]
+            .map(|csr| rv32_sys.read_csr(csr).unwrap());
+        (reg, rv32_sys.get_mem(0x110), counters)
+    };
+
+    let functional = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    functional.run().unwrap();
+    let expected = state(&functional);
+    assert_eq!(expected.0[10], 5050 + 338350 + 100);
+    assert_eq!(expected.0[12], 20 + 20 * 3);
+
+    let translated = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Translated);
+    translated.run().unwrap();
+    assert_eq!(state(&translated), expected);
+    assert_eq!(
+        translated.get_pipeline_stats().retired,
+        functional.get_pipeline_stats().retired
+    );
+
+    // the patched function is translated again
+    let stats = translated.get_dbt_stats();
+    assert!(stats.blocks_translated >= 4);
+    assert!(stats.chained > 0);
+    assert!(stats.invalidations > 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_translated_sv32() {
+    let mut rom_file = vec![
+        // M-mode: megapages 0x4000_0000 -> ROM and
+        // 0xc000_0000 -> ROM, both R|X
+        0x800002b7_u32, // lui t0, 0x80000
+        0x10000337,     // lui t1, 0x10000
+        0x00b30313,     // addi t1, t1, 0xb
+        0x4062a023,     // sw t1, 0x400(t0)
+        0x800013b7,     // lui t2, 0x80001
+        0xc063a023,     // sw t1, -0x400(t2)
+        0x00000297,     // auipc t0, 0
+        0x06028293,     // addi t0, t0, 0x60
+        0x30529073,     // csrw mtvec, t0
+        0x800802b7,     // lui t0, 0x80080
+        0x18029073,     // csrw satp, t0
+        0x12000073,     // sfence.vma
+        0x00000297,     // auipc t0, 0
+        0x01c28293,     // addi t0, t0, 0x1c
+        0x34129073,     // csrw mepc, t0
+        0x00100293,     // li t0, 1
+        0x00b29293,     // slli t0, t0, 11
+        0x30029073,     // csrw mstatus, t0
+        0x30200073,     // mret
+        // S-mode: call the function at 0xc000_1000
+        0xc00014b7, // lui s1, 0xc0001
+        0x02800693, // li a3, 40
+        0x000480e7, // calls: jalr s1
+        0xfff68693, // addi a3, a3, -1
+        0xfe069ce3, // bnez a3, calls
+        0x00000073, // ecall
+        0x02800693, // li a3, 40
+        0x000480e7, // calls: jalr s1
+        0xfff68693, // addi a3, a3, -1
+        0xfe069ce3, // bnez a3, calls
+        0,
+        // M-mode handler: map 0xc000_0000 to RAM instead,
+        // where the function adds 3
+        0x800012b7, // lui t0, 0x80001
+        0x20000337, // lui t1, 0x20000
+        0x00b30313, // addi t1, t1, 0xb
+        0xc062a023, // sw t1, -0x400(t0)
+        0x00360337, // lui t1, 0x360
+        0x61330313, // addi t1, t1, 0x613
+        0x0062a023, // sw t1, 0(t0)  # addi a2, a2, 3
+        0x00008337, // lui t1, 0x8
+        0x06730313, // addi t1, t1, 0x67
+        0x0062a223, // sw t1, 4(t0)  # ret
+        0x12000073, // sfence.vma
+        0x341022f3, // csrr t0, mepc
+        0x00428293, // addi t0, t0, 4
+        0x34129073, // csrw mepc, t0
+        0x30200073, // mret
+    ];
+    // the function in ROM adds 1
+    rom_file.resize(0x1000 / 4, 0);
+    rom_file.extend([
+        0x00160613, // addi a2, a2, 1
+        0x00008067, // ret
+    ]);
+
+    let functional = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    functional.run().unwrap();
+    assert_eq!(functional.get_reg()[12].0, 40 + 40 * 3);
+
+    // the blocks of the old mapping are not run after the
+    // sfence.vma, although the page they came from was
+    // never written
+    let translated = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Translated);
+    translated.run().unwrap();
+    assert_eq!(translated.get_reg()[12].0, 40 + 40 * 3);
+    let stats = translated.get_dbt_stats();
+    assert!(stats.blocks_translated >= 4);
+    assert_eq!(stats.invalidations, 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_clint() {
+    let rom_file = [
+        0x02004437_u32, // lui s0, 0x2004  # mtimecmp
+        0x020004b7,     // lui s1, 0x2000  # msip
+        0x00000297,     // auipc t0, 0
+        0x04828293,     // addi t0, t0, 72
+        0x30529073,     // csrw mtvec, t0
+        0x08000293,     // li t0, 0x80
+        0x30429073,     // csrw mie, t0  # MTIE
+        0x06400313,     // li t1, 100
+        0x00642023,     // sw t1, 0(s0)
+        0x00042223,     // sw zero, 4(s0)
+        0x00500393,     // li t2, 5
+        0x30046073,     // csrsi mstatus, 8
+        0x00150513,     // loop: addi a0, a0, 1
+        0xfe759ee3,     // bne a1, t2, loop
+        0x00800293,     // li t0, 8
+        0x30429073,     // csrw mie, t0  # MSIE
+        0x00100293,     // li t0, 1
+        0x0054a023,     // sw t0, 0(s1)
+        0x00170713,     // addi a4, a4, 1
+        0,
+        0x34202673,     // handler: csrr a2, mcause
+        0x0ff67e13,     // andi t3, a2, 0xff
+        0x00300e93,     // li t4, 3
+        0x01de0e63,     // beq t3, t4, software
+        0x00158593,     // addi a1, a1, 1
+        0x00042f03,     // lw t5, 0(s0)
+        0x064f0f13,     // addi t5, t5, 100
+        0x01e42023,     // sw t5, 0(s0)
+        0xc01027f3,     // rdtime a5
+        0x30200073,     // mret
+        0x0004a023,     // software: sw zero, 0(s1)
+        0x00168693,     // addi a3, a3, 1
+        0x30200073,     // mret
+    ];
+    let state = |rv32_sys: &RV32System| {
+        let reg = rv32_sys.get_reg().map(|reg| reg.0);
+        let counters = [0xb00, 0xb02, 0xc01, 0x341]
+            .map(|csr| rv32_sys.read_csr(csr).unwrap());
+        (reg, counters)
+    };
+
+    let functional = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    functional.run().unwrap();
+    let (reg, counters) = state(&functional);
+    assert_eq!(reg[11], 5); // timer interrupts
+    assert_eq!(reg[13], 1); // software interrupts
+    assert_eq!(reg[14], 1);
+    assert_eq!(reg[12], 0x8000_0003);
+    // taken right after the store to msip
+    assert_eq!(counters[3], ROM_START as u32 + 18 * 4);
+    // the first one once mtime has reached 100
+    assert!(reg[15] >= 500 && reg[15] < 510);
+
+    // the budget stops translated code exactly there
+    let translated = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Translated);
+    translated.run().unwrap();
+    assert_eq!(state(&translated), (reg, counters));
+    assert!(translated.get_dbt_stats().blocks_executed > 0);
+
+    // the pipeline takes them a few instructions later
+    let pipelined = new_halting(&rom_file);
+    pipelined.run().unwrap();
+    let (reg, counters) = state(&pipelined);
+    assert_eq!((reg[11], reg[13], reg[14]), (5, 1, 1));
+    assert_eq!(counters[3], ROM_START as u32 + 18 * 4);
+
+    // a slower timer leaves the loop more than twice the
+    // iterations, the handlers take the same time
+    let slow = new_halting(&rom_file)
+        .with_mode(ExecutionMode::Functional);
+    slow.set_time_base(2);
+    slow.run().unwrap();
+    let iterations = functional.get_reg()[10].0;
+    assert!(slow.get_reg()[10].0 > 2 * iterations);
+}
+
+#[cfg(test)]
+#[test]
+fn test_plic() {
+    use crate::bus::mmio_device::DeviceError;
+
+    // raises its line while a non-zero value is written
+    struct Doorbell {
+        line: IrqLine,
+    }
+
+    impl MMIODevice for Doorbell {
+        fn read(
+            &self,
+            _offset: usize,
+            _width: MemoryAccessWidth,
+        ) -> Result<u32, DeviceError> {
+            Ok(0)
+        }
+
+        fn write(
+            &self,
+            _offset: usize,
+            val: u32,
+            _width: MemoryAccessWidth,
+        ) -> Result<(), DeviceError> {
+            self.line.set_level(val != 0);
+            Ok(())
+        }
+    }
+
+    let rom_file = [
+        0x10001437_u32, // lui s0, 0x10001  # doorbell
+        0x0c0004b7,     // lui s1, 0xc000  # priorities
+        0x00000297,     // auipc t0, 0
+        0x04428293,     // addi t0, t0, 68
+        0x30529073,     // csrw mtvec, t0
+        0x00100293,     // li t0, 1
+        0x0054aa23,     // sw t0, 20(s1)  # source 5
+        0x02000293,     // li t0, 32
+        0x0c002337,     // lui t1, 0xc002  # M-mode enables
+        0x00532023,     // sw t0, 0(t1)
+        0x0c200937,     // lui s2, 0xc200  # M-mode threshold
+        0x000012b7,     // lui t0, 1
+        0x80028293,     // addi t0, t0, -2048
+        0x30429073,     // csrw mie, t0  # MEIE
+        0x30046073,     // csrsi mstatus, 8
+        0x00100293,     // li t0, 1
+        0x00542023,     // sw t0, 0(s0)
+        0x00170713,     // addi a4, a4, 1
+        0,
+        0x34202673,     // handler: csrr a2, mcause
+        0x00492503,     // lw a0, 4(s2)  # claim
+        0x00042023,     // sw zero, 0(s0)
+        0x00a92223,     // sw a0, 4(s2)  # complete
+        0x00168693,     // addi a3, a3, 1
+        0x30200073,     // mret
+    ];
+
+    for mode in [
+        ExecutionMode::Pipelined,
+        ExecutionMode::Functional,
+        ExecutionMode::Translated,
+    ] {
+        let rv32_sys =
+            new_halting(&rom_file).with_mode(mode);
+        let line = rv32_sys.irq_line(5);
+        let doorbell = Box::new(Doorbell { line });
+        let base = 0x1000_1000;
+        rv32_sys
+            .register_device("doorbell", base, 4, doorbell)
+            .unwrap();
+        rv32_sys.run().unwrap();
+
+        let reg = rv32_sys.get_reg().map(|reg| reg.0);
+        assert_eq!(reg[10], 5); // claimed source
+        assert_eq!(reg[12], 0x8000_000b);
+        assert_eq!((reg[13], reg[14]), (1, 1));
+        // taken right after the store to the doorbell
+        assert_eq!(
+            rv32_sys.read_csr(0x341),
+            Some(ROM_START as u32 + 17 * 4)
+        );
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_halt() {
+    use crate::elf::{Segment, Symbol};
+
+    let rom_file = [
+        0x001002b7_u32, // lui t0, 0x100  # test finisher
+        0x00033337,     // lui t1, 0x33
+        0x33330313,     // addi t1, t1, 0x333
+        0x0062a023,     // sw t1, 0(t0)  # fail with 3
+        0x00150513,     // addi a0, a0, 1
+        0x0000006f,     // j .
+    ];
+    for mode in [
+        ExecutionMode::Pipelined,
+        ExecutionMode::Functional,
+        ExecutionMode::Translated,
+    ] {
+        let rv32_sys =
+            RV32System::new(&rom_file).with_mode(mode);
+        let summary = rv32_sys.run().unwrap();
+        assert_eq!(summary.reason, HaltReason::Exit(3));
+        // nothing after the store has run
+        assert_eq!(rv32_sys.get_reg()[10].0, 0);
+        assert_eq!(summary.pc, ROM_START as u32 + 16);
+        assert_eq!(summary.retired, 4);
+        assert_eq!(rv32_sys.read_csr(0xb02), Some(4));
+    }
+
+    let code = [
+        0x00000297_u32, // auipc t0, 0
+        0x01828293,     // addi t0, t0, 24
+        0x30529073,     // csrw mtvec, t0
+        0,
+        0x00150513, // addi a0, a0, 1
+        0x0000006f, // j .
+        0x342025f3, // handler: csrr a1, mcause
+        0x00f00313, // li t1, 15
+        0x800003b7, // lui t2, 0x80000
+        0x1063a023, // sw t1, 0x100(t2)  # tohost
+        0x0000006f, // j .
+    ];
+    let elf = ElfFile {
+        entry: 0x8000_0000,
+        segments: vec![Segment {
+            paddr: 0x8000_0000,
+            data: code
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+            mem_size: 0x108,
+        }],
+        symbols: SymbolTable::new(vec![Symbol {
+            name: String::from("tohost"),
+            addr: 0x8000_0100,
+            size: 8,
+        }]),
+    };
+    for mode in [
+        ExecutionMode::Pipelined,
+        ExecutionMode::Functional,
+        ExecutionMode::Translated,
+    ] {
+        // legacy, the all-zero word halts
+        let rv32_sys = RV32System::from_elf(&elf)
+            .unwrap()
+            .with_mode(mode);
+        rv32_sys.set_halt_word(true);
+        let summary = rv32_sys.run().unwrap();
+        assert_eq!(summary.reason, HaltReason::HaltWord);
+
+        // by default it is an illegal instruction
+        let rv32_sys = RV32System::from_elf(&elf)
+            .unwrap()
+            .with_mode(mode);
+        let summary = rv32_sys.run().unwrap();
+        assert_eq!(summary.reason, HaltReason::Exit(7));
+        let reg = rv32_sys.get_reg();
+        assert_eq!(reg[10].0, 0);
+        assert_eq!(reg[11].0, 2);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_trap_loop() {
+    use crate::bus::bus_error::{AccessFault, BusError};
+
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x01028293,     // addi t0, t0, 16
+        0x30529073,     // csrw mtvec, t0
+        0x00100073,     // ebreak
+        0x00000073,     // handler: ecall
+    ];
+    for mode in [
+        ExecutionMode::Pipelined,
+        ExecutionMode::Functional,
+        ExecutionMode::Translated,
+    ] {
+        let rv32_sys =
+            RV32System::new(&rom_file).with_mode(mode);
+        let error = rv32_sys.run().unwrap_err();
+        let EmulatorError::TrapLoop { pc, trap, .. } = error
+        else {
+            panic!("{:?}", error);
+        };
+        assert_eq!(pc, ROM_START as u32 + 16);
+        assert_eq!(trap.cause, Exception::EcallFromMMode);
+        assert_eq!(error.get_instruction(), 0x00000073);
+        // the ebreak has trapped once, the ecall twice
+        assert_eq!(rv32_sys.read_csr(0x342), Some(11));
+        assert!(!rv32_sys.step());
+    }
+
+    // the illegal instruction is its own handler
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x00c28293,     // addi t0, t0, 12
+        0x30529073,     // csrw mtvec, t0
+        0xffffffff,     // illegal instruction
+    ];
+    let rv32_sys = RV32System::new(&rom_file);
+    assert_eq!(
+        rv32_sys.run(),
+        Err(EmulatorError::Decode {
+            pc: ROM_START as u32 + 12,
+            instruction: 0xffff_ffff,
+        })
+    );
+
+    // mtvec is 0, where nothing is mapped, the trap that
+    // has no handler is reported
+    let rom = ROM_START as u32;
+    let ecall = Trap::new(Exception::EcallFromMMode, 0);
+    let unhandled = [
+        (
+            vec![0xffffffff_u32], // illegal instruction
+            EmulatorError::Decode {
+                pc: rom,
+                instruction: 0xffff_ffff,
+            },
+        ),
+        (
+            vec![0x00000073], // ecall
+            EmulatorError::Unhandled {
+                pc: rom,
+                instruction: 0x00000073,
+                trap: ecall,
+            },
+        ),
+        (
+            vec![
+                0x200002b7, // lui t0, 0x20000
+                0x00028067, // jr t0
+            ],
+            EmulatorError::Bus {
+                pc: 0x2000_0000,
+                instruction: 0,
+                error: BusError::FetchAccessFault(
+                    0x2000_0000,
+                    AccessFault::Unmapped,
+                ),
+            },
+        ),
+    ];
+    for (rom_file, expected) in unhandled {
+        for mode in [
+            ExecutionMode::Pipelined,
+            ExecutionMode::Functional,
+            ExecutionMode::Translated,
+        ] {
+            let rv32_sys =
+                RV32System::new(&rom_file).with_mode(mode);
+            assert_eq!(rv32_sys.run(), Err(expected));
+            assert!(!rv32_sys.step());
+        }
+    }
+    let error = RV32System::new(&[0xffffffff]).run().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Illegal instruction with no usable trap handler, \
+         pc = 0x40000000, instruction = 0xffffffff"
+    );
+
+    // a handler that halts at once
+    let rom_file = [
+        0x00000297_u32, // auipc t0, 0
+        0x01028293,     // addi t0, t0, 16
+        0x30529073,     // csrw mtvec, t0
+        0x00000073,     // ecall
+        0,              // handler
+    ];
+    let rv32_sys = new_halting(&rom_file);
+    assert_eq!(
+        rv32_sys.run(),
+        Err(EmulatorError::Unhandled {
+            pc: rom + 12,
+            instruction: 0x00000073,
+            trap: ecall,
+        })
+    );
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+
+use crate::bus::bus_error::{BusError, FaultId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Exception {
+    InstructionAddrMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddrMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddrMisaligned = 6,
+    StoreAccessFault = 7,
+    EcallFromUMode = 8,
+    EcallFromSMode = 9,
+    EcallFromMMode = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    pub fn is_access_fault(&self) -> bool {
+        matches!(
+            self,
+            Exception::InstructionAccessFault
+                | Exception::LoadAccessFault
+                | Exception::StoreAccessFault
+        )
+    }
+
+    // the instruction could not be fetched
+    pub fn is_fetch_fault(&self) -> bool {
+        matches!(
+            self,
+            Exception::InstructionAddrMisaligned
+                | Exception::InstructionAccessFault
+                | Exception::InstructionPageFault
+        )
+    }
+}
+
+// the exception code of an interrupt is also its bit in mip
+// and mie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+impl Interrupt {
+    pub fn bit(&self) -> u32 {
+        1 << *self as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trap {
+    pub cause: Exception,
+    pub tval: u32,
+    // the bus error behind an access fault, in the fault log
+    // of the bus
+    pub fault: Option<FaultId>,
+}
+
+impl Trap {
+    pub fn new(cause: Exception, tval: u32) -> Self {
+        Self {
+            cause,
+            tval,
+            fault: None,
+        }
+    }
+
+    pub fn with_fault(self, fault: FaultId) -> Self {
+        Self {
+            fault: Some(fault),
+            ..self
+        }
+    }
+
+    // the bus only knows about loads and stores,
+    // a failed read during fetch is an instruction fault
+    pub fn from_fetch_error(err: BusError, vaddr: u32) -> Self {
+        match err {
+            BusError::LoadAddrMisaligned(_)
+            | BusError::StoreAddrMisaligned(_, _) => Self::new(
+                Exception::InstructionAddrMisaligned,
+                vaddr,
+            ),
+            BusError::LoadAccessFault(..)
+            | BusError::StoreAccessFault(..)
+            | BusError::FetchAccessFault(..) => Self::new(
+                Exception::InstructionAccessFault,
+                vaddr,
+            ),
+        }
+    }
+
+    // the bus sees physical addresses, tval takes the
+    // virtual address of the access
+    pub fn from_bus_error(err: BusError, vaddr: u32) -> Self {
+        match err {
+            BusError::LoadAddrMisaligned(_) => {
+                Self::new(Exception::LoadAddrMisaligned, vaddr)
+            }
+            BusError::StoreAddrMisaligned(_, _) => {
+                Self::new(Exception::StoreAddrMisaligned, vaddr)
+            }
+            BusError::LoadAccessFault(..) => {
+                Self::new(Exception::LoadAccessFault, vaddr)
+            }
+            BusError::StoreAccessFault(..) => {
+                Self::new(Exception::StoreAccessFault, vaddr)
+            }
+            BusError::FetchAccessFault(..) => {
+                Self::new(Exception::InstructionAccessFault, vaddr)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} (tval = {:#010x})", self.cause, self.tval)
+    }
+}